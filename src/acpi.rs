@@ -0,0 +1,168 @@
+//! ACPI table discovery
+//!
+//! Finds the RSDP in the BIOS areas, follows it to the RSDT or XSDT and
+//! parses the MADT, which lists the interrupt controllers. Tables are
+//! read through the physical memory window and their checksums
+//! verified; a table failing its checksum or shorter than its header is
+//! treated as missing.
+
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+/// Number of I/O APICs kept from the MADT
+pub const MAX_IO_APICS: usize = 4;
+/// Number of interrupt source overrides kept from the MADT
+pub const MAX_OVERRIDES: usize = 16;
+
+/// Size of the header every system description table starts with
+const SDT_HEADER_SIZE: u64 = 36;
+/// Offset of the first MADT entry, after the local APIC address and
+/// the flags
+const MADT_ENTRIES: u64 = SDT_HEADER_SIZE + 8;
+
+/// I/O APIC listed in the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub addr: PhysAddr,
+    /// First global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+/// ISA interrupt routed to a different global system interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    /// ISA IRQ line
+    pub source: u8,
+    pub gsi: u32,
+    /// Polarity in bits 0-1, trigger mode in bits 2-3
+    pub flags: u16,
+}
+
+/// Interrupt controllers described by the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    pub io_apics: [Option<IoApicEntry>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+/// Reads a `T` at the physical address `addr`
+fn read<T: Copy>(addr: PhysAddr) -> T {
+    let ptr: *const T = memory::phys_to_virt(addr).as_ptr();
+    unsafe { ptr.read_unaligned() }
+}
+
+/// Whether the `len` bytes at `addr` add up to zero
+fn checksum_ok(addr: PhysAddr, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(addr + i))) == 0
+}
+
+/// Length of the table at `addr`
+///
+/// `None` if the table is shorter than its header or fails its checksum
+fn table_len(addr: PhysAddr) -> Option<u64> {
+    let len = u64::from(read::<u32>(addr + 4u64));
+    if len < SDT_HEADER_SIZE || !checksum_ok(addr, len) {
+        return None;
+    }
+    Some(len)
+}
+
+/// Physical address of the RSDP
+///
+/// Searched in the first KiB of the EBDA and in the BIOS ROM area
+pub fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(read::<u16>(PhysAddr::new(0x40e))) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    areas
+        .iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| read::<[u8; 8]>(addr) == *b"RSD PTR " && checksum_ok(addr, 20))
+}
+
+/// Physical address of the table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = find_rsdp()?;
+    let revision: u8 = read(rsdp + 15u64);
+
+    // ACPI 2.0 and later point to the XSDT with 64 bit entries
+    let (root, entry_size) = if revision >= 2 {
+        (
+            PhysAddr::new(read::<u64>(rsdp + 24u64)),
+            size_of::<u64>() as u64,
+        )
+    } else {
+        let rsdt: u32 = read(rsdp + 16u64);
+        (PhysAddr::new(u64::from(rsdt)), size_of::<u32>() as u64)
+    };
+    let root_len = table_len(root)?;
+    let entries = (root_len - SDT_HEADER_SIZE) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + SDT_HEADER_SIZE + i * entry_size;
+            if entry_size == 8 {
+                PhysAddr::new(read::<u64>(entry))
+            } else {
+                PhysAddr::new(u64::from(read::<u32>(entry)))
+            }
+        })
+        .find(|&table| read::<[u8; 4]>(table) == *signature && table_len(table).is_some())
+}
+
+/// Parses the MADT
+///
+/// `None` without ACPI tables or MADT
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let len = table_len(table)?;
+    if len < MADT_ENTRIES {
+        return None;
+    }
+    let mut madt = Madt {
+        local_apic: PhysAddr::new(u64::from(read::<u32>(table + SDT_HEADER_SIZE))),
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= len {
+        let entry = table + offset;
+        let kind: u8 = read(entry);
+        let entry_len = u64::from(read::<u8>(entry + 1u64));
+        if entry_len < 2 {
+            break;
+        }
+        match kind {
+            1 => {
+                let io_apic = IoApicEntry {
+                    id: read(entry + 2u64),
+                    addr: PhysAddr::new(u64::from(read::<u32>(entry + 4u64))),
+                    gsi_base: read(entry + 8u64),
+                };
+                if let Some(slot) = madt.io_apics.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(io_apic);
+                }
+            }
+            2 => {
+                let interrupt_override = InterruptOverride {
+                    source: read(entry + 3u64),
+                    gsi: read(entry + 4u64),
+                    flags: read(entry + 8u64),
+                };
+                if let Some(slot) = madt.overrides.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some(interrupt_override);
+                }
+            }
+            // Local APIC address override
+            5 => madt.local_apic = PhysAddr::new(read(entry + 4u64)),
+            _ => {}
+        }
+        offset += entry_len;
+    }
+    Some(madt)
+}
@@ -1,62 +1,486 @@
 //!  Heap Allocator
-//!  Contains the struct implementing the `GlobalAllocator` trait
+//!  Contains the kernel heap mapping and the in-tree
+//!  fixed-size block allocator
+//!
+//!  The allocator backing the `#[global_allocator]` is selected at
+//!  build time: `linked_list_allocator::Heap` by default, the
+//!  `FixedSizeBlockAllocator` with the `fixed-size-block` feature.
+//!  Either one sits behind a `GrowableHeap` which maps more pages
+//!  when an allocation doesn't fit.
 
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::{
+    mem,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use linked_list_allocator::Heap;
+use spin::{Mutex, MutexGuard};
 
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, Size4KiB},
     VirtAddr,
 };
 
-/// HEAP memory starting address
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-/// 100 KB heap size
+use crate::{
+    kaslr, memory, paging,
+    stack::{self, GuardKind},
+};
+
+/// 100 KB heap size mapped by `map_heap`
 pub const HEAP_SIZE: usize = 100 * 1024;
+/// Default ceiling the heap may grow to
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+/// Smallest amount the heap grows by at once
+const HEAP_GROW_STEP: usize = 64 * 1024;
+
+/// Current ceiling on the heap size
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Name of the allocator backing the kernel heap
+#[cfg(not(feature = "fixed-size-block"))]
+pub const ALLOCATOR_NAME: &str = "linked-list";
+/// Name of the allocator backing the kernel heap
+#[cfg(feature = "fixed-size-block")]
+pub const ALLOCATOR_NAME: &str = "fixed-size-block";
+
+/// Allocator backing the kernel heap
+#[cfg(not(feature = "fixed-size-block"))]
+pub type Backend = Heap;
+/// Allocator backing the kernel heap
+#[cfg(feature = "fixed-size-block")]
+pub type Backend = FixedSizeBlockAllocator;
+
+/// Type of the global allocator
+pub type KernelHeap = Locked<GrowableHeap<Backend>>;
+
+/// Wrapper around `spin::Mutex`
+/// Allows implementing `GlobalAlloc` for allocators defined in this crate
+pub struct Locked<A> {
+    inner: Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// Allocator managing a contiguous heap region that can be extended
+/// at its end
+pub trait HeapBackend {
+    /// Hands the mapped range `start..start + size` to the allocator
+    ///
+    /// # Unsafe
+    /// ---------
+    /// The memory range must be mapped and unused.
+    /// Call only once.
+    /// ----------
+    unsafe fn init(&mut self, start: usize, size: usize);
+
+    /// Grows the managed range by `by` bytes past its current end
+    ///
+    /// # Unsafe
+    /// ---------
+    /// The memory being added must be mapped and unused
+    /// ----------
+    unsafe fn extend(&mut self, by: usize);
+
+    /// Returns null when the layout doesn't fit
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// # Unsafe
+    /// ---------
+    /// `ptr` must come from `allocate` with the same layout
+    /// ----------
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+impl HeapBackend for Heap {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        Heap::init(self, start, size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        Heap::extend(self, by);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match self.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
 
-/// Dummy ZST that implements `GlobalAlloc`
-pub struct SimpleAlloc;
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        Heap::deallocate(self, NonNull::new(ptr).unwrap(), layout);
+    }
+}
+
+/// Heap that maps more pages after its end when it runs out of space
+///
+/// Growth goes through the page table and frame allocator installed
+/// with `memory::install` and stops at the limit set by `set_heap_limit`.
+pub struct GrowableHeap<B> {
+    backend: B,
+    start: usize,
+    size: usize,
+    stats: HeapStats,
+}
+
+impl<B: HeapBackend> GrowableHeap<B> {
+    pub const fn new(backend: B) -> Self {
+        GrowableHeap {
+            backend,
+            start: 0,
+            size: 0,
+            stats: HeapStats::new(),
+        }
+    }
+
+    /// Initializes the heap with the given bounds
+    ///
+    /// # Unsafe
+    /// ---------
+    /// The memory range must be mapped and unused.
+    /// Call only once.
+    /// ----------
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.start = heap_start;
+        self.size = heap_size;
+        self.backend.init(heap_start, heap_size);
+    }
+
+    /// Bytes currently mapped for the heap
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Maps enough pages after the heap end to fit `layout`
+    ///
+    /// Returns false when the limit is reached, the kernel memory isn't
+    /// installed or is in use, or mapping fails
+    fn grow(&mut self, layout: Layout) -> bool {
+        if self.size == 0 {
+            return false; // Not initialized
+        }
+        let page_size = Size4KiB::SIZE as usize;
+        let needed = (layout.size() + layout.align() + page_size - 1) & !(page_size - 1);
+        let by = needed.max(HEAP_GROW_STEP);
+        if self.size + by > HEAP_LIMIT.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        // Mapping never allocates from the heap, but the lock may be
+        // held by whoever triggered this allocation
+        let mut kernel_memory = match memory::KERNEL_MEMORY.try_lock() {
+            Some(guard) => guard,
+            None => return false,
+        };
+        let kernel_memory = match kernel_memory.as_mut() {
+            Some(kernel_memory) => kernel_memory,
+            None => return false,
+        };
 
-unsafe impl GlobalAlloc for SimpleAlloc {
+        let end = VirtAddr::new((self.start + self.size) as u64);
+        let mapped = map_pages(
+            end,
+            by,
+            &mut kernel_memory.mapper,
+            &mut kernel_memory.frame_allocator,
+        );
+        if mapped.is_err() {
+            return false;
+        }
+        unsafe { self.backend.extend(by) };
+        self.size += by;
+        true
+    }
+}
+
+unsafe impl<B: HeapBackend> GlobalAlloc for Locked<GrowableHeap<B>> {
     /// Allocates heap memory
     /// Returns a raw pointer to the first byte of the
     /// allocated memory block
     ///
     /// Null pointer signals an allocation error
-    unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
-        null_mut()
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.lock();
+        let mut ptr = heap.backend.allocate(layout);
+        if ptr.is_null() && heap.grow(layout) {
+            ptr = heap.backend.allocate(layout);
+        }
+        if !ptr.is_null() {
+            heap.stats.record_alloc(&layout);
+            #[cfg(feature = "leak-tracking")]
+            crate::leak::track(ptr, &layout);
+        }
+        ptr
     }
+
     /// Frees an allocated memory block
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // allocater never returns memory
-        // call to `dealloc` shouldn't occure
-        panic!("Oops! Not sane to call `dealloc`")
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut heap = self.lock();
+        heap.backend.deallocate(ptr, layout);
+        heap.stats.record_dealloc(&layout);
+        #[cfg(feature = "leak-tracking")]
+        crate::leak::untrack(ptr);
     }
 }
 
-/// Maps Virtual heap memory region to Physical memory
-pub fn map_heap(
+/// Number of size buckets in `HeapStats`
+///
+/// Bucket `i` counts allocations of up to `8 << i` bytes,
+/// the last one everything larger
+pub const SIZE_BUCKETS: usize = 10;
+
+/// Kernel heap usage
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently handed out
+    pub bytes_allocated: usize,
+    /// Highest value `bytes_allocated` reached
+    pub peak_bytes_allocated: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    /// Allocation counts per size bucket
+    pub allocations_by_size: [u64; SIZE_BUCKETS],
+    /// Bytes currently mapped for the heap
+    pub heap_size: usize,
+}
+
+impl HeapStats {
+    const fn new() -> Self {
+        HeapStats {
+            bytes_allocated: 0,
+            peak_bytes_allocated: 0,
+            allocations: 0,
+            deallocations: 0,
+            allocations_by_size: [0; SIZE_BUCKETS],
+            heap_size: 0,
+        }
+    }
+
+    /// Pages currently mapped for the heap
+    pub fn mapped_pages(&self) -> usize {
+        self.heap_size / Size4KiB::SIZE as usize
+    }
+
+    /// Allocations currently live
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations - self.deallocations
+    }
+
+    fn record_alloc(&mut self, layout: &Layout) {
+        self.bytes_allocated += layout.size();
+        self.peak_bytes_allocated = self.peak_bytes_allocated.max(self.bytes_allocated);
+        self.allocations += 1;
+        self.allocations_by_size[size_bucket(layout.size())] += 1;
+    }
+
+    fn record_dealloc(&mut self, layout: &Layout) {
+        self.bytes_allocated -= layout.size();
+        self.deallocations += 1;
+    }
+}
+
+/// Bucket of `HeapStats::allocations_by_size` counting `size`
+fn size_bucket(size: usize) -> usize {
+    (0..SIZE_BUCKETS - 1)
+        .find(|&bucket| size <= 8 << bucket)
+        .unwrap_or(SIZE_BUCKETS - 1)
+}
+
+/// Sets the size the heap may grow to
+///
+/// Has no effect on memory already mapped. Capped at `HEAP_MAX_SIZE`,
+/// the page after which is a guard page.
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(bytes.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+/// Current ceiling on the heap size
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Block sizes served from the size-class free lists
+///
+/// Each size is a power of two and doubles as the block alignment.
+/// Larger layouts go to the fallback allocator.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Free block of a size class
+/// Stored in the freed memory itself
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Heap allocator with O(1) free lists for small size classes
+///
+/// Blocks are carved out of the fallback linked list allocator the
+/// first time a size class is used and are never given back to it;
+/// freed blocks go onto the free list of their class.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback: Heap,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an allocator without any heap memory
+    pub const fn empty() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback: Heap::empty(),
+        }
+    }
+
+    /// Allocates from the fallback allocator
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+/// Index of the smallest size class fitting the layout
+///
+/// `None` when the layout is too large or too strictly aligned
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+impl HeapBackend for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.fallback.init(start, size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback.extend(by);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // Empty size class: carve a new block
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                    self.fallback_alloc(layout)
+                }
+            },
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                // Blocks of every class can hold a node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback.deallocate(ptr, layout);
+            }
+        }
+    }
+}
+
+/// Maps `size` bytes of fresh frames starting at `start`
+///
+/// The pages are writable and never executable
+pub(crate) fn map_pages(
+    start: VirtAddr,
+    size: usize,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64; // Inclusive bound on last byte address
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
+        let end = start + size - 1u64; // Inclusive bound on last byte address
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(end);
+        Page::range_inclusive(start_page, end_page)
     };
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = paging::effective_flags(paging::data_flags());
         mapper.map_to(page, frame, flags, frame_allocator)?.flush();
     }
+    Ok(())
+}
+
+/// Maps Virtual heap memory region to Physical memory
+pub fn map_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError> {
+    let heap_start = VirtAddr::new(heap_start() as u64);
+    map_pages(heap_start, HEAP_SIZE, mapper, frame_allocator)?;
+
+    // Pages around the heap region are never mapped
+    let below = Page::containing_address(heap_start - 1u64);
+    let above = Page::containing_address(heap_start + HEAP_MAX_SIZE);
+    stack::register_guard("kernel heap", GuardKind::Region, below);
+    stack::register_guard("kernel heap", GuardKind::Region, above);
     unsafe {
-        super::ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        kernel_heap().lock().init(heap_start(), HEAP_SIZE);
     }
     Ok(())
 }
+
+/// HEAP memory starting address
+///
+/// Randomised at boot, see `kaslr`
+pub fn heap_start() -> usize {
+    kaslr::layout().heap as usize
+}
+
+/// Bytes currently mapped for the heap
+pub fn heap_size() -> usize {
+    kernel_heap().lock().size()
+}
+
+/// Current kernel heap usage
+pub fn stats() -> HeapStats {
+    let heap = kernel_heap().lock();
+    HeapStats {
+        heap_size: heap.size,
+        ..heap.stats
+    }
+}
+
+/// The kernel heap below any debugging layer
+fn kernel_heap() -> &'static KernelHeap {
+    #[cfg(not(feature = "heap-debug"))]
+    {
+        &super::ALLOCATOR
+    }
+    #[cfg(feature = "heap-debug")]
+    {
+        super::ALLOCATOR.inner()
+    }
+}
@@ -0,0 +1,317 @@
+//! Local APIC and I/O APIC
+//!
+//! Replaces the 8259 PICs when the CPU has an APIC: the PICs are masked,
+//! the local APIC enabled and the I/O APIC programmed to deliver the
+//! lines with registered handlers at the vectors the PICs used. The I/O APIC
+//! and the ISA IRQ overrides come from the ACPI MADT, with the usual
+//! I/O APIC address as a fallback. Without an APIC the PICs stay in
+//! charge, see `interrupts::end_of_interrupt`.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::{self, InterruptOverride, Madt, MAX_IO_APICS, MAX_OVERRIDES};
+use crate::interrupts::{self as irq, IRQ_LINES};
+use crate::serial_println;
+use crate::vma::{self, VmallocError};
+
+/// Vector of spurious local APIC interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// MSR holding the local APIC base
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Global enable bit of `IA32_APIC_BASE`
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// Where the I/O APIC usually sits when no MADT lists it
+const DEFAULT_IO_APIC: u64 = 0xfec0_0000;
+
+/// Local APIC register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+/// Software enable bit of the spurious interrupt vector register
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// I/O APIC register offsets
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+/// Version register, bits 16-23 hold the last redirection entry
+const IOAPICVER: u32 = 0x01;
+/// First redirection table register
+const IOREDTBL: u32 = 0x10;
+/// Redirection entry bits
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+/// Set once the APIC took over from the PICs
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Virtual address of the local APIC registers
+static LAPIC: AtomicU64 = AtomicU64::new(0);
+/// The I/O APICs, each serving its own range of global system interrupts
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+/// ISA IRQ overrides from the MADT
+static OVERRIDES: Mutex<[Option<InterruptOverride>; MAX_OVERRIDES]> =
+    Mutex::new([None; MAX_OVERRIDES]);
+
+/// Reasons the APIC couldn't be set up
+#[derive(Debug)]
+pub enum ApicError {
+    /// The CPU has no local APIC
+    Unsupported,
+    /// Mapping the registers failed
+    Map(VmallocError),
+    /// No I/O APIC serves the global system interrupt
+    NoIoApic(u32),
+}
+
+/// A mapped I/O APIC
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    /// Virtual address of the registers
+    base: u64,
+    /// First global system interrupt it serves
+    gsi_base: u32,
+    /// Number of redirection entries
+    pins: u32,
+}
+
+impl IoApic {
+    /// Maps the I/O APIC at `addr` and reads its number of pins
+    fn map(addr: PhysAddr, gsi_base: u32) -> Result<IoApic, ApicError> {
+        let base = unsafe { vma::ioremap("io apic", addr, 4096) };
+        let mut io_apic = IoApic {
+            base: base.map_err(ApicError::Map)?.as_u64(),
+            gsi_base,
+            pins: 0,
+        };
+        let version = unsafe { io_apic.read(IOAPICVER) };
+        io_apic.pins = ((version >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    fn serves(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.pins
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        let base = self.base as usize;
+        ((base + IOAPIC_REGSEL) as *mut u32).write_volatile(register);
+        ((base + IOAPIC_WINDOW) as *mut u32).write_volatile(value);
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        let base = self.base as usize;
+        ((base + IOAPIC_REGSEL) as *mut u32).write_volatile(register);
+        ((base + IOAPIC_WINDOW) as *const u32).read_volatile()
+    }
+}
+
+/// What `init` found and enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicInfo {
+    pub local_apic: PhysAddr,
+    pub io_apic: PhysAddr,
+    /// Local APIC ID of this CPU, the destination of all IRQs
+    pub lapic_id: u8,
+    /// Whether the I/O APIC came from the MADT or the default address
+    pub from_madt: bool,
+}
+
+/// Whether the CPU has a local APIC
+pub fn supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    // CPUID 1: EDX bit 9 is APIC
+    unsafe { __cpuid(1) }.edx & (1 << 9) != 0
+}
+
+/// Whether the APIC handles interrupts instead of the PICs
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Moves interrupt handling from the PICs to the APIC
+///
+/// Needs the kernel memory installed to map the registers. On error
+/// the PICs stay in charge.
+pub fn init() -> Result<ApicInfo, ApicError> {
+    if !supported() {
+        return Err(ApicError::Unsupported);
+    }
+    let madt = acpi::madt();
+
+    let base_msr = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    let local_apic = match madt {
+        Some(madt) => madt.local_apic,
+        None => PhysAddr::new(base_msr & 0x000f_ffff_ffff_f000),
+    };
+    let io_apic_entry = madt.and_then(|madt| madt.io_apics[0]);
+    let io_apic = io_apic_entry.map_or(PhysAddr::new(DEFAULT_IO_APIC), |entry| entry.addr);
+
+    let lapic_virt = unsafe { vma::ioremap("local apic", local_apic, 4096) };
+    let lapic_virt = lapic_virt.map_err(ApicError::Map)?;
+    let mut io_apics = [None; MAX_IO_APICS];
+    if let Err(err) = map_io_apics(madt, io_apic, &mut io_apics) {
+        // Nothing uses the registers mapped so far
+        unsafe {
+            let _ = vma::vfree(lapic_virt);
+            for io_apic in io_apics.iter().flatten() {
+                let _ = vma::vfree(VirtAddr::new(io_apic.base));
+            }
+        }
+        return Err(err);
+    }
+
+    let info = interrupts::without_interrupts(|| {
+        LAPIC.store(lapic_virt.as_u64(), Ordering::Relaxed);
+        *IO_APICS.lock() = io_apics;
+        if let Some(madt) = madt {
+            *OVERRIDES.lock() = madt.overrides;
+        }
+
+        disable_pics();
+        unsafe {
+            Msr::new(IA32_APIC_BASE).write(base_msr | APIC_BASE_ENABLE);
+            lapic_write(LAPIC_TPR, 0);
+            lapic_write(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+
+        // Lines registered later are routed by `register_irq`
+        let lines = irq::registered_lines();
+        for line in (0..IRQ_LINES as u8).filter(|&line| lines & 1 << line != 0) {
+            if let Err(err) = route_isa_irq(line, irq::irq_vector(line)) {
+                serial_println!("[apic] IRQ {} not routed: {:?}", line, err);
+            }
+        }
+        ApicInfo {
+            local_apic,
+            io_apic,
+            lapic_id: lapic_id(),
+            from_madt: io_apic_entry.is_some(),
+        }
+    });
+    Ok(info)
+}
+
+/// Maps the I/O APICs of `madt`, or the one at `fallback` without it
+///
+/// Stops at the first one failing, `io_apics` keeps those mapped before
+fn map_io_apics(
+    madt: Option<Madt>,
+    fallback: PhysAddr,
+    io_apics: &mut [Option<IoApic>; MAX_IO_APICS],
+) -> Result<(), ApicError> {
+    match madt {
+        Some(madt) => {
+            for (slot, entry) in io_apics.iter_mut().zip(madt.io_apics.iter()) {
+                if let Some(entry) = entry {
+                    *slot = Some(IoApic::map(entry.addr, entry.gsi_base)?);
+                }
+            }
+        }
+        None => io_apics[0] = Some(IoApic::map(fallback, 0)?),
+    }
+    Ok(())
+}
+
+/// Masks every line of both PICs
+fn disable_pics() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+fn lapic_register(offset: usize) -> *mut u32 {
+    (LAPIC.load(Ordering::Relaxed) as usize + offset) as *mut u32
+}
+
+unsafe fn lapic_read(offset: usize) -> u32 {
+    lapic_register(offset).read_volatile()
+}
+
+unsafe fn lapic_write(offset: usize, value: u32) {
+    lapic_register(offset).write_volatile(value)
+}
+
+/// Local APIC ID of this CPU
+pub fn lapic_id() -> u8 {
+    (unsafe { lapic_read(LAPIC_ID) } >> 24) as u8
+}
+
+/// Signals the end of the current interrupt to the local APIC
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// Delivers the ISA IRQ `irq` at `vector` to this CPU
+///
+/// Follows the MADT overrides for the global system interrupt,
+/// polarity and trigger mode
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    let (gsi, flags) = match isa_override(irq) {
+        Some(o) => (o.gsi, o.flags),
+        // ISA lines are edge triggered, active high
+        None => (u32::from(irq), 0),
+    };
+
+    let mut low = u32::from(vector);
+    if flags & 0b11 == 0b11 {
+        low |= REDIRECT_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        low |= REDIRECT_LEVEL;
+    }
+    let high = u32::from(lapic_id()) << 24;
+    let (io_apic, entry) = redirection_entry(gsi)?;
+    unsafe {
+        io_apic.write(entry, REDIRECT_MASKED);
+        io_apic.write(entry + 1, high);
+        io_apic.write(entry, low);
+    }
+    Ok(())
+}
+
+/// Masks or unmasks the ISA IRQ `irq`
+pub fn set_isa_irq_masked(irq: u8, masked: bool) -> Result<(), ApicError> {
+    let gsi = isa_override(irq).map_or(u32::from(irq), |o| o.gsi);
+    let (io_apic, entry) = redirection_entry(gsi)?;
+    unsafe {
+        let low = io_apic.read(entry);
+        if masked {
+            io_apic.write(entry, low | REDIRECT_MASKED);
+        } else {
+            io_apic.write(entry, low & !REDIRECT_MASKED);
+        }
+    }
+    Ok(())
+}
+
+/// MADT override of the ISA IRQ `irq`
+fn isa_override(irq: u8) -> Option<InterruptOverride> {
+    let overrides = OVERRIDES.lock();
+    overrides
+        .iter()
+        .flatten()
+        .find(|o| o.source == irq)
+        .copied()
+}
+
+/// I/O APIC serving `gsi` and the low register of its redirection entry
+fn redirection_entry(gsi: u32) -> Result<(IoApic, u32), ApicError> {
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .flatten()
+        .find(|io_apic| io_apic.serves(gsi))
+        .copied()
+        .ok_or(ApicError::NoIoApic(gsi))?;
+    Ok((io_apic, IOREDTBL + 2 * (gsi - io_apic.gsi_base)))
+}
@@ -0,0 +1,224 @@
+//! Register snapshots and stack backtraces
+//!
+//! Backtraces follow the saved frame pointers, which the target spec
+//! keeps enabled. Each frame is checked to be mapped before it's read,
+//! so a corrupt chain ends the backtrace instead of faulting. Before
+//! `memory::init` nothing can be checked and backtraces stay empty.
+//!
+//! Frames are printed as `function+offset` from the embedded symbol
+//! table, see `symbols`.
+
+use core::fmt;
+use x86_64::VirtAddr;
+
+use crate::{kernel_image, paging, symbols};
+
+/// Frames kept in a `Backtrace`
+pub const MAX_FRAMES: usize = 16;
+
+/// General purpose registers, RIP and RFLAGS
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+/// Registers of the calling function
+///
+/// Inlined into the caller, so RIP, RSP and RBP are the caller's
+#[inline(always)]
+pub fn capture_registers() -> Registers {
+    let mut registers = Registers::default();
+    // Offsets follow the field order of `Registers`
+    unsafe {
+        asm!("
+            mov %rax, 0x00($0)
+            mov %rbx, 0x08($0)
+            mov %rcx, 0x10($0)
+            mov %rdx, 0x18($0)
+            mov %rsi, 0x20($0)
+            mov %rdi, 0x28($0)
+            mov %rbp, 0x30($0)
+            mov %rsp, 0x38($0)
+            mov %r8, 0x40($0)
+            mov %r9, 0x48($0)
+            mov %r10, 0x50($0)
+            mov %r11, 0x58($0)
+            mov %r12, 0x60($0)
+            mov %r13, 0x68($0)
+            mov %r14, 0x70($0)
+            mov %r15, 0x78($0)
+            lea 0(%rip), %rax
+            mov %rax, 0x80($0)
+            pushfq
+            pop %rax
+            mov %rax, 0x88($0)"
+            :: "r"(&mut registers as *mut Registers)
+            : "rax", "memory"
+            : "volatile")
+    };
+    registers
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registers = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("rsp", self.rsp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+            ("rip", self.rip),
+            ("rflags", self.rflags),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            let separator = if i % 3 == 2 || i == registers.len() - 1 {
+                "\n"
+            } else {
+                " "
+            };
+            write!(f, "{:>6} {:#018x}{}", name, value, separator)?;
+        }
+        Ok(())
+    }
+}
+
+/// Frame pointer of the calling function
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile") };
+    rbp
+}
+
+/// Whether the 16 bytes of a frame record at `addr` can be read
+///
+/// Never before `memory::init`: without the page tables to check, the
+/// chain isn't followed at all
+fn frame_readable(addr: u64) -> bool {
+    addr != 0 && addr % 8 == 0 && paging::is_mapped(addr, 16)
+}
+
+/// Frame pointer the function with frame `rbp` saved on entry
+///
+/// For an exception handler, the frame pointer of the interrupted code
+pub fn saved_frame_pointer(rbp: u64) -> u64 {
+    if frame_readable(rbp) {
+        unsafe { *(rbp as *const u64) }
+    } else {
+        0
+    }
+}
+
+/// Calls `f` with each return address of the chain starting at `rbp`,
+/// innermost first, until it returns false
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64) -> bool) {
+    while frame_readable(rbp) {
+        let frame = rbp as *const u64;
+        // The return address sits above the saved rbp
+        let (next, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 || !f(return_address) {
+            break;
+        }
+        // Callers' frames are higher up the stack
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Return addresses of a call chain, innermost first
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Backtrace of the calling function
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self::from_frame(None, frame_pointer())
+    }
+
+    /// Backtrace of code stopped at `rip`, with the frame pointer `rbp`
+    ///
+    /// `rip` is the first frame when given, like the faulting
+    /// instruction of an exception
+    pub fn from_frame(rip: Option<u64>, rbp: u64) -> Self {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        if let Some(rip) = rip {
+            backtrace.push(rip);
+        }
+        walk(rbp, |return_address| backtrace.push(return_address));
+        backtrace
+    }
+
+    /// Adds a frame, false once full
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = address;
+        self.len += 1;
+        true
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate() {
+            let in_code = VirtAddr::try_new(address)
+                .ok()
+                .and_then(kernel_image::segment)
+                .map_or(false, |segment| segment.executable);
+            write!(f, "  #{:<2} {:#018x}", i, address)?;
+            match symbols::resolve(address) {
+                Some(symbol) => writeln!(f, " {}", symbol)?,
+                None if in_code => writeln!(f)?,
+                None => writeln!(f, " (outside kernel code)")?,
+            }
+        }
+        if self.len == MAX_FRAMES {
+            writeln!(f, "  ...")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+//! Buddy system physical frame allocator
+//!
+//! Hands out physically contiguous, naturally aligned blocks of
+//! `2^order` frames. Free blocks of each order are kept in a list
+//! threaded through the free memory itself, so the allocator needs
+//! no storage besides the list heads.
+
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    UnusedPhysFrame,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+/// Largest supported order: 2^18 frames = 1GiB
+pub const MAX_ORDER: usize = 18;
+/// Order of a 2MiB block
+pub const ORDER_2MIB: usize = 9;
+/// Order of a 1GiB block
+pub const ORDER_1GIB: usize = 18;
+
+/// Marks the end of a free list in memory
+const LIST_END: u64 = core::u64::MAX;
+
+/// Bytes covered by a block of the given order
+pub const fn block_size(order: usize) -> u64 {
+    Size4KiB::SIZE << order
+}
+
+/// Snapshot of the free lists
+#[derive(Debug, Clone, Copy)]
+pub struct BuddyStats {
+    /// Number of free blocks per order
+    pub free_blocks: [usize; MAX_ORDER + 1],
+    /// Frames managed by the allocator
+    pub total_frames: usize,
+    /// Frames available for allocation
+    pub free_frames: usize,
+}
+
+impl BuddyStats {
+    /// Largest order with at least one free block
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..=MAX_ORDER).rev().find(|&order| self.free_blocks[order] > 0)
+    }
+
+    /// Percentage of free memory that can't serve a request of `order`
+    ///
+    /// 0 means every free frame sits in a block large enough,
+    /// 100 means no free block of that size is left
+    pub fn fragmentation(&self, order: usize) -> usize {
+        if self.free_frames == 0 {
+            return 0;
+        }
+        let unusable: usize = (0..order.min(MAX_ORDER + 1))
+            .map(|o| self.free_blocks[o] << o)
+            .sum();
+        unusable * 100 / self.free_frames
+    }
+}
+
+/// Physical allocator serving power of two sized blocks
+pub struct BuddyFrameAllocator {
+    free_lists: [u64; MAX_ORDER + 1], // Address of the first free block or `LIST_END`
+    free_blocks: [usize; MAX_ORDER + 1],
+    physical_mem_offset: VirtAddr,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator without any memory
+    ///
+    /// Memory is added through `add_region`
+    pub fn new(physical_mem_offset: VirtAddr) -> Self {
+        BuddyFrameAllocator {
+            free_lists: [LIST_END; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            physical_mem_offset,
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Creates a Buddy allocator seeded with the usable regions
+    /// of the memory map
+    ///
+    /// # Unsafe
+    /// ---------
+    /// Guarantee the usable frames of the memory map aren't used
+    /// anywhere else, including by another frame allocator, and the
+    /// complete physical memory is mapped at `physical_mem_offset`
+    /// ----------
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_mem_offset: VirtAddr) -> Self {
+        let mut allocator = Self::new(physical_mem_offset);
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        for region in usable_regions {
+            allocator.add_region(
+                PhysAddr::new(region.range.start_addr()),
+                PhysAddr::new(region.range.end_addr()),
+            );
+        }
+        allocator
+    }
+
+    /// Hands the physical range `start..end` to the allocator
+    ///
+    /// The range is split into the largest naturally aligned blocks
+    /// that fit.
+    ///
+    /// # Unsafe
+    /// ---------
+    /// The range must be unused and mapped at the physical memory offset
+    /// ----------
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start.align_up(Size4KiB::SIZE).as_u64();
+        let end = end.align_down(Size4KiB::SIZE).as_u64();
+
+        while addr < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| addr % block_size(o) == 0 && addr + block_size(o) <= end)
+                .unwrap_or(0);
+            self.push(addr, order);
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates a block of `2^order` contiguous frames
+    ///
+    /// Returns the start address of the block, aligned to its size
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != LIST_END)?;
+        let block = self.pop(current);
+
+        // Split down to the requested size, freeing the upper halves
+        while current > order {
+            current -= 1;
+            self.push(block + block_size(current), current);
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysAddr::new(block))
+    }
+
+    /// Returns a block previously given out by `allocate`
+    ///
+    /// Merges the block with its buddy for as long as the buddy is free
+    ///
+    /// # Unsafe
+    /// ---------
+    /// `addr` and `order` must match an earlier allocation and the
+    /// block must no longer be in use
+    /// ----------
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        let mut block = addr.as_u64();
+        let mut order = order;
+        self.free_frames += 1 << order;
+
+        while order < MAX_ORDER {
+            let buddy = block ^ block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    /// Free list statistics
+    pub fn stats(&self) -> BuddyStats {
+        BuddyStats {
+            free_blocks: self.free_blocks,
+            total_frames: self.total_frames,
+            free_frames: self.free_frames,
+        }
+    }
+
+    /// Pointer to the list link stored in a free block
+    fn link(&self, block: u64) -> *mut u64 {
+        (self.physical_mem_offset + block).as_mut_ptr()
+    }
+
+    fn push(&mut self, block: u64, order: usize) {
+        unsafe { self.link(block).write(self.free_lists[order]) };
+        self.free_lists[order] = block;
+        self.free_blocks[order] += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> u64 {
+        let block = self.free_lists[order];
+        self.free_lists[order] = unsafe { self.link(block).read() };
+        self.free_blocks[order] -= 1;
+        block
+    }
+
+    /// Unlinks `block` from the free list of `order`
+    ///
+    /// Returns false when the block isn't free
+    fn remove(&mut self, block: u64, order: usize) -> bool {
+        let mut previous: Option<u64> = None;
+        let mut current = self.free_lists[order];
+
+        while current != LIST_END {
+            let next = unsafe { self.link(current).read() };
+            if current == block {
+                match previous {
+                    Some(prev) => unsafe { self.link(prev).write(next) },
+                    None => self.free_lists[order] = next,
+                }
+                self.free_blocks[order] -= 1;
+                return true;
+            }
+            previous = Some(current);
+            current = next;
+        }
+        false
+    }
+
+    fn allocate_sized<S: PageSize>(&mut self, order: usize) -> Option<UnusedPhysFrame<S>> {
+        let addr = self.allocate(order)?;
+        let frame = PhysFrame::containing_address(addr);
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
+        self.allocate_sized(0)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        self.allocate_sized(ORDER_2MIB)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size1GiB>> {
+        self.allocate_sized(ORDER_1GIB)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size4KiB>) {
+        unsafe { self.deallocate(frame.start_address(), 0) }
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size2MiB>) {
+        unsafe { self.deallocate(frame.start_address(), ORDER_2MIB) }
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size1GiB>) {
+        unsafe { self.deallocate(frame.start_address(), ORDER_1GIB) }
+    }
+}
@@ -0,0 +1,326 @@
+//! Console input
+//!
+//! Input sources, like the serial port, are polled in the order they
+//! were added. Reading through the console takes the first byte any of
+//! them has, so the kernel reads a `-serial stdio` pipe the same way as
+//! a keyboard and can be driven headlessly.
+//!
+//! `read_line` edits a line typed on the keyboard or a terminal on an
+//! input source, echoing it to the VGA buffer and serial.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::{keyboard, serial, vga_buffer};
+
+/// Most input sources at once
+pub const MAX_SOURCES: usize = 4;
+
+/// Next byte of an input source, if any
+pub type InputSource = fn() -> Option<u8>;
+
+/// Reasons an input source can't be added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    AlreadyAdded,
+    TooManySources,
+}
+
+static SOURCES: Mutex<[Option<InputSource>; MAX_SOURCES]> = Mutex::new([None; MAX_SOURCES]);
+
+/// Adds `source` to the sources `read_byte` polls
+pub fn add_source(source: InputSource) -> Result<(), ConsoleError> {
+    without_interrupts(|| {
+        let mut sources = SOURCES.lock();
+        if sources
+            .iter()
+            .flatten()
+            .any(|&s| s as usize == source as usize)
+        {
+            return Err(ConsoleError::AlreadyAdded);
+        }
+        let slot = sources.iter_mut().find(|s| s.is_none());
+        *slot.ok_or(ConsoleError::TooManySources)? = Some(source);
+        Ok(())
+    })
+}
+
+/// Next byte of the first source that has one
+pub fn read_byte() -> Option<u8> {
+    let sources = without_interrupts(|| *SOURCES.lock());
+    sources.iter().flatten().find_map(|source| source())
+}
+
+/// Waits for a byte from any source, halting in between
+///
+/// Interrupts must be enabled, they are again on return
+pub fn wait_byte() -> u8 {
+    loop {
+        interrupts::disable();
+        if let Some(byte) = read_byte() {
+            interrupts::enable();
+            return byte;
+        }
+        interrupts::enable_interrupts_and_hlt();
+    }
+}
+
+/// Longest line `read_line` takes
+pub const MAX_LINE: usize = 256;
+/// Lines `read_line` remembers
+pub const HISTORY_LEN: usize = 32;
+
+/// Earlier lines of `read_line`, oldest first
+static HISTORY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// Escape sequence and line ending state of the input sources, kept
+/// between lines
+static TERMINAL: Mutex<Terminal> = Mutex::new(Terminal::new());
+
+/// Keys `read_line` understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Previous line of the history
+    Up,
+    /// Next line of the history
+    Down,
+}
+
+impl Key {
+    fn from_keyboard(key: DecodedKey) -> Option<Key> {
+        let key = match key {
+            DecodedKey::Unicode('\n') => Key::Enter,
+            DecodedKey::Unicode('\u{8}') => Key::Backspace,
+            DecodedKey::Unicode('\u{7f}') => Key::Delete,
+            DecodedKey::Unicode(c) if !c.is_control() => Key::Char(c),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => Key::Left,
+            DecodedKey::RawKey(KeyCode::ArrowRight) => Key::Right,
+            DecodedKey::RawKey(KeyCode::ArrowUp) => Key::Up,
+            DecodedKey::RawKey(KeyCode::ArrowDown) => Key::Down,
+            DecodedKey::RawKey(KeyCode::Home) => Key::Home,
+            DecodedKey::RawKey(KeyCode::End) => Key::End,
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+/// Progress through a terminal escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// After ESC
+    Esc,
+    /// After ESC [
+    Csi,
+    /// After ESC [ and a digit
+    CsiDigit(u8),
+}
+
+/// Turns the bytes a terminal sends into keys
+struct Terminal {
+    escape: Escape,
+    /// `\n` right after `\r` ends no second line
+    after_cr: bool,
+}
+
+impl Terminal {
+    const fn new() -> Self {
+        Terminal {
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    fn key(&mut self, byte: u8) -> Option<Key> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        let (escape, key) = match (self.escape, byte) {
+            (Escape::None, 0x1b) => (Escape::Esc, None),
+            (Escape::None, b'\n') if after_cr => (Escape::None, None),
+            (Escape::None, b'\r') | (Escape::None, b'\n') => (Escape::None, Some(Key::Enter)),
+            // Terminals send DEL for backspace
+            (Escape::None, 0x08) | (Escape::None, 0x7f) => (Escape::None, Some(Key::Backspace)),
+            (Escape::None, 0x20..=0x7e) => (Escape::None, Some(Key::Char(char::from(byte)))),
+            (Escape::Esc, b'[') => (Escape::Csi, None),
+            (Escape::Csi, b'A') => (Escape::None, Some(Key::Up)),
+            (Escape::Csi, b'B') => (Escape::None, Some(Key::Down)),
+            (Escape::Csi, b'C') => (Escape::None, Some(Key::Right)),
+            (Escape::Csi, b'D') => (Escape::None, Some(Key::Left)),
+            (Escape::Csi, b'H') => (Escape::None, Some(Key::Home)),
+            (Escape::Csi, b'F') => (Escape::None, Some(Key::End)),
+            (Escape::Csi, b'0'..=b'9') => (Escape::CsiDigit(byte), None),
+            (Escape::CsiDigit(digit), b'~') => {
+                let key = match digit {
+                    b'1' | b'7' => Some(Key::Home),
+                    b'3' => Some(Key::Delete),
+                    b'4' | b'8' => Some(Key::End),
+                    _ => None,
+                };
+                (Escape::None, key)
+            }
+            _ => (Escape::None, None),
+        };
+        self.escape = escape;
+        key
+    }
+}
+
+/// Waits for the next key from the keyboard or an input source
+fn next_key() -> Key {
+    loop {
+        // Input arriving between the checks and `hlt` would be missed
+        interrupts::disable();
+        let key = if let Some(key) = keyboard::read_key() {
+            Key::from_keyboard(key)
+        } else if let Some(byte) = read_byte() {
+            TERMINAL.lock().key(byte)
+        } else {
+            interrupts::enable_interrupts_and_hlt();
+            continue;
+        };
+        interrupts::enable();
+        if let Some(key) = key {
+            return key;
+        }
+    }
+}
+
+/// Line being edited, echoed to VGA and serial
+struct LineEditor {
+    line: String,
+    /// Position in `line`
+    cursor: usize,
+    /// VGA column the line starts at
+    start_column: usize,
+    /// Terminal cursor position in `line`
+    shown_cursor: usize,
+    /// Entry of the history shown, counted from the newest
+    history_index: Option<usize>,
+    /// Line typed before going through the history
+    draft: String,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        LineEditor {
+            line: String::new(),
+            cursor: 0,
+            start_column: without_interrupts(|| vga_buffer::WRITER.lock().column()),
+            shown_cursor: 0,
+            history_index: None,
+            draft: String::new(),
+        }
+    }
+
+    fn apply(&mut self, key: Key) {
+        match key {
+            // Only ASCII can be shown on VGA
+            Key::Char(c) if c.is_ascii() && self.line.len() < MAX_LINE => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up => self.recall(self.history_index.map_or(0, |index| index + 1)),
+            Key::Down => match self.history_index {
+                Some(0) => {
+                    self.history_index = None;
+                    self.line = core::mem::replace(&mut self.draft, String::new());
+                    self.cursor = self.line.len();
+                }
+                Some(index) => self.recall(index - 1),
+                None => {}
+            },
+            _ => return,
+        }
+        self.redraw();
+    }
+
+    /// Shows the history entry `index`, counted from the newest
+    fn recall(&mut self, index: usize) {
+        let entry = {
+            let history = HISTORY.lock();
+            match history.len().checked_sub(index + 1) {
+                Some(position) => history[position].clone(),
+                None => return,
+            }
+        };
+        if self.history_index.is_none() {
+            self.draft = core::mem::replace(&mut self.line, String::new());
+        }
+        self.history_index = Some(index);
+        self.line = entry;
+        self.cursor = self.line.len();
+    }
+
+    fn redraw(&mut self) {
+        without_interrupts(|| {
+            vga_buffer::WRITER
+                .lock()
+                .rewrite_line(self.start_column, &self.line, self.cursor);
+
+            let mut serial = serial::SERIAL_A.lock();
+            // Back to the start, rewrite and clear the rest of the line
+            if self.shown_cursor > 0 {
+                let _ = write!(serial, "\x1b[{}D", self.shown_cursor);
+            }
+            let _ = write!(serial, "{}\x1b[K", self.line);
+            let behind = self.line.len() - self.cursor;
+            if behind > 0 {
+                let _ = write!(serial, "\x1b[{}D", behind);
+            }
+        });
+        self.shown_cursor = self.cursor;
+    }
+
+    /// Ends the line and remembers it
+    fn finish(self) -> String {
+        without_interrupts(|| {
+            let _ = vga_buffer::WRITER.lock().write_str("\n");
+            let _ = serial::SERIAL_A.lock().write_str("\n");
+        });
+        let mut history = HISTORY.lock();
+        if !self.line.is_empty() && history.last() != Some(&self.line) {
+            if history.len() == HISTORY_LEN {
+                history.remove(0);
+            }
+            history.push(self.line.clone());
+        }
+        self.line
+    }
+}
+
+/// Reads a line from the keyboard and the input sources
+///
+/// Backspace and delete remove characters, the arrow keys, home and end
+/// move the cursor, up and down go through earlier lines. Halts while
+/// waiting, so interrupts must be enabled.
+pub fn read_line() -> String {
+    let mut editor = LineEditor::new();
+    loop {
+        match next_key() {
+            Key::Enter => return editor.finish(),
+            key => editor.apply(key),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! CPU hardening
+//!
+//! Turns on the protections that keep the kernel away from user memory:
+//! SMEP (no executing user pages), SMAP (no accessing user pages outside
+//! of `with_user_access`) and UMIP (no descriptor table instructions in
+//! user mode), as well as CR0.WP so read-only pages stay read-only for
+//! the kernel too.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::rflags::{self, RFlags};
+
+use crate::serial_println;
+
+/// CR4 bit enabling UMIP
+const CR4_UMIP: u64 = 1 << 11;
+/// CR4 bit enabling SMEP
+const CR4_SMEP: u64 = 1 << 20;
+/// CR4 bit enabling SMAP
+const CR4_SMAP: u64 = 1 << 21;
+
+/// Protections enabled by `harden`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hardening {
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
+    pub write_protect: bool,
+}
+
+impl Hardening {
+    fn to_bits(self) -> u8 {
+        self.smep as u8
+            | (self.smap as u8) << 1
+            | (self.umip as u8) << 2
+            | (self.write_protect as u8) << 3
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Hardening {
+            smep: bits & 1 != 0,
+            smap: bits & 1 << 1 != 0,
+            umip: bits & 1 << 2 != 0,
+            write_protect: bits & 1 << 3 != 0,
+        }
+    }
+}
+
+impl fmt::Display for Hardening {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = |on: bool| if on { "on" } else { "unsupported" };
+        write!(
+            f,
+            "SMEP {}, SMAP {}, UMIP {}, WP {}",
+            state(self.smep),
+            state(self.smap),
+            state(self.umip),
+            state(self.write_protect)
+        )
+    }
+}
+
+/// Set by `harden`
+static ENABLED: AtomicU8 = AtomicU8::new(0);
+
+/// Protections the CPU supports
+pub fn supported() -> Hardening {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let (ebx, ecx) = if max_leaf >= 7 {
+        let leaf = unsafe { __cpuid_count(7, 0) };
+        (leaf.ebx, leaf.ecx)
+    } else {
+        (0, 0)
+    };
+    Hardening {
+        smep: ebx & (1 << 7) != 0,
+        smap: ebx & (1 << 20) != 0,
+        umip: ecx & (1 << 2) != 0,
+        // Part of every x86_64 CPU
+        write_protect: true,
+    }
+}
+
+/// Enables every supported protection and reports them over serial
+///
+/// Called by `init`
+pub fn harden() -> Hardening {
+    let enabled = supported();
+
+    let mut cr4 = 0;
+    if enabled.smep {
+        cr4 |= CR4_SMEP;
+    }
+    if enabled.smap {
+        cr4 |= CR4_SMAP;
+    }
+    if enabled.umip {
+        cr4 |= CR4_UMIP;
+    }
+    unsafe {
+        // AC must be clear before SMAP takes effect
+        if enabled.smap {
+            clac();
+        }
+        write_cr4(read_cr4() | cr4);
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    ENABLED.store(enabled.to_bits(), Ordering::Relaxed);
+    serial_println!("[cpu] {}", enabled);
+    enabled
+}
+
+/// Protections enabled at boot
+pub fn enabled() -> Hardening {
+    Hardening::from_bits(ENABLED.load(Ordering::Relaxed))
+}
+
+/// Runs `f` with access to user pages allowed
+///
+/// For the few places that have to read or write user memory. Without
+/// SMAP `f` just runs. Nested calls keep access allowed until the
+/// outermost one returns.
+pub fn with_user_access<T>(f: impl FnOnce() -> T) -> T {
+    let smap = enabled().smap;
+    let already_allowed = rflags::read().contains(RFlags::ALIGNMENT_CHECK);
+    if smap && !already_allowed {
+        unsafe { stac() };
+    }
+    let result = f();
+    if smap && !already_allowed {
+        unsafe { clac() };
+    }
+    result
+}
+
+/// Current value of CR4
+pub fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr4, $0" : "=r"(value) ::: "volatile") };
+    value
+}
+
+/// Writes CR4
+///
+/// # Unsafe
+/// ---------
+/// CR4 controls paging and protection features: clearing or setting
+/// the wrong bit breaks memory safety
+/// ----------
+unsafe fn write_cr4(value: u64) {
+    asm!("mov $0, %cr4" :: "r"(value) : "memory" : "volatile");
+}
+
+/// Allows supervisor access to user pages
+unsafe fn stac() {
+    asm!("stac" ::: "cc" : "volatile");
+}
+
+/// Forbids supervisor access to user pages
+unsafe fn clac() {
+    asm!("clac" ::: "cc" : "volatile");
+}
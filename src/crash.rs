@@ -0,0 +1,342 @@
+//! Crash reports of CPU exceptions
+//!
+//! Every exception handler describes what happened in a `CrashReport`:
+//! the vector and its name, the decoded error code, the registers of
+//! the interrupted code, the control registers and a backtrace. Panics
+//! get the same treatment from `report_panic`. Reports go to both the
+//! VGA buffer and serial. Fatal ones take the writers over if the
+//! crashed code held them, the others skip a writer that is held.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+use crate::backtrace::{self, Backtrace, Registers};
+use crate::trap::Context;
+use crate::{cpu, monitor, serial, vga_buffer};
+
+/// Names and mnemonics of the architectural exceptions
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("Divide Error", "#DE"),
+    ("Debug", "#DB"),
+    ("Non-maskable Interrupt", "NMI"),
+    ("Breakpoint", "#BP"),
+    ("Overflow", "#OF"),
+    ("Bound Range Exceeded", "#BR"),
+    ("Invalid Opcode", "#UD"),
+    ("Device Not Available", "#NM"),
+    ("Double Fault", "#DF"),
+    ("Coprocessor Segment Overrun", "-"),
+    ("Invalid TSS", "#TS"),
+    ("Segment Not Present", "#NP"),
+    ("Stack-Segment Fault", "#SS"),
+    ("General Protection Fault", "#GP"),
+    ("Page Fault", "#PF"),
+    ("Reserved", "-"),
+    ("x87 Floating-Point Exception", "#MF"),
+    ("Alignment Check", "#AC"),
+    ("Machine Check", "#MC"),
+    ("SIMD Floating-Point Exception", "#XM"),
+    ("Virtualization Exception", "#VE"),
+    ("Control Protection Exception", "#CP"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Reserved", "-"),
+    ("Hypervisor Injection Exception", "#HV"),
+    ("VMM Communication Exception", "#VC"),
+    ("Security Exception", "#SX"),
+    ("Reserved", "-"),
+];
+
+/// Name of the exception `vector`
+pub fn exception_name(vector: u8) -> &'static str {
+    EXCEPTIONS
+        .get(usize::from(vector))
+        .map_or("Interrupt", |e| e.0)
+}
+
+/// Mnemonic of the exception `vector`, like `#GP`
+pub fn exception_mnemonic(vector: u8) -> &'static str {
+    EXCEPTIONS.get(usize::from(vector)).map_or("-", |e| e.1)
+}
+
+/// Descriptor table a selector error code points into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// Error code of #TS, #NP, #SS and #GP
+///
+/// Names the segment selector or IDT entry the fault is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// Raised while delivering an external event, like an interrupt
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// Entry in the table
+    pub fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A #GP not caused by a segment has no selector
+        if self.0 == 0 {
+            return f.write_str("0 (no selector)");
+        }
+        write!(
+            f,
+            "{:#x} ({:?} index {:#x}{})",
+            self.0,
+            self.table(),
+            self.index(),
+            if self.external() { ", external" } else { "" }
+        )
+    }
+}
+
+/// Error code pushed with an exception, decoded where the format is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The exception has no error code
+    None,
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultErrorCode),
+    /// Error code without a selector, always zero for #DF and #AC
+    Raw(u64),
+}
+
+impl ErrorCode {
+    /// Decodes the error code of #TS, #NP, #SS and #GP
+    pub fn selector(code: u64) -> Self {
+        ErrorCode::Selector(SelectorErrorCode(code))
+    }
+
+    /// Decodes the error code `code` the exception `vector` pushed
+    pub fn decode(vector: u8, code: u64) -> Self {
+        match vector {
+            10..=13 => ErrorCode::selector(code),
+            14 => ErrorCode::PageFault(PageFaultErrorCode::from_bits_truncate(code)),
+            8 | 17 | 30 => ErrorCode::Raw(code),
+            _ => ErrorCode::None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::None => f.write_str("none"),
+            ErrorCode::Selector(code) => write!(f, "{}", code),
+            ErrorCode::PageFault(code) => write!(f, "{:#x} {:?}", code.bits(), code),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// Context the CPU saved when the exception interrupted the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Control registers at the time of the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    /// Last page fault address
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        let (level_4, cr3_flags) = Cr3::read();
+        ControlRegisters {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: level_4.start_address().as_u64() | cr3_flags.bits(),
+            cr4: cpu::read_cr4(),
+            efer: Efer::read_raw(),
+        }
+    }
+}
+
+/// Everything known about an exception
+pub struct CrashReport<'a> {
+    pub vector: u8,
+    pub error_code: ErrorCode,
+    pub frame: TrapFrame,
+    /// Registers of the interrupted code
+    pub registers: Registers,
+    pub control: ControlRegisters,
+    pub backtrace: Backtrace,
+    /// What the handler found out, like the guard page that was hit
+    pub detail: Option<fmt::Arguments<'a>>,
+}
+
+impl<'a> CrashReport<'a> {
+    /// Report of an exception entered through a `trap` trampoline, with
+    /// the exact registers of the interrupted code
+    pub fn from_context(context: &Context) -> Self {
+        let frame = TrapFrame {
+            rip: context.rip,
+            cs: context.cs,
+            rflags: context.rflags,
+            rsp: context.rsp,
+            ss: context.ss,
+        };
+        let vector = context.vector as u8;
+        let registers = context.registers();
+        CrashReport {
+            vector,
+            error_code: ErrorCode::decode(vector, context.error_code),
+            frame,
+            registers,
+            control: ControlRegisters::read(),
+            backtrace: Backtrace::from_frame(Some(frame.rip), registers.rbp),
+            detail: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: fmt::Arguments<'a>) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+impl fmt::Display for CrashReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = &self.frame;
+        writeln!(
+            f,
+            "EXCEPTION {} {}: {}",
+            self.vector,
+            exception_mnemonic(self.vector),
+            exception_name(self.vector)
+        )?;
+        writeln!(f, "error code: {}", self.error_code)?;
+        if let Some(detail) = self.detail {
+            writeln!(f, "{}", detail)?;
+        }
+        writeln!(f, "cs {:#06x} ss {:#06x}", frame.cs, frame.ss)?;
+        write!(f, "{}{}{}", self.registers, self.control, self.backtrace)
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "   cr0 {:#018x}    cr2 {:#018x}    cr3 {:#018x}",
+            self.cr0, self.cr2, self.cr3
+        )?;
+        writeln!(f, "   cr4 {:#018x}   efer {:#018x}", self.cr4, self.efer)
+    }
+}
+
+/// Non-fatal reports that found both writers held
+static SKIPPED_REPORTS: AtomicU64 = AtomicU64::new(0);
+
+/// Writes to serial and the VGA buffer at once
+pub(crate) struct CrashWriter;
+
+impl Write for CrashWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut serial = serial::SERIAL_A.lock();
+        serial.write_str(s)?;
+        vga_buffer::WRITER.lock().write_str(s)
+    }
+}
+
+/// Unlocks the writers by force
+///
+/// Only called on the way down, a writer still locked then belongs to
+/// the code that crashed
+pub(crate) fn take_writers() {
+    unsafe {
+        serial::SERIAL_A.force_unlock();
+        vga_buffer::WRITER.force_unlock();
+    }
+}
+
+/// Prints `report` to VGA and serial and carries on
+///
+/// The interrupted code is resumed afterwards, so a writer it holds is
+/// left alone and that copy of the report is skipped. Returns whether
+/// the report was printed anywhere.
+pub fn report(report: &CrashReport) -> bool {
+    let mut printed = false;
+    if let Some(mut serial) = serial::SERIAL_A.try_lock() {
+        printed |= write!(serial, "\n{}", report).is_ok();
+    }
+    if let Some(mut vga) = vga_buffer::WRITER.try_lock() {
+        printed |= write!(vga, "\n{}", report).is_ok();
+    }
+    if !printed {
+        SKIPPED_REPORTS.fetch_add(1, Ordering::Relaxed);
+    }
+    printed
+}
+
+/// Reports `report` could print nowhere
+pub fn skipped_reports() -> u64 {
+    SKIPPED_REPORTS.load(Ordering::Relaxed)
+}
+
+/// Prints `info` with the registers, control registers and a backtrace
+/// of the panicking code to VGA and serial
+pub fn report_panic(info: &PanicInfo) {
+    let registers = backtrace::capture_registers();
+    let backtrace = Backtrace::capture();
+    take_writers();
+    let _ = write!(
+        CrashWriter,
+        "\nKERNEL PANIC: {}\n{}{}{}",
+        info,
+        registers,
+        ControlRegisters::read(),
+        backtrace
+    );
+}
+
+/// Prints `crash`, stops in the monitor if enabled and panics
+pub fn fatal(crash: CrashReport) -> ! {
+    take_writers();
+    let _ = write!(CrashWriter, "\n{}", crash);
+    monitor::enter_fatal(&crash);
+    panic!(
+        "fatal exception {} {}, error code {}",
+        exception_mnemonic(crash.vector),
+        exception_name(crash.vector),
+        crash.error_code
+    );
+}
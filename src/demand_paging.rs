@@ -0,0 +1,202 @@
+//! Demand paging
+//!
+//! Virtual regions registered as lazily backed aren't mapped up front.
+//! The first access to one of their pages raises a page fault, which
+//! `resolve_fault` answers by mapping a zeroed frame with the region's
+//! flags. Faults anywhere else are left to the fatal fault report.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags,
+    Size4KiB, UnusedPhysFrame,
+};
+use x86_64::VirtAddr;
+
+use crate::vma::{self, Purpose, VmaError};
+use crate::{memory, paging};
+
+/// Number of lazy regions that can be registered
+const MAX_LAZY_REGIONS: usize = 32;
+
+/// Region backed by frames on first touch
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// Exclusive end
+    pub end: VirtAddr,
+    /// Flags of the pages mapped in the region
+    pub flags: PageTableFlags,
+}
+
+impl LazyRegion {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+/// Registered lazy regions
+///
+/// Changed with interrupts disabled so the fault handler can read it
+static REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Errors registering a lazy region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// Start or size isn't page aligned, or the size is zero
+    Unaligned,
+    /// The region overlaps a registered one
+    Overlap,
+    /// No free region slot is left
+    TooManyRegions,
+    /// The address space manager refused the range
+    Vma(VmaError),
+    /// The flags ask for writable and executable pages
+    WritableExecutable,
+}
+
+/// Reasons a page fault couldn't be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address isn't in a lazy region
+    NotLazy,
+    /// The page is present or the access isn't allowed by the region flags
+    ProtectionViolation,
+    /// The kernel memory isn't installed or was locked when the fault hit
+    KernelMemoryUnavailable,
+    /// No frame was left to back the page
+    OutOfMemory,
+    /// The page table walk failed
+    MapFailed,
+}
+
+/// Registers `start..start + size` as lazily backed with the given flags
+///
+/// `PRESENT` is added to the flags. Writable regions must be `NO_EXECUTE`.
+pub fn register_lazy_region(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), RegionError> {
+    if size == 0 || start.as_u64() % Size4KiB::SIZE != 0 || size % Size4KiB::SIZE != 0 {
+        return Err(RegionError::Unaligned);
+    }
+    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+        return Err(RegionError::WritableExecutable);
+    }
+    let region = LazyRegion {
+        name,
+        start,
+        end: start + size,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+
+    let registered = interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let overlaps = regions
+            .iter()
+            .flatten()
+            .any(|r| region.start < r.end && r.start < region.end);
+        if overlaps {
+            return Err(RegionError::Overlap);
+        }
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(RegionError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(())
+    });
+    registered?;
+
+    let reserved = vma::reserve(name, start, size, region.flags, Purpose::Lazy);
+    if let Err(err) = reserved {
+        unregister_lazy_region(start);
+        return Err(match err {
+            VmaError::Overlap => RegionError::Overlap,
+            err => RegionError::Vma(err),
+        });
+    }
+    Ok(())
+}
+
+/// Forgets the lazy region starting at `start`
+///
+/// Pages already backed stay mapped
+pub fn unregister_lazy_region(start: VirtAddr) -> Option<LazyRegion> {
+    let region = interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.map_or(false, |r| r.start == start))?;
+        slot.take()
+    })?;
+    let _ = vma::release(start);
+    Some(region)
+}
+
+/// Lazy region containing `addr`
+pub fn lazy_region(addr: VirtAddr) -> Option<LazyRegion> {
+    let regions = REGIONS.try_lock()?;
+    regions.iter().flatten().find(|r| r.contains(addr)).copied()
+}
+
+/// Tries to resolve a page fault at `addr` by backing the page
+///
+/// Called from the page fault handler
+pub fn resolve_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    let region = lazy_region(addr).ok_or(FaultError::NotLazy)?;
+
+    let forbidden = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        || (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !region.flags.contains(PageTableFlags::WRITABLE))
+        || (error_code.contains(PageFaultErrorCode::USER_MODE)
+            && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+        || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && region.flags.contains(PageTableFlags::NO_EXECUTE));
+    if forbidden {
+        return Err(FaultError::ProtectionViolation);
+    }
+
+    // The fault may have hit while the kernel memory was being changed
+    let mut kernel_memory = memory::KERNEL_MEMORY
+        .try_lock()
+        .ok_or(FaultError::KernelMemoryUnavailable)?;
+    let kernel_memory = kernel_memory
+        .as_mut()
+        .ok_or(FaultError::KernelMemoryUnavailable)?;
+
+    let frame = kernel_memory
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(FaultError::OutOfMemory)?;
+    let phys_frame = *frame;
+    let frame_ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, Size4KiB::SIZE as usize) };
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let map_to_result = kernel_memory.mapper.map_to(
+        page,
+        frame,
+        paging::effective_flags(region.flags),
+        &mut kernel_memory.frame_allocator,
+    );
+    let result = match map_to_result {
+        Ok(flush) => {
+            flush.flush();
+            return Ok(());
+        }
+        // Resolved by someone else in the meantime
+        Err(MapToError::PageAlreadyMapped) => Ok(()),
+        Err(_) => Err(FaultError::MapFailed),
+    };
+    // The frame wasn't mapped
+    kernel_memory
+        .frame_allocator
+        .deallocate_frame(unsafe { UnusedPhysFrame::new(phys_frame) });
+    result
+}
@@ -0,0 +1,89 @@
+//! QEMU firmware configuration device
+//!
+//! QEMU hands named files to the guest, given on its command line with
+//! `-fw_cfg name=opt/<name>,string=<value>` or `file=<path>`. They are
+//! read one byte at a time through the selector and data ports, so boot
+//! options can change without rebuilding the kernel.
+//!
+//! Without the device the data port reads back `0xff` and nothing is
+//! found.
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+/// Item holding `SIGNATURE`
+const SIGNATURE_KEY: u16 = 0x0000;
+/// Item listing the files
+const FILE_DIR_KEY: u16 = 0x0019;
+const SIGNATURE: &[u8; 4] = b"QEMU";
+/// Bytes of a file name in the directory, NUL padded
+const NAME_LEN: usize = 56;
+
+/// Selecting an item restarts reading it, so readers take turns
+static DEVICE: Mutex<()> = Mutex::new(());
+
+/// Starts reading the item `key` from its first byte
+fn select(key: u16) {
+    unsafe { Port::<u16>::new(SELECTOR_PORT).write(key) }
+}
+
+/// Next byte of the selected item
+fn read_byte() -> u8 {
+    unsafe { Port::<u8>::new(DATA_PORT).read() }
+}
+
+fn read_bytes(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        *byte = read_byte();
+    }
+}
+
+/// Directory fields are big endian
+fn read_be(len: usize) -> u32 {
+    (0..len).fold(0, |value, _| value << 8 | u32::from(read_byte()))
+}
+
+/// Whether QEMU's configuration device answers
+pub fn is_present() -> bool {
+    let _device = DEVICE.lock();
+    select(SIGNATURE_KEY);
+    let mut signature = [0; 4];
+    read_bytes(&mut signature);
+    &signature == SIGNATURE
+}
+
+/// Reads the file `name` into `buf`
+///
+/// Returns the bytes read, at most `buf.len()`, or `None` when there
+/// is no such file.
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    if name.len() >= NAME_LEN || !is_present() {
+        return None;
+    }
+    let _device = DEVICE.lock();
+    select(FILE_DIR_KEY);
+    let count = read_be(4);
+    let mut found = None;
+    for _ in 0..count {
+        let size = read_be(4) as usize;
+        let key = read_be(2) as u16;
+        let _reserved = read_be(2);
+        let mut entry_name = [0; NAME_LEN];
+        read_bytes(&mut entry_name);
+
+        let len = entry_name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        if &entry_name[..len] == name.as_bytes() {
+            found = Some((key, size));
+            break;
+        }
+    }
+
+    let (key, size) = found?;
+    let len = size.min(buf.len());
+    select(key);
+    read_bytes(&mut buf[..len]);
+    Some(len)
+}
@@ -0,0 +1,594 @@
+//! GDB remote serial protocol stub
+//!
+//! Lets `gdb` debug the kernel over COM2. Start QEMU with a second
+//! serial port, like `-serial stdio -serial tcp::1234,server,nowait`,
+//! call `init` and attach with `target remote :1234`. The kernel stops
+//! in the debugger on `breakpoint()`, on breakpoints set by gdb and
+//! after single steps.
+//!
+//! Supported packets: `?`, `g`, `G`, `m`, `M`, `c`, `s`, `Z0`, `z0`,
+//! `qSupported`, `k` and `D`. Everything else gets the empty reply,
+//! which tells gdb it isn't supported.
+//!
+//! Breakpoints patch `int3` into code. The kernel image is read-only
+//! after `kernel_image::protect`, so writes go through with CR0.WP
+//! cleared.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+use crate::backtrace::Registers;
+use crate::trap::{Context, BREAKPOINT_VECTOR, RFLAGS_TRAP};
+use crate::uart::{self, ComPort, UartConfig};
+use crate::{paging, serial_println};
+
+/// Port the stub talks on, at 115200 baud
+pub const GDB_PORT: ComPort = ComPort::Com2;
+
+/// Largest packet exchanged, announced in `qSupported`
+const PACKET_SIZE: usize = 1024;
+/// Breakpoints set at once
+pub const MAX_BREAKPOINTS: usize = 32;
+/// The `int3` instruction
+const INT3: u8 = 0xcc;
+/// Bytes of the `g` packet: 17 64 bit and 7 32 bit registers
+const REGISTER_BYTES: usize = 17 * 8 + 7 * 4;
+
+/// Byte stream to the debugger
+pub trait Connection: Send {
+    /// Waits for the next byte
+    fn read_byte(&mut self) -> u8;
+    fn write_byte(&mut self, byte: u8);
+}
+
+/// Reasons the stub couldn't start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdbError {
+    /// No UART answers at the port
+    NoSerialPort(ComPort),
+}
+
+/// COM port polled by the stub
+struct UartConnection(ComPort);
+
+impl Connection for UartConnection {
+    fn read_byte(&mut self) -> u8 {
+        uart::port(self.0).lock().receive()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        uart::port(self.0).lock().send(byte)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// Byte `int3` replaced
+    original: u8,
+}
+
+/// The debugger's connection, `None` when detached
+static CONNECTION: Mutex<Option<Box<dyn Connection>>> = Mutex::new(None);
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Mutex::new([None; MAX_BREAKPOINTS]);
+/// Set once gdb sent its first packet, it expects stop replies then
+static TALKING: AtomicBool = AtomicBool::new(false);
+
+/// Starts the stub on COM2
+///
+/// The kernel runs on until the next `breakpoint()`
+pub fn init() -> Result<(), GdbError> {
+    let config = UartConfig {
+        baud: uart::MAX_BAUD,
+        ..UartConfig::default()
+    };
+    uart::port(GDB_PORT)
+        .lock()
+        .configure(config)
+        .map_err(|_| GdbError::NoSerialPort(GDB_PORT))?;
+    attach(Box::new(UartConnection(GDB_PORT)));
+    serial_println!("[gdb] waiting on {:?} ({:#x})", GDB_PORT, GDB_PORT.base());
+    Ok(())
+}
+
+/// Hands breakpoint and debug exceptions to the debugger at `connection`
+pub fn attach(connection: Box<dyn Connection>) {
+    without_interrupts(|| {
+        *CONNECTION.lock() = Some(connection);
+    });
+    TALKING.store(false, Ordering::Relaxed);
+}
+
+/// Stops the stub, removing its breakpoints
+pub fn detach() {
+    without_interrupts(|| {
+        remove_breakpoints();
+        *CONNECTION.lock() = None;
+    });
+}
+
+/// Whether a debugger is attached
+pub fn is_attached() -> bool {
+    CONNECTION.lock().is_some()
+}
+
+/// Stops in the debugger
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Runs the debugger session for a trap, false when none is attached
+pub(crate) fn handle_trap(context: &mut Context) -> bool {
+    // Locked when the trap hit the stub itself
+    let mut slot = match CONNECTION.try_lock() {
+        Some(slot) => slot,
+        None => return false,
+    };
+    let detached = match slot.as_mut() {
+        Some(connection) => Session {
+            connection: &mut **connection,
+            context,
+        }
+        .run(),
+        None => return false,
+    };
+    if detached {
+        *slot = None;
+    }
+    true
+}
+
+/// Reply being built
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Reply {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> &mut Self {
+        let end = (self.len + bytes.len()).min(PACKET_SIZE);
+        self.buf[self.len..end].copy_from_slice(&bytes[..end - self.len]);
+        self.len = end;
+        self
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) -> &mut Self {
+        for &byte in bytes {
+            self.push(&[hex_digit(byte >> 4), hex_digit(byte & 0xf)]);
+        }
+        self
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// What the debugger asked for after a command
+enum Next {
+    /// Reply and wait for the next command
+    Reply,
+    /// Resume the kernel
+    Resume,
+    /// Resume the kernel and drop the connection
+    Detach,
+}
+
+struct Session<'a> {
+    connection: &'a mut dyn Connection,
+    context: &'a mut Context,
+}
+
+impl Session<'_> {
+    /// Reports the stop and serves commands until the kernel resumes
+    ///
+    /// True when the debugger detached
+    fn run(&mut self) -> bool {
+        self.context.rflags &= !RFLAGS_TRAP;
+        let at_breakpoint = self.context.vector == BREAKPOINT_VECTOR
+            && breakpoint_at(self.context.rip.wrapping_sub(1)).is_some();
+        // Resume at the patched instruction, not after the int3
+        if at_breakpoint {
+            self.context.rip -= 1;
+        }
+        if TALKING.load(Ordering::Relaxed) {
+            self.send(stop_reply(at_breakpoint));
+        }
+
+        let mut packet = [0; PACKET_SIZE];
+        loop {
+            let len = self.receive(&mut packet);
+            TALKING.store(true, Ordering::Relaxed);
+            let mut reply = Reply::new();
+            match self.command(&packet[..len], &mut reply, at_breakpoint) {
+                Next::Reply => self.send(reply.as_bytes()),
+                Next::Resume => return false,
+                Next::Detach => {
+                    remove_breakpoints();
+                    TALKING.store(false, Ordering::Relaxed);
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Carries out `packet`, filling `reply`
+    fn command(&mut self, packet: &[u8], reply: &mut Reply, at_breakpoint: bool) -> Next {
+        let (&kind, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return Next::Reply,
+        };
+        match kind {
+            b'?' => {
+                reply.push(stop_reply(at_breakpoint));
+            }
+            b'g' => {
+                reply.push_hex(&register_bytes(&self.context.registers(), self.context));
+            }
+            b'G' => {
+                let mut bytes = [0; REGISTER_BYTES];
+                match decode_hex(args, &mut bytes) {
+                    Some(REGISTER_BYTES) => {
+                        let registers = registers_from_bytes(&bytes);
+                        self.context.set_registers(&registers);
+                        reply.push(b"OK");
+                    }
+                    _ => {
+                        reply.push(b"E22");
+                    }
+                }
+            }
+            b'm' => match parse_range(args) {
+                Some((addr, len)) if len <= (PACKET_SIZE - 4) / 2 => {
+                    let mut bytes = [0; PACKET_SIZE / 2];
+                    if read_memory(addr, &mut bytes[..len]) {
+                        reply.push_hex(&bytes[..len]);
+                    } else {
+                        reply.push(b"E14");
+                    }
+                }
+                _ => {
+                    reply.push(b"E22");
+                }
+            },
+            b'M' => {
+                let split = args.iter().position(|&b| b == b':');
+                let parsed = split.and_then(|colon| {
+                    let (addr, len) = parse_range(&args[..colon])?;
+                    let mut bytes = [0; PACKET_SIZE / 2];
+                    let data = &args[colon + 1..];
+                    if len > bytes.len() || decode_hex(data, &mut bytes)? != len {
+                        return None;
+                    }
+                    Some((addr, bytes, len))
+                });
+                match parsed {
+                    Some((addr, bytes, len)) if write_memory(addr, &bytes[..len]) => {
+                        reply.push(b"OK");
+                    }
+                    Some(_) => {
+                        reply.push(b"E14");
+                    }
+                    None => {
+                        reply.push(b"E22");
+                    }
+                }
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    self.context.rip = addr;
+                }
+                if kind == b's' {
+                    self.context.rflags |= RFLAGS_TRAP;
+                }
+                return Next::Resume;
+            }
+            b'Z' | b'z' if args.starts_with(b"0,") => {
+                let result = parse_range(&args[2..]).and_then(|(addr, _)| {
+                    if kind == b'Z' {
+                        insert_breakpoint(addr)
+                    } else {
+                        remove_breakpoint(addr)
+                    }
+                });
+                reply.push(if result.is_some() { b"OK" } else { b"E14" });
+            }
+            b'q' if args.starts_with(b"Supported") => {
+                reply.push(b"PacketSize=400;swbreak+");
+            }
+            b'q' if args.starts_with(b"Attached") => {
+                reply.push(b"1");
+            }
+            b'H' => {
+                reply.push(b"OK");
+            }
+            // The kernel can't be killed, gdb just goes away
+            b'k' => return Next::Detach,
+            b'D' => {
+                self.send(b"OK");
+                return Next::Detach;
+            }
+            _ => {}
+        }
+        Next::Reply
+    }
+
+    /// Waits for a packet with a valid checksum and acknowledges it
+    ///
+    /// Returns the length of its data in `buf`
+    fn receive(&mut self, buf: &mut [u8]) -> usize {
+        loop {
+            while self.connection.read_byte() != b'$' {}
+
+            let mut len = 0;
+            let mut sum = 0u8;
+            loop {
+                let byte = self.connection.read_byte();
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                if len < buf.len() {
+                    buf[len] = byte;
+                    len += 1;
+                }
+            }
+            let high = hex_value(self.connection.read_byte());
+            let low = hex_value(self.connection.read_byte());
+            if let (Some(high), Some(low)) = (high, low) {
+                if high << 4 | low == sum {
+                    self.connection.write_byte(b'+');
+                    return len;
+                }
+            }
+            self.connection.write_byte(b'-');
+        }
+    }
+
+    /// Sends `data` as a packet until the debugger acknowledges it
+    fn send(&mut self, data: &[u8]) {
+        let sum = checksum(data);
+        loop {
+            self.connection.write_byte(b'$');
+            for &byte in data {
+                self.connection.write_byte(byte);
+            }
+            self.connection.write_byte(b'#');
+            self.connection.write_byte(hex_digit(sum >> 4));
+            self.connection.write_byte(hex_digit(sum & 0xf));
+
+            loop {
+                match self.connection.read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Checksum of packet data, the sum of its bytes
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn stop_reply(at_breakpoint: bool) -> &'static [u8] {
+    // SIGTRAP, with swbreak gdb doesn't adjust the PC itself
+    if at_breakpoint {
+        b"T05swbreak:;"
+    } else {
+        b"S05"
+    }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[usize::from(value & 0xf)]
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | u64::from(hex_value(digit)?))
+    })
+}
+
+/// Parses `addr,len`
+fn parse_range(args: &[u8]) -> Option<(u64, usize)> {
+    let comma = args.iter().position(|&b| b == b',')?;
+    let addr = parse_hex(&args[..comma])?;
+    let len = parse_hex(&args[comma + 1..])?;
+    Some((addr, len as usize))
+}
+
+/// Decodes hex pairs into `bytes`, returning how many were decoded
+fn decode_hex(digits: &[u8], bytes: &mut [u8]) -> Option<usize> {
+    if digits.len() % 2 != 0 || digits.len() / 2 > bytes.len() {
+        return None;
+    }
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        *byte = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
+
+/// Registers in the order of gdb's amd64 description
+///
+/// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15 and rip take 8 bytes,
+/// eflags, cs, ss, ds, es, fs and gs 4 bytes
+fn register_bytes(registers: &Registers, context: &Context) -> [u8; REGISTER_BYTES] {
+    let long = [
+        registers.rax,
+        registers.rbx,
+        registers.rcx,
+        registers.rdx,
+        registers.rsi,
+        registers.rdi,
+        registers.rbp,
+        registers.rsp,
+        registers.r8,
+        registers.r9,
+        registers.r10,
+        registers.r11,
+        registers.r12,
+        registers.r13,
+        registers.r14,
+        registers.r15,
+        registers.rip,
+    ];
+    // The data segments are all null in long mode
+    let short = [registers.rflags, context.cs, context.ss, 0, 0, 0, 0];
+
+    let mut bytes = [0; REGISTER_BYTES];
+    let (long_bytes, short_bytes) = bytes.split_at_mut(long.len() * 8);
+    for (chunk, value) in long_bytes.chunks_mut(8).zip(long.iter()) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    for (chunk, &value) in short_bytes.chunks_mut(4).zip(short.iter()) {
+        chunk.copy_from_slice(&(value as u32).to_le_bytes());
+    }
+    bytes
+}
+
+/// Inverse of `register_bytes`, segment registers are ignored
+fn registers_from_bytes(bytes: &[u8; REGISTER_BYTES]) -> Registers {
+    let long = |i: usize| {
+        let mut value = [0; 8];
+        value.copy_from_slice(&bytes[i * 8..i * 8 + 8]);
+        u64::from_le_bytes(value)
+    };
+    let mut rflags = [0; 4];
+    rflags.copy_from_slice(&bytes[17 * 8..17 * 8 + 4]);
+    Registers {
+        rax: long(0),
+        rbx: long(1),
+        rcx: long(2),
+        rdx: long(3),
+        rsi: long(4),
+        rdi: long(5),
+        rbp: long(6),
+        rsp: long(7),
+        r8: long(8),
+        r9: long(9),
+        r10: long(10),
+        r11: long(11),
+        r12: long(12),
+        r13: long(13),
+        r14: long(14),
+        r15: long(15),
+        rip: long(16),
+        rflags: u64::from(u32::from_le_bytes(rflags)),
+    }
+}
+
+/// Reads kernel memory, showing the original bytes under breakpoints
+fn read_memory(addr: u64, bytes: &mut [u8]) -> bool {
+    if !paging::is_mapped(addr, bytes.len() as u64) {
+        return false;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let at = addr + i as u64;
+        *byte = match breakpoint_at(at) {
+            Some(breakpoint) => breakpoint.original,
+            None => unsafe { (at as *const u8).read_volatile() },
+        };
+    }
+    true
+}
+
+/// Writes kernel memory, read-only pages included
+fn write_memory(addr: u64, bytes: &[u8]) -> bool {
+    if !paging::is_mapped(addr, bytes.len() as u64) {
+        return false;
+    }
+    with_write_protect_off(|| {
+        for (i, &byte) in bytes.iter().enumerate() {
+            unsafe { ((addr + i as u64) as *mut u8).write_volatile(byte) };
+        }
+    });
+    true
+}
+
+/// Runs `f` with CR0.WP cleared, so read-only pages take writes
+fn with_write_protect_off<T>(f: impl FnOnce() -> T) -> T {
+    let protected = Cr0::read().contains(Cr0Flags::WRITE_PROTECT);
+    if protected {
+        unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::WRITE_PROTECT)) };
+    }
+    let result = f();
+    if protected {
+        unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+    }
+    result
+}
+
+fn breakpoint_at(addr: u64) -> Option<Breakpoint> {
+    let breakpoints = BREAKPOINTS.try_lock()?;
+    breakpoints
+        .iter()
+        .flatten()
+        .find(|b| b.addr == addr)
+        .copied()
+}
+
+/// Patches `int3` at `addr`
+fn insert_breakpoint(addr: u64) -> Option<()> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    if breakpoints.iter().flatten().any(|b| b.addr == addr) {
+        return Some(());
+    }
+    let slot = breakpoints.iter_mut().find(|b| b.is_none())?;
+    if !paging::is_mapped(addr, 1) {
+        return None;
+    }
+    let original = unsafe { (addr as *const u8).read_volatile() };
+    with_write_protect_off(|| unsafe { (addr as *mut u8).write_volatile(INT3) });
+    *slot = Some(Breakpoint { addr, original });
+    Some(())
+}
+
+/// Restores the byte under the breakpoint at `addr`
+fn remove_breakpoint(addr: u64) -> Option<()> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = breakpoints
+        .iter_mut()
+        .find(|b| b.map_or(false, |b| b.addr == addr))?;
+    if let Some(breakpoint) = slot.take() {
+        restore(breakpoint);
+    }
+    Some(())
+}
+
+fn remove_breakpoints() {
+    let mut breakpoints = BREAKPOINTS.lock();
+    for breakpoint in breakpoints.iter_mut().filter_map(Option::take) {
+        restore(breakpoint);
+    }
+}
+
+fn restore(breakpoint: Breakpoint) {
+    let addr = breakpoint.addr as *mut u8;
+    with_write_protect_off(|| unsafe { addr.write_volatile(breakpoint.original) });
+}
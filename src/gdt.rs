@@ -1,5 +1,6 @@
 //! Handles implementations for the Global Descriptor Table
 
+use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -7,12 +8,16 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_IDX: u16 = 0;
 
+/// Size of the interrupt stacks used until `stack::init_ist_stacks`
+/// maps guarded ones
+const BOOT_STACK_SIZE: usize = 4096;
+
 lazy_static! {
     /// Global DescriptorTable instance
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (gdt, Selectors {code_selector, tss_selector})
     };
 }
@@ -24,22 +29,31 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+/// The TSS, changed in place by `set_ist_stack`
+///
+/// The CPU reads it behind our back, so it's only ever accessed through
+/// the raw pointer of the cell. `set_ist_stack` is the only writer and
+/// no reference into it is kept.
+struct TssCell(UnsafeCell<TaskStateSegment>);
+
+// Only written by `set_ist_stack` with interrupts disabled
+unsafe impl Sync for TssCell {}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: TssCell = {
         let mut tss = TaskStateSegment::new();
 
         // Write address of Double fault stack to entry 0
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_IDX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
             let stack_entry = VirtAddr::from_ptr(unsafe { &STACK });// Accessing mut static
-            let stack_end = stack_entry + STACK_SIZE;
+            let stack_end = stack_entry + BOOT_STACK_SIZE;
 
             // Stacks grow downwards, so write the top address
             stack_end
         };
-        tss
+        TssCell(UnsafeCell::new(tss))
     };
 }
 
@@ -55,3 +69,20 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Points an interrupt stack table entry at a new stack
+///
+/// # Unsafe
+/// ---------
+/// `stack_top` must be the top of a mapped stack that stays valid
+/// and the stack being replaced must not be in use
+/// ----------
+pub unsafe fn set_ist_stack(index: u16, stack_top: VirtAddr) {
+    use x86_64::instructions::interrupts;
+
+    // The CPU reads the IST from the loaded TSS on every interrupt,
+    // so updating the entry in place is enough
+    interrupts::without_interrupts(|| {
+        (*TSS.0.get()).interrupt_stack_table[index as usize] = stack_top;
+    })
+}
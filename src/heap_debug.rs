@@ -0,0 +1,179 @@
+//! Heap debugging layer
+//!
+//! Wraps the global allocator when the `heap-debug` feature is on.
+//! Each allocation is surrounded by red zones that are checked when it
+//! is freed, freed memory is poisoned and parked in a quarantine before
+//! going back to the real allocator, and frees of memory that isn't a
+//! live allocation are reported over serial.
+//!
+//! Block layout:
+//! `[padding][Header][front red zone][user data][rear red zone]`
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use spin::Mutex;
+
+use crate::serial_println;
+
+/// Bytes of each red zone
+const RED_ZONE: usize = 16;
+/// Red zone fill byte
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// Fill byte of freed memory
+const POISON_BYTE: u8 = 0x6b;
+/// Number of freed blocks held back from the real allocator
+pub const QUARANTINE_SIZE: usize = 64;
+
+/// Header magic of a live allocation
+const MAGIC_LIVE: u64 = 0x_a110_ca7e_d0c5_1234;
+/// Header magic of a quarantined allocation
+const MAGIC_FREED: u64 = 0x_dead_f4ee_d0c5_1234;
+
+/// Bookkeeping in front of each allocation
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+/// Freed block waiting in the quarantine
+#[derive(Clone, Copy)]
+struct Quarantined {
+    user: *mut u8,
+    layout: Layout,
+}
+
+/// Ring of recently freed blocks
+struct Quarantine {
+    blocks: [Option<Quarantined>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+// Quarantined pointers are only touched with the lock held
+unsafe impl Send for Quarantine {}
+
+/// Debugging wrapper around a global allocator
+pub struct DebugAlloc<A> {
+    inner: A,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl<A: GlobalAlloc> DebugAlloc<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugAlloc {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+        }
+    }
+
+    /// The wrapped allocator
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+/// Offset of the user data from the start of the real block
+fn user_offset(layout: &Layout) -> usize {
+    let front = mem::size_of::<Header>() + RED_ZONE;
+    let align = layout.align();
+    (front + align - 1) & !(align - 1)
+}
+
+/// Layout of the real block backing a user allocation
+fn outer_layout(layout: &Layout) -> Option<Layout> {
+    let size = user_offset(layout) + layout.size() + RED_ZONE;
+    let align = layout.align().max(mem::align_of::<Header>());
+    Layout::from_size_align(size, align).ok()
+}
+
+unsafe fn header(user: *mut u8) -> *mut Header {
+    user.sub(RED_ZONE + mem::size_of::<Header>()) as *mut Header
+}
+
+/// Whether all `len` bytes at `ptr` equal `byte`
+unsafe fn is_filled(ptr: *const u8, len: usize, byte: u8) -> bool {
+    (0..len).all(|i| *ptr.add(i) == byte)
+}
+
+/// Reports a heap corruption and stops the kernel
+fn report(kind: &str, ptr: *mut u8, layout: &Layout) -> ! {
+    serial_println!("[heap-debug] {} at {:p}", kind, ptr);
+    serial_println!("[heap-debug] layout: {:?}", layout);
+    panic!("Heap corruption: {} at {:p}", kind, ptr);
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let outer = match outer_layout(&layout) {
+            Some(outer) => outer,
+            None => return core::ptr::null_mut(),
+        };
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let user = base.add(user_offset(&layout));
+        header(user).write(Header {
+            magic: MAGIC_LIVE,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        user.sub(RED_ZONE).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        user.add(layout.size()).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        user
+    }
+
+    unsafe fn dealloc(&self, user: *mut u8, layout: Layout) {
+        if user.is_null() || user as usize % layout.align() != 0 {
+            report("invalid free", user, &layout);
+        }
+
+        let header = header(user);
+        match (*header).magic {
+            MAGIC_LIVE => {}
+            MAGIC_FREED => report("double free", user, &layout),
+            _ => report("invalid free", user, &layout),
+        }
+        if (*header).size != layout.size() || (*header).align != layout.align() {
+            serial_println!(
+                "[heap-debug] allocated with size {} align {}",
+                (*header).size,
+                (*header).align
+            );
+            report("free with mismatched layout", user, &layout);
+        }
+        if !is_filled(user.sub(RED_ZONE), RED_ZONE, RED_ZONE_BYTE) {
+            report("heap underflow", user, &layout);
+        }
+        if !is_filled(user.add(layout.size()), RED_ZONE, RED_ZONE_BYTE) {
+            report("heap overflow", user, &layout);
+        }
+
+        (*header).magic = MAGIC_FREED;
+        user.write_bytes(POISON_BYTE, layout.size());
+
+        // Park the block, releasing the oldest one in its place
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let slot = quarantine.next;
+            quarantine.next = (slot + 1) % QUARANTINE_SIZE;
+            core::mem::replace(
+                &mut quarantine.blocks[slot],
+                Some(Quarantined { user, layout }),
+            )
+        };
+        if let Some(block) = evicted {
+            if !is_filled(block.user, block.layout.size(), POISON_BYTE) {
+                report("write after free", block.user, &block.layout);
+            }
+            let base = block.user.sub(user_offset(&block.layout));
+            let outer = outer_layout(&block.layout).unwrap();
+            self.inner.dealloc(base, outer);
+        }
+    }
+}
@@ -1,14 +1,27 @@
 /// Interrupts
 ///
-use crate::{gdt, halt_loop, println};
+use crate::backtrace;
+use crate::crash::{self, CrashReport};
+use crate::trap::{self, Context};
+use crate::{apic, demand_paging, gdt, keyboard, monitor, println, stack};
 use lazy_static::lazy_static;
 
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Number of PIC lines
+pub const IRQ_LINES: usize = 16;
+/// Handlers that can share one line
+pub const MAX_SHARED_HANDLERS: usize = 4;
+/// Line of the master PIC the slave is chained to
+const CASCADE_LINE: u8 = 2;
+
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -25,113 +38,474 @@ pub enum InterruptIndex {
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    /// PIC line of the interrupt
+    pub fn line(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
+/// Vector the PIC line `line` is delivered at
+pub fn irq_vector(line: u8) -> u8 {
+    PIC_1_OFFSET + line
+}
+
+/// Generates the IDT entry of every PIC line
+///
+/// Each stub hands its line to `dispatch_irq`
+macro_rules! irq_stubs {
+    ($($line:literal => $stub:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $stub(_stack_frame: &mut InterruptStackFrame) {
+                dispatch_irq($line);
+            }
+        )*
+
+        /// IDT entries of the PIC lines, indexed by line
+        const IRQ_STUBS: [HandlerFunc; IRQ_LINES] = [$($stub),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq0_handler,
+    1 => irq1_handler,
+    2 => irq2_handler,
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    7 => irq7_handler,
+    8 => irq8_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler,
+    14 => irq14_handler,
+    15 => irq15_handler,
+}
+
 lazy_static! {
     /// Interrupt DT
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_error.set_handler_fn(trap::entry(0));
+        idt.debug.set_handler_fn(trap::entry(1));
+        idt.non_maskable_interrupt.set_handler_fn(trap::entry(2));
+        idt.breakpoint.set_handler_fn(trap::entry(3));
+        idt.overflow.set_handler_fn(trap::entry(4));
+        idt.bound_range_exceeded.set_handler_fn(trap::entry(5));
+        idt.invalid_opcode.set_handler_fn(trap::entry(6));
+        idt.device_not_available.set_handler_fn(trap::entry(7));
         unsafe {
-        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_IDX);
+        idt.double_fault.set_handler_fn(trap::entry(8)).set_stack_index(gdt::DOUBLE_FAULT_IST_IDX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_er_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // Not on an interrupt stack: faults are resolved in place and may
+        // nest, and guard page hits escalate to the double fault
+        idt.page_fault.set_handler_fn(trap::entry(14));
+        idt.invalid_tss.set_handler_fn(trap::entry(10));
+        idt.segment_not_present.set_handler_fn(trap::entry(11));
+        idt.stack_segment_fault.set_handler_fn(trap::entry(12));
+        idt.general_protection_fault.set_handler_fn(trap::entry(13));
+        idt.x87_floating_point.set_handler_fn(trap::entry(16));
+        idt.alignment_check.set_handler_fn(trap::entry(17));
+        idt.machine_check.set_handler_fn(trap::entry(18));
+        idt.simd_floating_point.set_handler_fn(trap::entry(19));
+        idt.virtualization.set_handler_fn(trap::entry(20));
+        idt.security_exception.set_handler_fn(trap::entry(30));
+        for (line, &stub) in IRQ_STUBS.iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + line].set_handler_fn(stub);
+        }
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
 
 /// Creates the IDT
+/// and registers the timer and keyboard handlers
 pub fn init_idt() -> () {
     IDT.load();
+    register_irq(InterruptIndex::Timer.line(), timer_interrupt)
+        .expect("Failed to register the timer handler");
+    register_irq(InterruptIndex::Keyboard.line(), keyboard_interrupt)
+        .expect("Failed to register the keyboard handler");
 }
 
-/// Handles breakpoint Exceptions
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("Oops! Exception:\n\t{:#?}", stack_frame);
+/// Handles breakpoint Exceptions no debugger took
+pub(crate) fn breakpoint_trap(context: &mut Context) {
+    println!("Oops! Exception:\n\t{:#?}", context);
 }
 
-/// Handles Double Faults
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("Exception: Double Fault\n{:#?}", stack_frame);
+/// Reports debug exceptions no debugger took and carries on
+pub(crate) fn debug_trap(context: &mut Context) {
+    crash::report(&CrashReport::from_context(context));
 }
 
-/// Handler function for the timer interrupt
-/// Implements the CPU reaction to the timer exception
-extern "x86-interrupt" fn timer_er_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    println!("Timer interrupt");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// Handles the exceptions other than debug and breakpoint
+///
+/// Only non-maskable interrupts and page faults can be recovered from.
+/// The reserved vectors, coprocessor segment overrun (9) and the
+/// exceptions added after x86_64 0.8 (21, 28, 29) have no public IDT
+/// entry and can't get a handler. The CPU doesn't raise them here.
+pub(crate) fn exception(context: &mut Context) {
+    match context.vector {
+        // Reported, the interrupted code carries on
+        2 => {
+            crash::report(&CrashReport::from_context(context));
+        }
+        8 => double_fault(context),
+        14 => page_fault(context),
+        // Machine checks leave the hardware in an unknown state
+        _ => crash::fatal(CrashReport::from_context(context)),
     }
 }
 
-/// Handles Keyboard interrupts
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-    use x86_64::instructions::port::Port;
+/// Handles Double Faults
+fn double_fault(context: &Context) -> ! {
+    use x86_64::registers::control::Cr2;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+    let report = CrashReport::from_context(context);
+    // A fault on a guard page escalates here when the faulting
+    // stack can't take the page fault frame
+    match stack::guard_hit(Cr2::read()) {
+        Some(hit) => crash::fatal(report.with_detail(format_args!("{}", hit))),
+        None => crash::fatal(report),
     }
-    // Read data from PS/2 controller: port number 0x60
-    let mut port = Port::new(0x60);
+}
 
-    // Lock mutex on each interrupt
-    let mut keyboard = KEYBOARD.lock();
+/// Handler of a PIC line
+///
+/// Called with the line that fired. All handlers sharing a line are
+/// called, each telling whether its device raised the interrupt.
+pub type IrqHandler = fn(line: u8) -> IrqResult;
 
-    let scancode: u8 = unsafe { port.read() };
+/// What a handler made of an interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+    /// The handler's device raised it
+    Handled,
+    /// Left to the other handlers of the line
+    NotMine,
+}
+
+/// Reasons a handler couldn't be registered or unregistered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// No such line, or the cascade line
+    InvalidLine(u8),
+    /// The handler is registered for the line already
+    AlreadyRegistered,
+    /// `MAX_SHARED_HANDLERS` handlers share the line already
+    LineFull,
+    /// The handler isn't registered for the line
+    NotRegistered,
+    /// No I/O APIC pin serves the line
+    NotRouted(u8),
+}
 
-    // Translate scan code into <Option<KeyEvent>>
-    // KeyEvent: Info on key and whether press on release event
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => println!("{}", character),
-                DecodedKey::RawKey(character) => println!("{:?}", character),
+/// Interrupt counters of a line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqStats {
+    /// Interrupts delivered
+    pub count: u64,
+    /// Interrupts no handler claimed
+    pub unhandled: u64,
+    /// Handlers registered
+    pub handlers: usize,
+}
+
+#[derive(Clone, Copy)]
+struct IrqLine {
+    handlers: [Option<IrqHandler>; MAX_SHARED_HANDLERS],
+    count: u64,
+    unhandled: u64,
+}
+
+/// Handlers and counters of every line
+///
+/// Only locked with interrupts disabled, so the dispatcher never
+/// finds it taken
+static IRQS: Mutex<[IrqLine; IRQ_LINES]> = Mutex::new(
+    [IrqLine {
+        handlers: [None; MAX_SHARED_HANDLERS],
+        count: 0,
+        unhandled: 0,
+    }; IRQ_LINES],
+);
+
+/// Spurious interrupts of the PICs and the local APIC
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Index of `line` in `IRQS`
+fn line_index(line: u8) -> Result<usize, IrqError> {
+    if usize::from(line) >= IRQ_LINES || line == CASCADE_LINE {
+        return Err(IrqError::InvalidLine(line));
+    }
+    Ok(usize::from(line))
+}
+
+fn same_handler(a: IrqHandler, b: IrqHandler) -> bool {
+    a as usize == b as usize
+}
+
+/// Registers `handler` for the PIC line `line`
+///
+/// Handlers of a shared line are called in registration order. The
+/// first handler unmasks the line, on the I/O APIC when the APIC is
+/// enabled. The end of interrupt is signalled once all handlers ran.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let index = line_index(line)?;
+    without_interrupts(|| {
+        let mut irqs = IRQS.lock();
+        let handlers = &mut irqs[index].handlers;
+        if handlers.iter().flatten().any(|&h| same_handler(h, handler)) {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let first = handlers.iter().all(Option::is_none);
+        let slot = handlers.iter_mut().find(|h| h.is_none());
+        *slot.ok_or(IrqError::LineFull)? = Some(handler);
+
+        if first {
+            if let Err(err) = set_line_masked(line, false) {
+                handlers[0] = None;
+                return Err(err);
             }
+        }
+        Ok(())
+    })
+}
+
+/// Removes `handler` from the PIC line `line`
+///
+/// The line is masked once its last handler is gone
+pub fn unregister_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let index = line_index(line)?;
+    without_interrupts(|| {
+        let mut irqs = IRQS.lock();
+        let handlers = &mut irqs[index].handlers;
+        let position = handlers
+            .iter()
+            .position(|h| h.map_or(false, |h| same_handler(h, handler)))
+            .ok_or(IrqError::NotRegistered)?;
+
+        // Keep the rest of the chain in registration order
+        handlers[position..].rotate_left(1);
+        handlers[MAX_SHARED_HANDLERS - 1] = None;
+
+        if handlers[0].is_none() {
+            set_line_masked(line, true)?;
+        }
+        Ok(())
+    })
+}
+
+/// Counters of the PIC line `line`
+pub fn irq_stats(line: u8) -> IrqStats {
+    without_interrupts(|| {
+        IRQS.lock()
+            .get(usize::from(line))
+            .map_or(IrqStats::default(), |irq| IrqStats {
+                count: irq.count,
+                unhandled: irq.unhandled,
+                handlers: irq.handlers.iter().flatten().count(),
+            })
+    })
+}
+
+/// Spurious interrupts since boot
+///
+/// Counts the spurious IRQ 7 and 15 of the PICs and the spurious vector
+/// of the local APIC. They reach no handler and aren't in `irq_stats`.
+pub fn spurious_interrupts() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Number of timer interrupts handled so far
+pub fn timer_ticks() -> u64 {
+    irq_stats(InterruptIndex::Timer.line()).count
+}
+
+/// Lines with at least one handler, as a bit mask
+pub(crate) fn registered_lines() -> u16 {
+    without_interrupts(|| {
+        let irqs = IRQS.lock();
+        (0..IRQ_LINES)
+            .filter(|&line| irqs[line].handlers[0].is_some())
+            .fold(0, |mask, line| mask | 1 << line)
+    })
+}
+
+/// Masks or unmasks `line` on whichever controller is in charge
+fn set_line_masked(line: u8, masked: bool) -> Result<(), IrqError> {
+    if apic::is_enabled() {
+        let result = if masked {
+            apic::set_isa_irq_masked(line, true)
+        } else {
+            apic::route_isa_irq(line, irq_vector(line))
         };
-    };
+        return result.map_err(|_| IrqError::NotRouted(line));
+    }
 
-    println!("{}", scancode);
+    let (port, bit) = if line < 8 {
+        (0x21, line)
+    } else {
+        (0xa1, line - 8)
+    };
+    unsafe {
+        let mut port = Port::<u8>::new(port);
+        let mask = port.read();
+        if masked {
+            port.write(mask | 1 << bit);
+        } else {
+            port.write(mask & !(1 << bit));
+        }
+    }
+    // The slave only gets through with the cascade line open
+    if line >= 8 && !masked {
+        set_line_masked(CASCADE_LINE, false)?;
+    }
+    Ok(())
+}
 
+/// Whether `line` is set in the in-service register of its PIC
+fn pic_in_service(line: u8) -> bool {
+    let (command, bit) = if line < 8 {
+        (0x20, line)
+    } else {
+        (0xa0, line - 8)
+    };
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        let mut port = Port::<u8>::new(command);
+        // OCW3: read the in-service register next
+        port.write(0x0b);
+        port.read() & (1 << bit) != 0
+    }
+}
+
+/// Whether an interrupt on `line` is a spurious one of the PICs
+///
+/// The lowest priority line of a PIC fires without being in service
+/// when the request went away before it was acknowledged
+fn pic_spurious(line: u8) -> bool {
+    (line == 7 || line == 15) && !apic::is_enabled() && !pic_in_service(line)
+}
+
+/// Runs the handlers of `line`, counts the interrupt and ends it
+fn dispatch_irq(line: u8) {
+    if pic_spurious(line) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        // The master did see the cascade line
+        if line == 15 {
+            end_of_interrupt(CASCADE_LINE);
+        }
+        return;
+    }
+
+    let handlers = {
+        let mut irqs = IRQS.lock();
+        let irq = &mut irqs[usize::from(line)];
+        irq.count += 1;
+        irq.handlers
+    };
+    // Every handler runs, more than one device may be waiting
+    let handled = handlers.iter().flatten().fold(false, |handled, handler| {
+        handler(line) == IrqResult::Handled || handled
+    });
+    if !handled {
+        IRQS.lock()[usize::from(line)].unhandled += 1;
+    }
+
+    end_of_interrupt(line);
+}
+
+/// Signals the end of an interrupt on `line` to the APIC, or the PICs
+/// without one
+fn end_of_interrupt(line: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(irq_vector(line)) };
     }
 }
 
+/// Handler function for the timer interrupt
+///
+/// Ticks are counted with the line's statistics, see `timer_ticks`
+fn timer_interrupt(_line: u8) -> IrqResult {
+    IrqResult::Handled
+}
+
+/// Handles Keyboard interrupts
+///
+/// Queues the scancode, `keyboard` decodes it later
+fn keyboard_interrupt(_line: u8) -> IrqResult {
+    // Read data from PS/2 controller: port number 0x60
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    if sysrq(scancode) {
+        monitor::enter_sysrq(backtrace::capture_registers());
+        return IrqResult::Handled;
+    }
+    keyboard::add_scancode(scancode);
+    IrqResult::Handled
+}
+
+/// Whether `scancode` completes Alt+SysRq
+///
+/// Works on raw scancodes, so the monitor can be entered whatever
+/// state the decoder is in
+fn sysrq(scancode: u8) -> bool {
+    static ALT: AtomicBool = AtomicBool::new(false);
+
+    match scancode {
+        // Left Alt pressed and released
+        0x38 => ALT.store(true, Ordering::Relaxed),
+        0xb8 => ALT.store(false, Ordering::Relaxed),
+        // SysRq is Print Screen with Alt held
+        0x54 => return ALT.load(Ordering::Relaxed),
+        _ => {}
+    }
+    false
+}
+
+/// Handles spurious local APIC interrupts, which take no EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
 use x86_64::structures::idt::PageFaultErrorCode;
 
 /// Handles page fault exceptions
 /// With this done, generic double faults shouldn't be raised
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    // Type of memory access causing the fault
-    error_code: PageFaultErrorCode,
-) {
+fn page_fault(context: &Context) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: Page Fault");
+    // Type of memory access causing the fault
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
     // Accessed Virtual address that caused the page fault
-    println!("Accessed Address: {:#?}", Cr2::read());
-    println!(
-        "Error Code: {:#?}\n Stack Frame: {:#?}",
-        error_code, stack_frame
-    );
-    halt_loop();
+    let addr = Cr2::read();
+
+    // Faults in lazily backed regions only need a frame
+    let resolved = demand_paging::resolve_fault(addr, error_code);
+    if resolved.is_ok() {
+        return;
+    }
+
+    let report = CrashReport::from_context(context);
+    match stack::guard_hit(addr) {
+        Some(hit) => {
+            crash::fatal(report.with_detail(format_args!("accessed {:#x}: {}", addr.as_u64(), hit)))
+        }
+        None => crash::fatal(report.with_detail(format_args!(
+            "accessed {:#x}: {:?}",
+            addr.as_u64(),
+            resolved.unwrap_err()
+        ))),
+    }
 }
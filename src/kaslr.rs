@@ -0,0 +1,266 @@
+//! Kernel address space layout randomisation
+//!
+//! Picks the bases of the heap, slab, stack and vmalloc regions at boot.
+//! Each base is a random 2MiB aligned address inside the region's
+//! window, drawn from a seed taken from RDSEED, RDRAND or, lacking
+//! both, the time stamp counter. The chosen layout is logged over
+//! serial together with the seed.
+//!
+//! The `opt/x86-kernel/kaslr` boot option, a QEMU `fw_cfg` file read at
+//! boot, overrides the randomisation for reproducible debugging:
+//! - `off` keeps the fixed layout
+//! - `seed=<hex>` derives the layout from the given seed
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::{allocator, fw_cfg, serial_println, slab, stack, vma};
+
+/// `fw_cfg` file of the boot option
+pub const BOOT_OPTION: &str = "opt/x86-kernel/kaslr";
+
+/// Granularity of the randomised bases
+const ALIGN: u64 = 2 * 1024 * 1024;
+/// Attempts before RDRAND or RDSEED are given up on
+const RETRIES: usize = 10;
+
+/// Range a region's base is placed in
+///
+/// The whole region, not only its base, stays inside the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub start: u64,
+    /// Exclusive end
+    pub end: u64,
+}
+
+/// Window of the heap base
+pub const HEAP_WINDOW: Window = Window {
+    start: 0x_4000_0000_0000,
+    end: 0x_4800_0000_0000,
+};
+/// Window of the slab region
+pub const SLAB_WINDOW: Window = Window {
+    start: 0x_5000_0000_0000,
+    end: 0x_5800_0000_0000,
+};
+/// Window of the kernel stack region
+pub const STACKS_WINDOW: Window = Window {
+    start: 0x_6000_0000_0000,
+    end: 0x_6800_0000_0000,
+};
+/// Window of the vmalloc region
+pub const VMALLOC_WINDOW: Window = Window {
+    start: 0x_7000_0000_0000,
+    end: 0x_7400_0000_0000,
+};
+
+/// Bases of the randomised regions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub heap: u64,
+    pub slabs: u64,
+    pub stacks: u64,
+    pub vmalloc: u64,
+}
+
+impl Layout {
+    /// Layout without randomisation
+    pub const FIXED: Layout = Layout {
+        heap: 0x_4444_4444_0000,
+        slabs: 0x_5555_0000_0000,
+        stacks: 0x_6666_0000_0000,
+        vmalloc: 0x_7000_0000_0000,
+    };
+
+    /// Layout derived from `seed`
+    pub fn from_seed(seed: u64) -> Self {
+        let mut rng = SplitMix64(seed);
+        Layout {
+            heap: HEAP_WINDOW.pick(allocator::HEAP_MAX_SIZE as u64, rng.next()),
+            slabs: SLAB_WINDOW.pick(slab::SLAB_REGION_SIZE, rng.next()),
+            stacks: STACKS_WINDOW.pick(stack::STACKS_SIZE, rng.next()),
+            vmalloc: VMALLOC_WINDOW.pick(vma::VMALLOC_SIZE, rng.next()),
+        }
+    }
+}
+
+impl Window {
+    /// Base of a region of `size` bytes picked by `random`
+    fn pick(self, size: u64, random: u64) -> u64 {
+        let slots = (self.end - self.start - size) / ALIGN + 1;
+        self.start + random % slots * ALIGN
+    }
+}
+
+/// Where the seed of the layout came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Randomisation turned off by the boot option
+    Disabled,
+    /// Seed given by the boot option
+    BootOption,
+    Rdseed,
+    Rdrand,
+    /// Time stamp counter, when no hardware generator exists
+    Tsc,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::Disabled => "disabled",
+            Source::BootOption => "boot option",
+            Source::Rdseed => "RDSEED",
+            Source::Rdrand => "RDRAND",
+            Source::Tsc => "TSC",
+        })
+    }
+}
+
+/// splitmix64, spreads one seed over the regions
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static HEAP: AtomicU64 = AtomicU64::new(Layout::FIXED.heap);
+static SLABS: AtomicU64 = AtomicU64::new(Layout::FIXED.slabs);
+static STACKS: AtomicU64 = AtomicU64::new(Layout::FIXED.stacks);
+static VMALLOC: AtomicU64 = AtomicU64::new(Layout::FIXED.vmalloc);
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Chooses the layout and logs it over serial
+///
+/// Called by `init` before any region is mapped. Later calls keep the
+/// layout chosen first.
+pub fn init() -> Layout {
+    if INITIALIZED.swap(true, Ordering::Relaxed) {
+        return layout();
+    }
+    let (seed, source) = match boot_option() {
+        Some(BootOption::Off) => (0, Source::Disabled),
+        Some(BootOption::Seed(seed)) => (seed, Source::BootOption),
+        None => hardware_seed(),
+    };
+    let layout = match source {
+        Source::Disabled => Layout::FIXED,
+        _ => Layout::from_seed(seed),
+    };
+
+    HEAP.store(layout.heap, Ordering::Relaxed);
+    SLABS.store(layout.slabs, Ordering::Relaxed);
+    STACKS.store(layout.stacks, Ordering::Relaxed);
+    VMALLOC.store(layout.vmalloc, Ordering::Relaxed);
+    SEED.store(seed, Ordering::Relaxed);
+
+    serial_println!("[kaslr] seed {:#x} from {}", seed, source);
+    serial_println!("[kaslr] heap    {:#x}", layout.heap);
+    serial_println!("[kaslr] slabs   {:#x}", layout.slabs);
+    serial_println!("[kaslr] stacks  {:#x}", layout.stacks);
+    serial_println!("[kaslr] vmalloc {:#x}", layout.vmalloc);
+    layout
+}
+
+/// Current layout, `Layout::FIXED` until `init` ran
+pub fn layout() -> Layout {
+    Layout {
+        heap: HEAP.load(Ordering::Relaxed),
+        slabs: SLABS.load(Ordering::Relaxed),
+        stacks: STACKS.load(Ordering::Relaxed),
+        vmalloc: VMALLOC.load(Ordering::Relaxed),
+    }
+}
+
+/// Seed the layout was derived from, to reproduce it with `seed=`
+pub fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+
+enum BootOption {
+    Off,
+    Seed(u64),
+}
+
+/// Reads and parses the `BOOT_OPTION` file
+fn boot_option() -> Option<BootOption> {
+    let mut buf = [0; 32];
+    let len = fw_cfg::read_file(BOOT_OPTION, &mut buf)?;
+    let option = match core::str::from_utf8(&buf[..len]) {
+        // `-fw_cfg file=` keeps the newline of the file
+        Ok(option) => option.trim_end_matches(|c: char| c == '\n' || c == '\0'),
+        Err(_) => {
+            serial_println!("[kaslr] ignoring option that isn't UTF-8");
+            return None;
+        }
+    };
+    if option == "off" {
+        return Some(BootOption::Off);
+    }
+    let seed = option.trim_start_matches("seed=").trim_start_matches("0x");
+    match u64::from_str_radix(seed, 16) {
+        Ok(seed) if option.starts_with("seed=") => Some(BootOption::Seed(seed)),
+        _ => {
+            serial_println!("[kaslr] ignoring unknown option {:?}", option);
+            None
+        }
+    }
+}
+
+/// Seed from the best entropy source the CPU has
+fn hardware_seed() -> (u64, Source) {
+    use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    // CPUID 7: EBX bit 18 is RDSEED
+    if max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0 {
+        if let Some(seed) = retry(|| unsafe { rdseed() }) {
+            return (seed, Source::Rdseed);
+        }
+    }
+    // CPUID 1: ECX bit 30 is RDRAND
+    if unsafe { __cpuid(1) }.ecx & (1 << 30) != 0 {
+        if let Some(seed) = retry(|| unsafe { rdrand() }) {
+            return (seed, Source::Rdrand);
+        }
+    }
+    let tsc = unsafe { _rdtsc() };
+    (SplitMix64(tsc).next(), Source::Tsc)
+}
+
+/// Calls `f` until it yields a value, at most `RETRIES` times
+fn retry(f: impl Fn() -> Option<u64>) -> Option<u64> {
+    (0..RETRIES).find_map(|_| f())
+}
+
+/// Random value from RDRAND, `None` when none is ready
+unsafe fn rdrand() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!("rdrand $0; setc $1" : "=r"(value), "=r"(ok) :: "cc" : "volatile");
+    if ok != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Random value from RDSEED, `None` when none is ready
+unsafe fn rdseed() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!("rdseed $0; setc $1" : "=r"(value), "=r"(ok) :: "cc" : "volatile");
+    if ok != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,198 @@
+//! Kernel image protection
+//!
+//! Reads the program headers of the running kernel and remaps its
+//! loadable segments with the least permissions they need: code
+//! read-only and executable, read-only data and writable data never
+//! executable. Writable pages outside the image, like the physical
+//! memory window, lose execute permission too, which leaves the kernel
+//! with no writable and executable page.
+//!
+//! Code pages are read-only afterwards, so anything patching code, like
+//! a debugger setting breakpoints, has to clear CR0.WP while writing.
+
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::paging::{self, PagingError};
+use crate::{pt_dump, serial_println};
+
+/// Loadable segment
+const PT_LOAD: u32 = 1;
+/// Segment flag: executable
+const PF_X: u32 = 1;
+/// Segment flag: writable
+const PF_W: u32 = 2;
+
+extern "C" {
+    /// ELF header of the kernel, defined by the linker
+    static __ehdr_start: ElfHeader;
+}
+
+/// ELF64 file header
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+/// ELF64 program header
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Loaded part of the kernel image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: VirtAddr,
+    /// Exclusive end
+    pub end: VirtAddr,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Page flags granting the segment's permissions
+    pub fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Program headers of the running kernel
+///
+/// `__ehdr_start` only links when the ELF header is in a loaded
+/// segment, so it's mapped with the image. Empty if the header isn't
+/// an ELF64 one with program headers of the expected size.
+fn program_headers() -> &'static [ProgramHeader] {
+    let header = unsafe { &__ehdr_start };
+    if &header.ident[..4] != b"\x7fELF"
+        || usize::from(header.phentsize) != core::mem::size_of::<ProgramHeader>()
+    {
+        return &[];
+    }
+    let first = (header as *const ElfHeader as u64 + header.phoff) as *const ProgramHeader;
+    unsafe { core::slice::from_raw_parts(first, usize::from(header.phnum)) }
+}
+
+/// Calls `f` with every loaded segment of the kernel
+pub fn for_each_segment(mut f: impl FnMut(&Segment)) {
+    let loaded = program_headers()
+        .iter()
+        .filter(|ph| ph.kind == PT_LOAD && ph.memsz > 0);
+    for ph in loaded {
+        f(&Segment {
+            start: VirtAddr::new(ph.vaddr),
+            end: VirtAddr::new(ph.vaddr + ph.memsz),
+            writable: ph.flags & PF_W != 0,
+            executable: ph.flags & PF_X != 0,
+        });
+    }
+}
+
+/// Segment containing `addr`
+pub fn segment(addr: VirtAddr) -> Option<Segment> {
+    let mut found = None;
+    for_each_segment(|segment| {
+        if segment.contains(addr) {
+            found = Some(*segment);
+        }
+    });
+    found
+}
+
+/// Remaps the kernel segments and all other writable pages W^X
+///
+/// Needs the kernel memory installed. Pages holding the end of one
+/// segment and the start of the next keep their mapping.
+pub fn protect() -> Result<(), PagingError> {
+    let mut result = Ok(());
+    let mut previous_end: Option<Page> = None;
+    for_each_segment(|segment| {
+        if result.is_err() {
+            return;
+        }
+        let mut first = Page::containing_address(segment.start);
+        let end = Page::containing_address(segment.end - 1u64) + 1;
+        if previous_end == Some(first + 1) {
+            first += 1;
+        }
+        previous_end = Some(end);
+        if first < end {
+            result = paging::protect(Page::range(first, end), segment.flags());
+        }
+    });
+    result?;
+    protect_writable_pages()?;
+
+    if !paging::nx_enabled() {
+        serial_println!("[wx] no NX support, data stays executable");
+    }
+    for_each_segment(|segment| {
+        serial_println!(
+            "[wx] {:#x}-{:#x} {}{}",
+            segment.start.as_u64(),
+            segment.end.as_u64(),
+            if segment.writable { 'w' } else { '-' },
+            if segment.executable { 'x' } else { '-' },
+        )
+    });
+    Ok(())
+}
+
+/// Adds `NO_EXECUTE` to every writable page outside the kernel image
+///
+/// Covers the physical memory window and whatever else the bootloader
+/// left writable and executable. Each page keeps the rest of its leaf
+/// flags, like the caching bits of device memory, which the merged
+/// ranges of `pt_dump` don't carry.
+fn protect_writable_pages() -> Result<(), PagingError> {
+    if !paging::nx_enabled() {
+        return Ok(());
+    }
+    let ranges = pt_dump::ranges();
+    let writable_executable = ranges
+        .iter()
+        .filter(|range| range.writable() && !range.no_execute());
+    for range in writable_executable {
+        let mut offset = 0;
+        while offset < range.size {
+            let start = range.start + offset;
+            let leaf = paging::query(Page::containing_address(start))?;
+            let flags = leaf.flags | PageTableFlags::NO_EXECUTE;
+            paging::protect_region(start, range.page_size, flags)?;
+            offset += range.page_size;
+        }
+    }
+    Ok(())
+}
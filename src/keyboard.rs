@@ -0,0 +1,70 @@
+//! Keyboard input
+//!
+//! The keyboard interrupt only queues scancodes. They are decoded with
+//! `pc_keyboard` outside interrupt context, by `read_key` or by whoever
+//! polls a `ScancodeStream`.
+
+use core::task::{Context, Poll};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::ring::AsyncRing;
+
+/// Scancodes not read yet
+static SCANCODES: AsyncRing = AsyncRing::new();
+
+lazy_static! {
+    /// Decoder of `read_key`, keeping the modifier state between keys
+    static ref DECODER: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+}
+
+/// Queues `scancode` for the readers
+///
+/// Called by the keyboard interrupt, a full queue drops the scancode
+pub fn add_scancode(scancode: u8) {
+    SCANCODES.push(scancode);
+}
+
+/// Oldest queued scancode, if any
+pub fn read_scancode() -> Option<u8> {
+    SCANCODES.pop()
+}
+
+/// Scancodes dropped because the queue was full
+pub fn dropped_scancodes() -> u64 {
+    SCANCODES.dropped()
+}
+
+/// Decodes queued scancodes until one completes a key press
+///
+/// `None` once the queue is empty
+pub fn read_key() -> Option<DecodedKey> {
+    without_interrupts(|| {
+        let mut decoder = DECODER.lock();
+        while let Some(scancode) = SCANCODES.pop() {
+            if let Ok(Some(event)) = decoder.add_byte(scancode) {
+                if let Some(key) = decoder.process_keyevent(event) {
+                    return Some(key);
+                }
+            }
+        }
+        None
+    })
+}
+
+/// Stream of the queued scancodes
+///
+/// `poll_next` follows `futures::Stream`, the stream never ends. Only
+/// one stream should be polled at a time, it takes the scancodes from
+/// `read_key`.
+#[derive(Debug, Default)]
+pub struct ScancodeStream;
+
+impl ScancodeStream {
+    pub fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<u8>> {
+        SCANCODES.poll_pop(cx).map(Some)
+    }
+}
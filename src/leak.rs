@@ -0,0 +1,144 @@
+//! Live allocation tracking
+//!
+//! With the `leak-tracking` feature every heap allocation is recorded
+//! together with the return addresses leading to it, so a test can
+//! assert that nothing allocated inside a scope outlived it.
+//!
+//! Relies on frame pointers, which the target spec keeps enabled.
+
+use core::alloc::Layout;
+use spin::Mutex;
+
+use crate::{backtrace, serial_println};
+
+/// Live allocations recorded at once
+/// Allocations beyond that are only counted
+const MAX_TRACKED: usize = 1024;
+/// Return addresses stored per allocation
+pub const CALLER_FRAMES: usize = 6;
+
+/// A live heap allocation
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub addr: usize,
+    pub size: usize,
+    /// Allocation sequence number
+    pub id: u64,
+    /// Return addresses of the allocating call chain, innermost first
+    pub callers: [usize; CALLER_FRAMES],
+}
+
+struct LiveTable {
+    entries: [Option<Allocation>; MAX_TRACKED],
+    next_id: u64,
+    untracked: u64,
+}
+
+static LIVE: Mutex<LiveTable> = Mutex::new(LiveTable {
+    entries: [None; MAX_TRACKED],
+    next_id: 0,
+    untracked: 0,
+});
+
+/// Point in the allocation sequence
+#[derive(Debug, Clone, Copy)]
+pub struct LeakMark(u64);
+
+/// Records a new allocation
+pub(crate) fn track(ptr: *mut u8, layout: &Layout) {
+    let callers = callers();
+    let mut live = LIVE.lock();
+    let id = live.next_id;
+    live.next_id += 1;
+
+    match live.entries.iter_mut().find(|entry| entry.is_none()) {
+        Some(entry) => {
+            *entry = Some(Allocation {
+                addr: ptr as usize,
+                size: layout.size(),
+                id,
+                callers,
+            })
+        }
+        None => live.untracked += 1,
+    }
+}
+
+/// Forgets a freed allocation
+pub(crate) fn untrack(ptr: *mut u8) {
+    let mut live = LIVE.lock();
+    let entry = live
+        .entries
+        .iter_mut()
+        .find(|entry| entry.map_or(false, |a| a.addr == ptr as usize));
+    if let Some(entry) = entry {
+        *entry = None;
+    }
+}
+
+/// Marks the current point in the allocation sequence
+pub fn mark() -> LeakMark {
+    LeakMark(LIVE.lock().next_id)
+}
+
+/// Number of allocations made after `mark` that are still live
+pub fn leaks_since(mark: LeakMark) -> usize {
+    LIVE.lock()
+        .entries
+        .iter()
+        .flatten()
+        .filter(|a| a.id >= mark.0)
+        .count()
+}
+
+/// Prints every allocation made after `mark` that is still live
+///
+/// Returns their number
+pub fn report_leaks_since(mark: LeakMark) -> usize {
+    let live = LIVE.lock();
+    let mut leaks = 0;
+    for allocation in live.entries.iter().flatten().filter(|a| a.id >= mark.0) {
+        serial_println!(
+            "[leak] #{} {} bytes at {:#x}",
+            allocation.id,
+            allocation.size,
+            allocation.addr
+        );
+        for caller in allocation.callers.iter().filter(|&&c| c != 0) {
+            serial_println!("[leak]     called from {:#x}", caller);
+        }
+        leaks += 1;
+    }
+    if live.untracked > 0 {
+        serial_println!("[leak] {} allocations weren't tracked", live.untracked);
+    }
+    leaks
+}
+
+/// Runs `scope` and panics if any allocation made inside it is still live
+///
+/// The value returned by `scope` is checked too, so it must not own
+/// heap memory.
+pub fn assert_no_leaks<R>(scope: impl FnOnce() -> R) -> R {
+    let mark = mark();
+    let result = scope();
+    let leaks = report_leaks_since(mark);
+    assert_eq!(leaks, 0, "{} allocations outlived the scope", leaks);
+    result
+}
+
+/// Return addresses of the current call chain, innermost first
+fn callers() -> [usize; CALLER_FRAMES] {
+    let mut callers = [0; CALLER_FRAMES];
+    let mut slots = callers.iter_mut();
+    backtrace::walk(backtrace::frame_pointer(), |return_address| {
+        match slots.next() {
+            Some(slot) => {
+                *slot = return_address as usize;
+                true
+            }
+            None => false,
+        }
+    });
+    callers
+}
@@ -5,24 +5,65 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(const_fn)]
+#![feature(const_mut_refs)]
 
 use core::panic::PanicInfo;
 
-use linked_list_allocator::LockedHeap;
-
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod buddy;
+pub mod console;
+pub mod cpu;
+pub mod crash;
+pub mod demand_paging;
+pub mod fw_cfg;
+pub mod gdb;
 pub mod gdt;
+#[cfg(feature = "heap-debug")]
+pub mod heap_debug;
 pub mod interrupts;
+pub mod kaslr;
+pub mod kernel_image;
+pub mod keyboard;
+#[cfg(feature = "leak-tracking")]
+pub mod leak;
 pub mod memory;
+pub mod monitor;
+pub mod paging;
+pub mod pt_dump;
+pub mod ring;
 pub mod serial;
+pub mod slab;
+pub mod stack;
+pub mod symbols;
+pub mod trap;
+pub mod uart;
 pub mod vga_buffer;
+pub mod vma;
 
 /// Global Allocator
 /// Allocator instance to be used as the global heap allocator
+///
+/// Backed by `allocator::Backend`, see the `fixed-size-block` feature
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+static ALLOCATOR: allocator::KernelHeap =
+    allocator::Locked::new(allocator::GrowableHeap::new(allocator::Backend::empty()));
+
+/// Global Allocator
+/// Kernel heap wrapped in the debugging layer of the `heap-debug` feature
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: heap_debug::DebugAlloc<allocator::KernelHeap> = heap_debug::DebugAlloc::new(
+    allocator::Locked::new(allocator::GrowableHeap::new(allocator::Backend::empty())),
+);
 
 /// Called on allocation failure
 #[alloc_error_handler]
@@ -69,13 +110,20 @@ pub fn exit_qemu(code: QemuExitCode) -> () {
 
 /// General Initializer for the exceptions
 /// Initializes by calling `init_idt`
-/// Loads the GDT
+/// Loads the GDT, turns on NX support, hardens the CPU, picks the
+/// randomised memory layout and takes console input from serial
 pub fn init() {
+    paging::enable_nx();
+    cpu::harden();
+    kaslr::init();
     gdt::init();
     interrupts::init_idt();
     unsafe {
         interrupts::PICS.lock().initialize();
     }
+    uart::init();
+    serial::init_input().expect("Failed to enable serial input");
+    console::add_source(serial::read_byte).expect("Failed to add the serial console");
     x86_64::instructions::interrupts::enable();
 }
 
@@ -97,8 +145,11 @@ pub fn test_runner(tests: &[&dyn Fn()]) -> () {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    let registers = backtrace::capture_registers();
+    let backtrace = backtrace::Backtrace::capture();
     serial_println!("[oops!]");
     serial_println!("\nError: {}\n", info);
+    serial_println!("{}{}", registers, backtrace);
     exit_qemu(QemuExitCode::Failure);
     halt_loop();
 }
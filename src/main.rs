@@ -8,7 +8,10 @@ extern crate alloc;
 
 use core::panic::PanicInfo;
 
-use x86_kernel::{allocator, println};
+use x86_kernel::{
+    allocator, apic, gdb, kernel_image, monitor, paging, println, pt_dump, serial_println, stack,
+    vma,
+};
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
@@ -19,7 +22,7 @@ entry_point!(kernel_entry); // Defined the lower level _start()
 /// Linker entry point
 pub fn kernel_entry(boot_info: &'static BootInfo) -> ! {
     use x86_64::structures::paging::Page;
-    use x86_64::VirtAddr;
+    use x86_64::{PhysAddr, VirtAddr};
     use x86_kernel::memory::{self};
 
     x86_kernel::init();
@@ -27,7 +30,47 @@ pub fn kernel_entry(boot_info: &'static BootInfo) -> ! {
     let physical_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(physical_mem_offset) };
     let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        unsafe { memory::BitmapFrameAllocator::init(&boot_info.memory_map, physical_mem_offset) };
+
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    // Let the heap grow past `HEAP_SIZE`
+    memory::install(mapper, frame_allocator);
+    stack::init_ist_stacks().expect("interrupt stack initialization failed");
+    kernel_image::protect().expect("kernel image protection failed");
+    match apic::init() {
+        Ok(info) => serial_println!("[apic] enabled: {:?}", info),
+        Err(err) => serial_println!("[apic] keeping the PICs: {:?}", err),
+    }
+    // Wait for a debugger when built with `KERNEL_GDB` set
+    if option_env!("KERNEL_GDB").is_some() {
+        match gdb::init() {
+            Ok(()) => gdb::breakpoint(),
+            Err(err) => serial_println!("[gdb] not started: {:?}", err),
+        }
+    }
+    // Breakpoints and fatal exceptions stop in the monitor
+    monitor::enable();
+
+    // map unused page to the VGA buffer
+    let page = Page::containing_address(VirtAddr::new(0));
+    let flags = paging::data_flags();
+    vma::reserve(
+        "vga alias",
+        page.start_address(),
+        4096,
+        flags,
+        vma::Purpose::Fixed,
+    )
+    .expect("page 0 already reserved");
+    unsafe { paging::map_physical(Page::range(page, page + 1), PhysAddr::new(0xb8000), flags) }
+        .expect("mapping page 0 failed");
+    vma::dump();
+    pt_dump::dump();
+
+    // Write something to screen through the new mapping
+    // let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
+    // unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
 
     let heap_item = Box::new("fdf");
     let mut vect = Vec::new();
@@ -40,15 +83,6 @@ pub fn kernel_entry(boot_info: &'static BootInfo) -> ! {
 
     // let level_four_table = unsafe { level_four_active_table(physical_mem_offset) };
 
-    // map unused page
-    let page = Page::containing_address(VirtAddr::new(0));
-    memory::create_mapping(page, &mut mapper, &mut frame_allocator);
-
-    // Write something to screen through the new mapping
-    // let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
-    // unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
-    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
-
     let ref_counted_vec = Rc::new(vec![1, 2, 3, 4, 5]);
     let cloned_ref = ref_counted_vec.clone();
     println!("Current ref count - {}", Rc::strong_count(&cloned_ref));
@@ -70,8 +104,8 @@ pub fn kernel_entry(boot_info: &'static BootInfo) -> ! {
 /// Called by the compiler on panic
 #[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    println!("{}", _info);
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::crash::report_panic(info);
     x86_kernel::halt_loop();
 }
 
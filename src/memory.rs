@@ -1,49 +1,206 @@
 //! Mapping of Virtual addresses to Physical Addresses
 
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
     UnusedPhysFrame,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::allocator::{self, HeapStats};
+use crate::vma;
+
+/// Page table and frame allocator of the running kernel
+///
+/// Used wherever memory has to be mapped outside of the boot path,
+/// e.g. when the heap grows
+pub static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// Active page table paired with the frame allocator feeding it
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator,
+}
+
+/// Hands the page table and frame allocator over to `KERNEL_MEMORY`
+///
+/// Also reserves the fixed kernel regions in the address space manager
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    });
+    vma::reserve_kernel_regions();
+}
+
+/// Offset the complete physical memory is mapped at, set by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Virtual address the physical memory is mapped at
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Virtual address of `addr` in the physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Physical frame and heap usage
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub used_frames: usize,
+    pub heap: HeapStats,
+}
+
+/// Current memory usage
+///
+/// Frame counts are zero until `install` has been called
+pub fn stats() -> MemoryStats {
+    let (total_frames, free_frames, used_frames) = match KERNEL_MEMORY.lock().as_ref() {
+        Some(memory) => {
+            let frames = &memory.frame_allocator;
+            (
+                frames.total_frames(),
+                frames.free_frames(),
+                frames.used_frames(),
+            )
+        }
+        None => (0, 0, 0),
+    };
+    MemoryStats {
+        total_frames,
+        free_frames,
+        used_frames,
+        heap: allocator::stats(),
+    }
+}
 
 /// Empty Frame allocator
 /// Returns `None`
 pub struct EmptyFrameAllocator;
 
+/// Size of a physical frame in bytes
+const FRAME_SIZE: u64 = 4096;
+
 /// Returns usable frames from the Bootloader's memory map
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize, // Number of next frame to return
+///
+/// Keeps one bit per 4KiB frame between the lowest and the highest
+/// usable address of the memory map. A set bit marks a free frame.
+/// The bitmap itself lives in the first usable region large enough
+/// to hold it and is accessed through the physical memory offset.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    base: PhysAddr,   // Address of the frame tracked by bit 0
+    next_word: usize, // No free frame exists in words before this one
+    total_frames: usize,
+    free_frames: usize,
 }
 
-impl BootInfoFrameAllocator {
+impl BitmapFrameAllocator {
     /// Creates a Frame Allocator from the given memory map
     ///
     /// # Unsafe
     /// ---------
     /// Guarantee the usable frames of the memory map
-    /// aren't used anywhere else
+    /// aren't used anywhere else and that the complete physical
+    /// memory is mapped at `physical_mem_offset`
     /// ----------
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_mem_offset: VirtAddr) -> Self {
+        let usable_ranges = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| {
+                    let start = align_up(r.range.start_addr(), FRAME_SIZE);
+                    let end = align_down(r.range.end_addr(), FRAME_SIZE);
+                    start..end
+                })
+                .filter(|range| range.start < range.end)
+        };
+
+        let lowest = usable_ranges().map(|r| r.start).min().unwrap_or(0);
+        let highest = usable_ranges().map(|r| r.end).max().unwrap_or(0);
+        let frame_count = ((highest - lowest) / FRAME_SIZE) as usize;
+        let words = (frame_count + 63) / 64;
+        let bitmap_bytes = align_up((words * 8) as u64, FRAME_SIZE);
+
+        // Store the bitmap at the start of the first region that fits it
+        let bitmap_start = usable_ranges()
+            .find(|r| r.end - r.start >= bitmap_bytes)
+            .map(|r| r.start)
+            .expect("No usable region large enough for the frame bitmap");
+        let bitmap_end = bitmap_start + bitmap_bytes;
+
+        let bitmap_ptr: *mut u64 = (physical_mem_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        for word in bitmap.iter_mut() {
+            *word = 0;
         }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            base: PhysAddr::new(lowest),
+            next_word: 0,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        for range in usable_ranges() {
+            for addr in (range.start..range.end).step_by(FRAME_SIZE as usize) {
+                if addr >= bitmap_start && addr < bitmap_end {
+                    continue; // Frames holding the bitmap are never handed out
+                }
+                let (word, bit) = allocator.position(PhysAddr::new(addr));
+                allocator.bitmap[word] |= 1 << bit;
+                allocator.total_frames += 1;
+                allocator.free_frames += 1;
+            }
+        }
+        allocator
+    }
+
+    /// Number of frames managed by the allocator
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
     }
 
-    /// Gives an iterator over the usable frames in the memory map
-    fn frames(&mut self) -> impl Iterator<Item = UnusedPhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_start_addresses = addr_ranges.flat_map(|addr| addr.step_by(4096));
+    /// Number of frames available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames currently handed out
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
 
-        let phys_frames =
-            frame_start_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
-        phys_frames.map(|frame| unsafe { UnusedPhysFrame::new(frame) })
+    /// Word index and bit offset tracking the frame at `addr`
+    fn position(&self, addr: PhysAddr) -> (usize, u64) {
+        let index = ((addr.as_u64() - self.base.as_u64()) / FRAME_SIZE) as usize;
+        (index / 64, (index % 64) as u64)
     }
+
+    /// Whether `addr` lies inside the range covered by the bitmap
+    fn tracks(&self, addr: PhysAddr) -> bool {
+        let end = self.base.as_u64() + self.bitmap.len() as u64 * 64 * FRAME_SIZE;
+        addr >= self.base && addr.as_u64() < end
+    }
+}
+
+/// Rounds `addr` up to the given power of two alignment
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Rounds `addr` down to the given power of two alignment
+fn align_down(addr: u64, align: u64) -> u64 {
+    addr & !(align - 1)
 }
 
 // Unsafe -> Guarantee return of only unused frames
@@ -54,33 +211,49 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        let usable_frame = self.frames().nth(self.next);
-        self.next += 1;
-        usable_frame
+        // Words before `next_word` are fully allocated, so the scan
+        // only moves forward until a frame is given back
+        while self.next_word < self.bitmap.len() {
+            let word = self.bitmap[self.next_word];
+            if word != 0 {
+                let bit = u64::from(word.trailing_zeros());
+                self.bitmap[self.next_word] &= !(1 << bit);
+                self.free_frames -= 1;
+
+                let index = self.next_word as u64 * 64 + bit;
+                let addr = self.base + index * FRAME_SIZE;
+                let frame = PhysFrame::containing_address(addr);
+                return Some(unsafe { UnusedPhysFrame::new(frame) });
+            }
+            self.next_word += 1;
+        }
+        None
     }
 }
 
-/// Creates a Page table mapping (Page to Frame) for the VGA buffer Oxb8000
-///
-/// # Arguments
-/// - Page: The page to be mapped
-/// - Frame: The frame to map the page to
-/// - Mapper: Flags for the page table entry
-/// - Frame Allocator: Allocates unused frames.
-///     These might be needed for creation of additional page tables
-pub fn create_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let unused_frame = unsafe { UnusedPhysFrame::new(frame) };
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-    let map_to_result = mapper.map_to(page, unused_frame, flags, frame_allocator);
-    map_to_result.expect("map_to failed").flush();
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        let addr = frame.start_address();
+        assert!(
+            self.tracks(addr),
+            "Frame {:?} not owned by the allocator",
+            addr
+        );
+
+        let (word, bit) = self.position(addr);
+        assert!(
+            self.bitmap[word] & (1 << bit) == 0,
+            "Frame {:?} freed twice",
+            addr
+        );
+        self.bitmap[word] |= 1 << bit;
+        self.free_frames += 1;
+        if word < self.next_word {
+            self.next_word = word;
+        }
+    }
 }
 
 /// Returns a mutable reference to the active level 4 page table
@@ -97,9 +270,13 @@ unsafe fn level_four_active_table(physical_mem_offset: VirtAddr) -> &'static mut
 }
 
 /// Translates a virtual address to the mapped physical address
-
-/// NOTE
-/// Unused
+///
+/// Follows 2MiB and 1GiB pages
+///
+/// # Unsafe
+/// ---------
+/// The complete physical memory must be mapped at `physical_mem_offset`
+/// ----------
 pub unsafe fn translate_virt_addr(
     addr: VirtAddr,
     physical_mem_offset: VirtAddr,
@@ -108,12 +285,9 @@ pub unsafe fn translate_virt_addr(
 }
 
 /// Called by `translate_virt_addr` to limit the unsafe scope
-
-/// NOTE: Unused -> The x86 MapperAllSizes provides implementation
-/// for translation of huge pages
 fn translate_addr_inner((addr, mem_offset): (VirtAddr, VirtAddr)) -> Option<PhysAddr> {
     use x86_64::registers::control::Cr3;
-    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::structures::paging::PageTableFlags;
 
     let (level_four_table_frame, _) = Cr3::read();
     let table_indices = [
@@ -122,10 +296,12 @@ fn translate_addr_inner((addr, mem_offset): (VirtAddr, VirtAddr)) -> Option<Phys
         addr.p2_index(),
         addr.p1_index(),
     ];
+    // Bytes covered by an entry of the P4, P3, P2 and P1 table
+    let entry_sizes = [1 << 39, 1 << 30, 1 << 21, 1 << 12];
     let mut frame = level_four_table_frame;
 
     // traverse Page Table
-    for &index in &table_indices {
+    for (level, &index) in table_indices.iter().enumerate() {
         // Convert frame to PT reference
         let virt = mem_offset + frame.start_address().as_u64();
         let table_pointer: *const PageTable = virt.as_ptr();
@@ -133,14 +309,18 @@ fn translate_addr_inner((addr, mem_offset): (VirtAddr, VirtAddr)) -> Option<Phys
 
         // Read table entry and update frame
         let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("Huge pages not supported"),
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // A huge entry in the P3 or P2 table maps the rest of the address
+        let size: u64 = entry_sizes[level];
+        if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            return Some(entry.addr() + (addr.as_u64() & (size - 1)));
         }
+        frame = PhysFrame::containing_address(entry.addr());
     }
-    // Pysical Address
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    unreachable!()
 }
 
 /// Initializes a new offset page table
@@ -150,6 +330,7 @@ fn translate_addr_inner((addr, mem_offset): (VirtAddr, VirtAddr)) -> Option<Phys
 pub unsafe fn init(physical_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
     // unsafe -> guarantee physical memory mapped to virtual memory at
     // the passed offset
+    PHYSICAL_MEMORY_OFFSET.store(physical_mem_offset.as_u64(), Ordering::Relaxed);
     let level_four_page_table = level_four_active_table(physical_mem_offset);
     OffsetPageTable::new(level_four_page_table, physical_mem_offset)
 }
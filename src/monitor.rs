@@ -0,0 +1,391 @@
+//! Interactive kernel monitor
+//!
+//! Stops the kernel and takes commands from the keyboard or the serial
+//! console, answering on both. Alt+SysRq enters it at any time. Once
+//! `enable`d, `int3` and fatal exceptions enter it too.
+//!
+//! Input is polled with interrupts off, so nothing else runs while the
+//! monitor is up.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+
+use crate::backtrace::{Backtrace, Registers};
+use crate::crash::{self, ControlRegisters, CrashReport, CrashWriter};
+use crate::interrupts::{self, IRQ_LINES};
+use crate::trap::Context;
+use crate::{paging, pt_dump, serial, vga_buffer};
+
+/// Longest command line
+const LINE_LENGTH: usize = 78;
+/// Bytes `mem` shows when no length is given
+const DEFAULT_DUMP: u64 = 64;
+/// Most bytes `mem` shows at once
+const MAX_DUMP: u64 = 1024;
+
+/// MSRs `msr` shows, reading others could fault
+const MSRS: [(&str, u32); 9] = [
+    ("tsc", 0x10),
+    ("apic_base", 0x1b),
+    ("pat", 0x277),
+    ("efer", 0xc000_0080),
+    ("star", 0xc000_0081),
+    ("lstar", 0xc000_0082),
+    ("fs_base", 0xc000_0100),
+    ("gs_base", 0xc000_0101),
+    ("kernel_gs_base", 0xc000_0102),
+];
+
+const HELP: &str = "\
+mem <addr> [len]  dump memory
+pt                dump the page table
+tasks             list tasks
+irq               show interrupt counters
+msr [name]        read model specific registers
+regs              show registers
+bt                show the backtrace
+cont              resume
+";
+
+/// Whether `int3` and fatal exceptions enter the monitor
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set while the monitor runs, a fault inside it doesn't enter it again
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Lets `int3` and fatal exceptions enter the monitor
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether `enable` was called
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// What stopped the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Breakpoint,
+    /// The exception with this vector, the kernel can't resume
+    Fatal(u8),
+    SysRq,
+}
+
+/// Whether the monitor waits for the next command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Stay,
+    Leave,
+}
+
+/// Commands on the state of the stopped kernel
+pub struct Monitor {
+    reason: Reason,
+    /// Registers of the stopped code
+    registers: Registers,
+    control: ControlRegisters,
+}
+
+impl Monitor {
+    pub fn new(reason: Reason, registers: Registers) -> Self {
+        Monitor {
+            reason,
+            registers,
+            control: ControlRegisters::read(),
+        }
+    }
+
+    pub fn reason(&self) -> Reason {
+        self.reason
+    }
+
+    /// Runs the command `line`, writing its output to `out`
+    pub fn execute(&self, line: &str, out: &mut impl Write) -> Flow {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Flow::Stay,
+        };
+        let args = (words.next(), words.next());
+        // Output errors have nowhere to go
+        let _ = match (command, args) {
+            ("help", _) => out.write_str(HELP),
+            ("mem", (Some(addr), len)) => match (parse_number(addr), len.map(parse_number)) {
+                (Some(addr), None) => dump_memory(out, addr, DEFAULT_DUMP),
+                (Some(addr), Some(Some(len))) => dump_memory(out, addr, len.min(MAX_DUMP)),
+                _ => writeln!(out, "usage: mem <addr> [len]"),
+            },
+            ("mem", _) => writeln!(out, "usage: mem <addr> [len]"),
+            ("pt", _) => {
+                let mut result = Ok(());
+                pt_dump::walk(pt_dump::active_level_4(), |range| {
+                    result = result.and_then(|()| writeln!(out, "{}", range));
+                });
+                result
+            }
+            ("tasks", _) => writeln!(out, "no tasks, the kernel runs a single thread"),
+            ("irq", _) => show_interrupts(out),
+            ("msr", (name, _)) => show_msrs(out, name),
+            ("regs", _) => write!(out, "{}{}", self.registers, self.control),
+            ("bt", _) => {
+                let backtrace = Backtrace::from_frame(Some(self.registers.rip), self.registers.rbp);
+                write!(out, "{}", backtrace)
+            }
+            ("cont", _) | ("c", _) => {
+                if let Reason::Fatal(_) = self.reason {
+                    let _ = writeln!(out, "can't resume from a fatal exception, panicking");
+                }
+                return Flow::Leave;
+            }
+            _ => writeln!(out, "unknown command {:?}, try help", line.trim()),
+        };
+        Flow::Stay
+    }
+
+    /// Takes commands until one resumes
+    fn run(&self) {
+        let mut out = CrashWriter;
+        let _ = writeln!(
+            out,
+            "\nmonitor: {} at {:#x}, help lists the commands",
+            self.describe(),
+            self.registers.rip
+        );
+
+        let mut input = Input::new();
+        let mut line = [0; LINE_LENGTH];
+        loop {
+            let _ = write!(out, "mon> ");
+            let len = input.read_line(&mut line);
+            // Only ASCII goes into the line
+            let command = core::str::from_utf8(&line[..len]).unwrap_or("");
+            if self.execute(command, &mut out) == Flow::Leave {
+                return;
+            }
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self.reason {
+            Reason::Breakpoint => "breakpoint",
+            Reason::Fatal(vector) => crash::exception_name(vector),
+            Reason::SysRq => "SysRq",
+        }
+    }
+}
+
+/// Runs `monitor` unless one is running already
+///
+/// The stopped code may hold the writers. Only after a fatal exception
+/// are they taken over, code that resumes keeps its locks and the
+/// monitor isn't entered. Returns whether it ran.
+fn enter(monitor: Monitor) -> bool {
+    if ACTIVE.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    let entered = match monitor.reason {
+        Reason::Fatal(_) => {
+            crash::take_writers();
+            true
+        }
+        _ => !serial::SERIAL_A.is_locked() && !vga_buffer::WRITER.is_locked(),
+    };
+    if entered {
+        monitor.run();
+    }
+    ACTIVE.store(false, Ordering::Release);
+    entered
+}
+
+/// Stops at a breakpoint, false when the monitor isn't enabled or
+/// can't print
+pub(crate) fn enter_breakpoint(context: &Context) -> bool {
+    is_enabled() && enter(Monitor::new(Reason::Breakpoint, context.registers()))
+}
+
+/// Stops after a fatal exception was reported
+pub(crate) fn enter_fatal(report: &CrashReport) {
+    if is_enabled() {
+        enter(Monitor::new(Reason::Fatal(report.vector), report.registers));
+    }
+}
+
+/// Stops on Alt+SysRq, from the keyboard interrupt
+///
+/// `registers` are the interrupt handler's, the interrupted code
+/// resumes once the monitor is left. Ignored while the interrupted code
+/// holds a writer.
+pub(crate) fn enter_sysrq(registers: Registers) {
+    enter(Monitor::new(Reason::SysRq, registers));
+}
+
+/// Parses hexadecimal with an optional `0x`, or decimal with a `#`
+fn parse_number(text: &str) -> Option<u64> {
+    if text.starts_with('#') {
+        text[1..].parse().ok()
+    } else {
+        u64::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+    }
+}
+
+/// Hex dump of `len` bytes at `addr`, stopping at the first unmapped line
+fn dump_memory(out: &mut impl Write, addr: u64, len: u64) -> fmt::Result {
+    let end = addr.saturating_add(len);
+    let mut line = addr & !0xf;
+    while line < end {
+        if !paging::is_mapped(line, 16) {
+            return writeln!(out, "{:#018x}: not mapped", line);
+        }
+        let bytes = unsafe { core::ptr::read_volatile(line as *const [u8; 16]) };
+        write!(out, "{:#018x}:", line)?;
+        for (i, byte) in bytes.iter().enumerate() {
+            let at = line + i as u64;
+            if at < addr || at >= end {
+                write!(out, "   ")?;
+            } else {
+                write!(out, " {:02x}", byte)?;
+            }
+        }
+        write!(out, "  ")?;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let at = line + i as u64;
+            let shown = match byte {
+                _ if at < addr || at >= end => ' ',
+                0x20..=0x7e => byte as char,
+                _ => '.',
+            };
+            out.write_char(shown)?;
+        }
+        writeln!(out)?;
+        line += 16;
+    }
+    Ok(())
+}
+
+fn show_interrupts(out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "line      count  unhandled  handlers")?;
+    for line in 0..IRQ_LINES as u8 {
+        let stats = interrupts::irq_stats(line);
+        if stats.count == 0 && stats.handlers == 0 {
+            continue;
+        }
+        writeln!(
+            out,
+            "{:>4} {:>10} {:>10} {:>9}",
+            line, stats.count, stats.unhandled, stats.handlers
+        )?;
+    }
+    writeln!(out, "spurious {}", interrupts::spurious_interrupts())
+}
+
+/// Shows the MSR called `name`, or all known ones
+fn show_msrs(out: &mut impl Write, name: Option<&str>) -> fmt::Result {
+    let mut found = false;
+    for &(msr_name, number) in MSRS.iter() {
+        if name.map_or(true, |name| name == msr_name) {
+            let value = unsafe { Msr::new(number).read() };
+            writeln!(out, "{:>14} {:#010x} {:#018x}", msr_name, number, value)?;
+            found = true;
+        }
+    }
+    if !found {
+        write!(out, "unknown MSR, one of:")?;
+        for (msr_name, _) in MSRS.iter() {
+            write!(out, " {}", msr_name)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Polls the PS/2 keyboard and COM1
+struct Input {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+}
+
+impl Input {
+    fn new() -> Self {
+        Input {
+            keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1),
+        }
+    }
+
+    /// Next character typed on either console
+    fn read_char(&mut self) -> char {
+        loop {
+            if let Some(c) = self.poll_keyboard().or_else(poll_serial) {
+                return c;
+            }
+            spin_loop_hint();
+        }
+    }
+
+    fn poll_keyboard(&mut self) -> Option<char> {
+        let status = unsafe { Port::<u8>::new(0x64).read() };
+        // Bit 0: output buffer full, bit 5: the byte is from the mouse
+        if status & 0x01 == 0 {
+            return None;
+        }
+        let scancode = unsafe { Port::<u8>::new(0x60).read() };
+        if status & 0x20 != 0 {
+            return None;
+        }
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        match self.keyboard.process_keyevent(event)? {
+            DecodedKey::Unicode(c) => Some(c),
+            DecodedKey::RawKey(_) => None,
+        }
+    }
+
+    /// Reads a line into `line`, echoing it, and returns its length
+    fn read_line(&mut self, line: &mut [u8]) -> usize {
+        let mut len = 0;
+        loop {
+            match self.read_char() {
+                '\n' | '\r' => {
+                    echo("\n");
+                    return len;
+                }
+                // Backspace and delete
+                '\u{8}' | '\u{7f}' => {
+                    if len > 0 {
+                        len -= 1;
+                        erase();
+                    }
+                }
+                c if c.is_ascii() && !c.is_ascii_control() && len < line.len() => {
+                    line[len] = c as u8;
+                    len += 1;
+                    echo(c.encode_utf8(&mut [0; 4]));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Byte received on COM1
+///
+/// Read from the UART itself: the monitor may have stopped a thread
+/// taking bytes from the receive ring, which only has one consumer
+fn poll_serial() -> Option<char> {
+    // Line status bit 0: data ready
+    let status = unsafe { Port::<u8>::new(serial::COM1 + 5).read() };
+    if status & 0x01 == 0 {
+        return None;
+    }
+    Some(char::from(unsafe { Port::<u8>::new(serial::COM1).read() }))
+}
+
+fn echo(s: &str) {
+    let _ = CrashWriter.write_str(s);
+}
+
+/// Erases the last character on both consoles
+fn erase() {
+    let _ = serial::SERIAL_A.lock().write_str("\u{8} \u{8}");
+    vga_buffer::WRITER.lock().backspace();
+}
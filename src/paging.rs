@@ -0,0 +1,623 @@
+//! Paging API
+//!
+//! Maps, unmaps, re-protects and queries pages of the active address
+//! space through the page table and frame allocator installed with
+//! `memory::install`. Every operation returns a `PagingError` instead
+//! of panicking and flushes the TLB entries it changes.
+//!
+//! Physically contiguous regions can be mapped with 2MiB and 1GiB pages
+//! through `map_physical_region`.
+//!
+//! Mappings are W^X: a request for a page that is both writable and
+//! executable is refused. Data should be mapped with `data_flags`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{
+    frame::PhysFrameRange,
+    mapper::{FlagUpdateError, MapToError, MapperFlush, UnmapError},
+    page::PageRange,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+    PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, UnusedPhysFrame,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{self, KernelMemory};
+
+/// Ranges longer than this flush the whole TLB once instead of
+/// every page on its own
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+/// Errors of the paging API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// `memory::install` hasn't been called
+    NoKernelMemory,
+    /// No frame was left for the page or a page table
+    FrameAllocationFailed,
+    /// The page is already mapped
+    AlreadyMapped(Page),
+    /// The page isn't mapped
+    NotMapped(Page),
+    /// A huge page covers the page
+    ParentEntryHugePage(Page),
+    /// The page and frame ranges differ in length
+    SizeMismatch,
+    /// An address or size isn't a multiple of 4KiB
+    Unaligned,
+    /// The flags ask for a writable and executable page
+    WritableExecutable,
+}
+
+/// A 4KiB page and the frame it maps to
+///
+/// Inside a huge page `frame` is the 4KiB part of the huge frame
+/// backing `page`, and `page_size` tells the size of the huge page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub page: Page,
+    pub frame: PhysFrame,
+    pub flags: PageTableFlags,
+    /// Size of the page table entry mapping the page: 4KiB, 2MiB or 1GiB
+    pub page_size: u64,
+}
+
+impl PagingError {
+    fn from_map(err: MapToError, page: Page) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => PagingError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => PagingError::ParentEntryHugePage(page),
+            MapToError::PageAlreadyMapped => PagingError::AlreadyMapped(page),
+        }
+    }
+
+    fn from_unmap(err: UnmapError, page: Page) -> Self {
+        match err {
+            UnmapError::ParentEntryHugePage => PagingError::ParentEntryHugePage(page),
+            _ => PagingError::NotMapped(page),
+        }
+    }
+
+    fn from_flag_update(err: FlagUpdateError, page: Page) -> Self {
+        match err {
+            FlagUpdateError::ParentEntryHugePage => PagingError::ParentEntryHugePage(page),
+            FlagUpdateError::PageNotMapped => PagingError::NotMapped(page),
+        }
+    }
+}
+
+/// Set once `enable_nx` turned on EFER.NXE
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the CPU supports the no-execute bit
+pub fn nx_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    // CPUID 0x8000_0001: EDX bit 20 is NX
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0
+}
+
+/// Turns on EFER.NXE so `NO_EXECUTE` takes effect
+///
+/// Called by `init`. Returns whether NX is enabled.
+pub fn enable_nx() -> bool {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    if !nx_supported() {
+        return false;
+    }
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    NX_ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Whether `NO_EXECUTE` is honored
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Flags of kernel data: writable, never executable
+pub fn data_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+/// `flags` as written to the page table
+///
+/// Without NX support the `NO_EXECUTE` bit is reserved, so it is dropped
+pub fn effective_flags(flags: PageTableFlags) -> PageTableFlags {
+    if nx_enabled() {
+        flags
+    } else {
+        flags & !PageTableFlags::NO_EXECUTE
+    }
+}
+
+/// Refuses writable and executable flags
+fn checked_flags(flags: PageTableFlags) -> Result<PageTableFlags, PagingError> {
+    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+        return Err(PagingError::WritableExecutable);
+    }
+    Ok(effective_flags(flags))
+}
+
+/// Runs `f` with the kernel page table and frame allocator
+fn with_kernel_memory<T>(
+    f: impl FnOnce(&mut KernelMemory) -> Result<T, PagingError>,
+) -> Result<T, PagingError> {
+    let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+    let kernel_memory = kernel_memory.as_mut().ok_or(PagingError::NoKernelMemory)?;
+    f(kernel_memory)
+}
+
+/// Collects the flushes of a range operation
+///
+/// Long ranges flush the whole TLB once when done
+struct RangeFlush {
+    flush_all: bool,
+}
+
+impl RangeFlush {
+    fn new(pages: PageRange) -> Self {
+        RangeFlush {
+            flush_all: page_count(pages) > FLUSH_ALL_THRESHOLD,
+        }
+    }
+
+    fn add(&self, flush: MapperFlush<Size4KiB>) {
+        if self.flush_all {
+            flush.ignore();
+        } else {
+            flush.flush();
+        }
+    }
+
+    fn finish(self) {
+        if self.flush_all {
+            tlb::flush_all();
+        }
+    }
+}
+
+fn page_count(pages: PageRange) -> u64 {
+    (pages.end.start_address() - pages.start.start_address()) / 4096
+}
+
+/// Maps `pages` to `frames` page by page
+///
+/// Nothing stays mapped when a page fails.
+///
+/// # Unsafe
+/// ---------
+/// The caller decides what memory the pages alias: the frames must
+/// not be in use in a way that conflicts with the new mapping
+/// ----------
+pub unsafe fn map_to_frames(
+    pages: PageRange,
+    frames: PhysFrameRange,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    let flags = checked_flags(flags)?;
+    let frame_count = (frames.end.start_address() - frames.start.start_address()) / 4096;
+    if page_count(pages) != frame_count {
+        return Err(PagingError::SizeMismatch);
+    }
+    with_kernel_memory(|memory| {
+        let flush = RangeFlush::new(pages);
+        for (page, frame) in pages.zip(frames) {
+            let frame = UnusedPhysFrame::new(frame);
+            let mapped = memory
+                .mapper
+                .map_to(page, frame, flags, &mut memory.frame_allocator);
+            match mapped {
+                Ok(page_flush) => flush.add(page_flush),
+                Err(err) => {
+                    flush.finish();
+                    rollback(memory, Page::range(pages.start, page), false);
+                    return Err(PagingError::from_map(err, page));
+                }
+            }
+        }
+        flush.finish();
+        Ok(())
+    })
+}
+
+/// Maps the physical range starting at `start` to `pages`
+///
+/// # Unsafe
+/// ---------
+/// See `map_to_frames`
+/// ----------
+pub unsafe fn map_physical(
+    pages: PageRange,
+    start: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    let first = PhysFrame::containing_address(start);
+    let frames = PhysFrame::range(first, first + page_count(pages));
+    map_to_frames(pages, frames, flags)
+}
+
+/// Maps `pages` to freshly allocated frames
+///
+/// The frames aren't zeroed. Nothing stays mapped when a page fails.
+pub fn map_alloc(pages: PageRange, flags: PageTableFlags) -> Result<(), PagingError> {
+    let flags = checked_flags(flags)?;
+    with_kernel_memory(|memory| {
+        let flush = RangeFlush::new(pages);
+        for page in pages {
+            let mapped = match memory.frame_allocator.allocate_frame() {
+                Some(frame) => memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)
+                    .map_err(|err| PagingError::from_map(err, page)),
+                None => Err(PagingError::FrameAllocationFailed),
+            };
+            match mapped {
+                Ok(page_flush) => flush.add(page_flush),
+                Err(err) => {
+                    flush.finish();
+                    rollback(memory, Page::range(pages.start, page), true);
+                    return Err(err);
+                }
+            }
+        }
+        flush.finish();
+        Ok(())
+    })
+}
+
+/// Undoes a partially mapped range
+fn rollback(memory: &mut KernelMemory, pages: PageRange, free_frames: bool) {
+    for page in pages {
+        if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+            flush.flush();
+            if free_frames {
+                let frame = unsafe { UnusedPhysFrame::new(frame) };
+                memory.frame_allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+/// Unmaps `pages` and returns their frames to the frame allocator
+///
+/// For ranges set up by `map_alloc`. Stops at the first page that
+/// isn't mapped.
+///
+/// # Unsafe
+/// ---------
+/// Nothing may use the memory anymore
+/// ----------
+pub unsafe fn unmap(pages: PageRange) -> Result<(), PagingError> {
+    unmap_range(pages, true)
+}
+
+/// Unmaps `pages`, leaving their frames alone
+///
+/// For ranges set up by `map_to_frames`, e.g. device memory
+///
+/// # Unsafe
+/// ---------
+/// Nothing may use the memory anymore
+/// ----------
+pub unsafe fn unmap_keep_frames(pages: PageRange) -> Result<(), PagingError> {
+    unmap_range(pages, false)
+}
+
+fn unmap_range(pages: PageRange, free_frames: bool) -> Result<(), PagingError> {
+    with_kernel_memory(|memory| {
+        let flush = RangeFlush::new(pages);
+        let mut result = Ok(());
+        for page in pages {
+            match memory.mapper.unmap(page) {
+                Ok((frame, page_flush)) => {
+                    flush.add(page_flush);
+                    if free_frames {
+                        let frame = unsafe { UnusedPhysFrame::new(frame) };
+                        memory.frame_allocator.deallocate_frame(frame);
+                    }
+                }
+                Err(err) => {
+                    result = Err(PagingError::from_unmap(err, page));
+                    break;
+                }
+            }
+        }
+        flush.finish();
+        result
+    })
+}
+
+/// Replaces the flags of every page in `pages`
+///
+/// Stops at the first page that isn't mapped
+pub fn protect(pages: PageRange, flags: PageTableFlags) -> Result<(), PagingError> {
+    let flags = checked_flags(flags)?;
+    with_kernel_memory(|memory| {
+        let flush = RangeFlush::new(pages);
+        let mut result = Ok(());
+        for page in pages {
+            match memory.mapper.update_flags(page, flags) {
+                Ok(page_flush) => flush.add(page_flush),
+                Err(err) => {
+                    result = Err(PagingError::from_flag_update(err, page));
+                    break;
+                }
+            }
+        }
+        flush.finish();
+        result
+    })
+}
+
+/// Current mapping of `page`
+pub fn query(page: Page) -> Result<Mapping, PagingError> {
+    use x86_64::registers::control::Cr3;
+
+    let addr = page.start_address();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    // Bytes covered by an entry of the P4, P3, P2 and P1 table
+    let entry_sizes = [1 << 39, Size1GiB::SIZE, Size2MiB::SIZE, Size4KiB::SIZE];
+    let (mut table_frame, _) = Cr3::read();
+
+    for (level, &index) in indices.iter().enumerate() {
+        let table_ptr: *const PageTable =
+            memory::phys_to_virt(table_frame.start_address()).as_ptr();
+        let entry = unsafe { &(*table_ptr)[index] };
+        let flags = entry.flags();
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(PagingError::NotMapped(page));
+        }
+        let page_size = entry_sizes[level];
+        if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            let offset = addr.as_u64() & (page_size - 1);
+            return Ok(Mapping {
+                page,
+                frame: PhysFrame::containing_address(entry.addr() + offset),
+                flags,
+                page_size,
+            });
+        }
+        table_frame = PhysFrame::containing_address(entry.addr());
+    }
+    unreachable!()
+}
+
+/// Physical address `addr` is mapped to
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let mapping = query(Page::containing_address(addr)).ok()?;
+    Some(mapping.frame.start_address() + u64::from(addr.page_offset()))
+}
+
+/// Whether the `len` bytes at `addr` are all mapped
+///
+/// False for non-canonical addresses and before `memory::init`, when
+/// the page tables can't be walked
+pub fn is_mapped(addr: u64, len: u64) -> bool {
+    if len == 0 {
+        return true;
+    }
+    if memory::physical_memory_offset().as_u64() == 0 {
+        return false;
+    }
+    let last = match addr.checked_add(len - 1) {
+        Some(last) => last,
+        None => return false,
+    };
+    let (start, end) = match (VirtAddr::try_new(addr), VirtAddr::try_new(last)) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => return false,
+    };
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    );
+    pages.all(|page| translate(page.start_address()).is_some())
+}
+
+/// Whether the CPU can map 1GiB pages
+///
+/// 2MiB pages are always available in long mode
+pub fn gigabyte_pages_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    // CPUID 0x8000_0001: EDX bit 26 is Page1GB
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+/// Maps the `size` bytes of physical memory at `phys` to `start`
+///
+/// Uses the largest pages the alignment of both addresses and the
+/// remaining size allow: 1GiB pages where supported, then 2MiB and
+/// 4KiB pages. The frames aren't allocated, so this is meant for memory
+/// like the physical memory window or framebuffers. Nothing stays
+/// mapped when a page fails.
+///
+/// # Unsafe
+/// ---------
+/// See `map_to_frames`
+/// ----------
+pub unsafe fn map_physical_region(
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    let aligned = |value: u64| value % Size4KiB::SIZE == 0;
+    if size == 0 || !aligned(start.as_u64()) || !aligned(phys.as_u64()) || !aligned(size) {
+        return Err(PagingError::Unaligned);
+    }
+    let flags = checked_flags(flags)?;
+    let gigabyte_pages = gigabyte_pages_supported();
+
+    with_kernel_memory(|memory| {
+        let mut offset = 0;
+        while offset < size {
+            let (virt, target, left) = (start + offset, phys + offset, size - offset);
+            let fits = |page_size: u64| {
+                virt.as_u64() % page_size == 0
+                    && target.as_u64() % page_size == 0
+                    && left >= page_size
+            };
+            let mapped = if gigabyte_pages && fits(Size1GiB::SIZE) {
+                map_one::<Size1GiB>(memory, virt, target, flags)
+            } else if fits(Size2MiB::SIZE) {
+                map_one::<Size2MiB>(memory, virt, target, flags)
+            } else {
+                map_one::<Size4KiB>(memory, virt, target, flags)
+            };
+            match mapped {
+                Ok(page_size) => offset += page_size,
+                Err(err) => {
+                    if offset > 0 {
+                        let _ = unmap_region_locked(memory, start, offset);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmaps `start..start + size`, leaving the frames alone
+///
+/// Undoes `map_physical_region`, whatever page sizes it picked. Stops
+/// at the first page that isn't mapped, or at a huge page reaching
+/// past the range.
+///
+/// # Unsafe
+/// ---------
+/// Nothing may use the memory anymore
+/// ----------
+pub unsafe fn unmap_region(start: VirtAddr, size: u64) -> Result<(), PagingError> {
+    if !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(PagingError::Unaligned);
+    }
+    with_kernel_memory(|memory| unmap_region_locked(memory, start, size))
+}
+
+/// Replaces the flags of `start..start + size`, whatever page sizes
+/// map it
+///
+/// Stops at the first page that isn't mapped, or at a huge page
+/// reaching past the range.
+pub fn protect_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    if !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(PagingError::Unaligned);
+    }
+    let flags = checked_flags(flags)?;
+    with_kernel_memory(|memory| {
+        let mut offset = 0;
+        while offset < size {
+            let virt = start + offset;
+            let page_size = huge_page_within(virt, size - offset)?;
+            if page_size == Size1GiB::SIZE {
+                protect_one::<Size1GiB>(memory, virt, flags | PageTableFlags::HUGE_PAGE)?;
+            } else if page_size == Size2MiB::SIZE {
+                protect_one::<Size2MiB>(memory, virt, flags | PageTableFlags::HUGE_PAGE)?;
+            } else {
+                protect_one::<Size4KiB>(memory, virt, flags)?;
+            }
+            offset += page_size;
+        }
+        Ok(())
+    })
+}
+
+/// Size of the page mapping `virt`, which must start there and end
+/// within `left` bytes
+fn huge_page_within(virt: VirtAddr, left: u64) -> Result<u64, PagingError> {
+    let page = Page::containing_address(virt);
+    let mapping = query(page)?;
+    if !virt.is_aligned(mapping.page_size) || left < mapping.page_size {
+        return Err(PagingError::ParentEntryHugePage(page));
+    }
+    Ok(mapping.page_size)
+}
+
+fn unmap_region_locked(
+    memory: &mut KernelMemory,
+    start: VirtAddr,
+    size: u64,
+) -> Result<(), PagingError> {
+    let mut offset = 0;
+    while offset < size {
+        let virt = start + offset;
+        let page_size = huge_page_within(virt, size - offset)?;
+        if page_size == Size1GiB::SIZE {
+            unmap_one::<Size1GiB>(memory, virt)?;
+        } else if page_size == Size2MiB::SIZE {
+            unmap_one::<Size2MiB>(memory, virt)?;
+        } else {
+            unmap_one::<Size4KiB>(memory, virt)?;
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// Maps the page of size `S` at `virt` to the frame at `phys`
+///
+/// Returns the size of the page
+fn map_one<S: PageSize>(
+    memory: &mut KernelMemory,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<u64, PagingError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(virt);
+    let frame = unsafe { UnusedPhysFrame::new(PhysFrame::<S>::containing_address(phys)) };
+    memory
+        .mapper
+        .map_to(page, frame, flags, &mut memory.frame_allocator)
+        .map(|flush| flush.flush())
+        .map_err(|err| PagingError::from_map(err, Page::containing_address(virt)))?;
+    Ok(S::SIZE)
+}
+
+/// Unmaps the page of size `S` at `virt`
+fn unmap_one<S: PageSize>(memory: &mut KernelMemory, virt: VirtAddr) -> Result<(), PagingError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let (_, flush) = memory
+        .mapper
+        .unmap(Page::<S>::containing_address(virt))
+        .map_err(|err| PagingError::from_unmap(err, Page::containing_address(virt)))?;
+    flush.flush();
+    Ok(())
+}
+
+/// Replaces the flags of the page of size `S` at `virt`
+fn protect_one<S: PageSize>(
+    memory: &mut KernelMemory,
+    virt: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), PagingError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    memory
+        .mapper
+        .update_flags(Page::<S>::containing_address(virt), flags)
+        .map_err(|err| PagingError::from_flag_update(err, Page::containing_address(virt)))?
+        .flush();
+    Ok(())
+}
@@ -0,0 +1,222 @@
+//! Page table inspector
+//!
+//! Walks an L4 page table hierarchy and reports its leaf mappings merged
+//! into ranges: virtually and physically contiguous pages of the same
+//! size and flags become one `PtRange`. The ranges can be dumped over
+//! serial, e.g. to diff the address space between boots, or collected
+//! to assert on them in tests.
+
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{memory, serial_println};
+
+/// Contiguous mapped range with uniform page size and flags
+///
+/// The flags are the effective ones: writable and user accessible only
+/// if every level allows it, no-execute if any level sets it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtRange {
+    pub start: VirtAddr,
+    /// Length in bytes
+    pub size: u64,
+    /// Physical address `start` maps to
+    pub phys: PhysAddr,
+    pub flags: PageTableFlags,
+    /// Size of the pages making up the range: 4KiB, 2MiB or 1GiB
+    pub page_size: u64,
+}
+
+impl PtRange {
+    /// Exclusive end, wraps to zero for a range ending at the top of
+    /// the address space
+    pub fn end(&self) -> u64 {
+        self.start.as_u64().wrapping_add(self.size)
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr.as_u64() - self.start.as_u64() < self.size
+    }
+
+    /// Physical address `addr` maps to, if the range contains it
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        if self.contains(addr) {
+            Some(self.phys + (addr - self.start))
+        } else {
+            None
+        }
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+
+    pub fn user_accessible(&self) -> bool {
+        self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    }
+
+    pub fn no_execute(&self) -> bool {
+        self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+
+    pub fn global(&self) -> bool {
+        self.flags.contains(PageTableFlags::GLOBAL)
+    }
+
+    pub fn huge(&self) -> bool {
+        self.page_size > 4096
+    }
+
+    /// Whether `next` continues the range
+    fn extends_to(&self, next: &PtRange) -> bool {
+        self.end() == next.start.as_u64()
+            && self.phys + self.size == next.phys
+            && self.flags == next.flags
+            && self.page_size == next.page_size
+    }
+}
+
+impl fmt::Display for PtRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        let page_size = match self.page_size {
+            0x4000_0000 => "1G",
+            0x20_0000 => "2M",
+            _ => "4K",
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {}{}{}{} {} {:>8}K",
+            self.start.as_u64(),
+            self.end(),
+            self.phys.as_u64(),
+            flag(self.writable(), 'w'),
+            flag(self.user_accessible(), 'u'),
+            flag(!self.no_execute(), 'x'),
+            flag(self.global(), 'g'),
+            page_size,
+            self.size / 1024
+        )
+    }
+}
+
+/// Collects leaf mappings into ranges
+struct Merger<F: FnMut(&PtRange)> {
+    current: Option<PtRange>,
+    f: F,
+}
+
+impl<F: FnMut(&PtRange)> Merger<F> {
+    fn push(&mut self, next: PtRange) {
+        if let Some(current) = self.current.as_mut() {
+            if current.extends_to(&next) {
+                current.size += next.size;
+                return;
+            }
+            (self.f)(current);
+        }
+        self.current = Some(next);
+    }
+
+    fn finish(mut self) {
+        if let Some(current) = self.current.take() {
+            (self.f)(&current);
+        }
+    }
+}
+
+/// Frame of the active L4 table
+pub fn active_level_4() -> PhysFrame {
+    Cr3::read().0
+}
+
+/// Calls `f` with the merged ranges mapped by the L4 table in `level_4`,
+/// in address order
+///
+/// Tables are read through the physical memory window, so
+/// `memory::init` must have run.
+pub fn walk(level_4: PhysFrame, f: impl FnMut(&PtRange)) {
+    let mut merger = Merger { current: None, f };
+    walk_table(
+        level_4.start_address(),
+        4,
+        0,
+        PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        &mut merger,
+    );
+    merger.finish();
+}
+
+/// Visits the entries of the table at `table` of the given `level`,
+/// which maps the addresses from `base`
+///
+/// `inherited` carries the flags of the parent entries
+fn walk_table<F: FnMut(&PtRange)>(
+    table: PhysAddr,
+    level: u32,
+    base: u64,
+    inherited: PageTableFlags,
+    merger: &mut Merger<F>,
+) {
+    let table_ptr: *const PageTable = memory::phys_to_virt(table).as_ptr();
+    let table = unsafe { &*table_ptr };
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = canonical(base + index as u64 * entry_size);
+        // Only the permission flags are reported, the rest (accessed,
+        // dirty, ..) change behind our back
+        let effective =
+            (inherited & flags & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE))
+                | ((inherited | flags) & PageTableFlags::NO_EXECUTE);
+
+        if level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            merger.push(PtRange {
+                start: VirtAddr::new(start),
+                size: entry_size,
+                phys: entry.addr(),
+                flags: effective | (flags & PageTableFlags::GLOBAL),
+                page_size: entry_size,
+            });
+        } else {
+            walk_table(entry.addr(), level - 1, start, effective, merger);
+        }
+    }
+}
+
+/// Sign extends bit 47 of `addr`
+fn canonical(addr: u64) -> u64 {
+    if addr & (1 << 47) != 0 {
+        addr | 0xffff_0000_0000_0000
+    } else {
+        addr
+    }
+}
+
+/// Merged ranges of the active page table
+pub fn ranges() -> Vec<PtRange> {
+    let mut ranges = Vec::new();
+    walk(active_level_4(), |range| ranges.push(*range));
+    ranges
+}
+
+/// Prints the ranges of the active page table over serial
+pub fn dump() {
+    dump_table(active_level_4());
+}
+
+/// Prints the ranges mapped by the L4 table in `level_4` over serial
+pub fn dump_table(level_4: PhysFrame) {
+    serial_println!(
+        "[pt] page table at {:#x}:",
+        level_4.start_address().as_u64()
+    );
+    walk(level_4, |range| serial_println!("[pt] {}", range));
+}
@@ -0,0 +1,167 @@
+//! Lock-free byte queue between an interrupt handler and the kernel
+//!
+//! `ByteRing` has a single producer and a single consumer. The producer
+//! is an interrupt handler, so neither side may take a lock the other
+//! holds: the head and tail are atomics each only moved by one side.
+//! The ring can't tell who is calling, so `push` and `pop` are unsafe
+//! and their callers keep each side to one caller at a time, usually by
+//! disabling interrupts.
+//!
+//! `AsyncRing` does that for input read by tasks: it wraps a `ByteRing`
+//! and wakes the task waiting for it on each push.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Bytes a `ByteRing` holds
+pub const RING_CAPACITY: usize = 256;
+
+/// Bounded single producer, single consumer byte queue
+///
+/// A push to a full ring drops the byte and counts it.
+pub struct ByteRing {
+    buf: UnsafeCell<[u8; RING_CAPACITY]>,
+    /// Bytes pushed so far, only moved by the producer
+    head: AtomicUsize,
+    /// Bytes popped so far, only moved by the consumer
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+// The producer only writes slots the consumer is done with, and
+// `push` and `pop` leave one producer and one consumer to their callers
+unsafe impl Sync for ByteRing {}
+
+impl ByteRing {
+    pub const fn new() -> Self {
+        ByteRing {
+            buf: UnsafeCell::new([0; RING_CAPACITY]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds `byte`, false when the ring is full
+    ///
+    /// # Unsafe
+    /// ---------
+    /// No other `push` may run at the same time, including one this
+    /// call interrupted
+    /// ----------
+    pub unsafe fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == RING_CAPACITY {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        // Not full, so the consumer is done with this slot
+        (*self.buf.get())[head % RING_CAPACITY] = byte;
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Takes the oldest byte
+    ///
+    /// # Unsafe
+    /// ---------
+    /// No other `pop` may run at the same time, including one this
+    /// call interrupted
+    /// ----------
+    pub unsafe fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let byte = (*self.buf.get())[tail % RING_CAPACITY];
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Bytes waiting
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes dropped because the ring was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ByteRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `ByteRing` filled by an interrupt handler and read by a task
+///
+/// Pushes and pops run with interrupts off, so on one CPU each side has
+/// a single caller at a time.
+pub struct AsyncRing {
+    ring: ByteRing,
+    /// Waker of the task waiting in `poll_pop`
+    waker: Mutex<Option<Waker>>,
+}
+
+impl AsyncRing {
+    pub const fn new() -> Self {
+        AsyncRing {
+            ring: ByteRing::new(),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Adds `byte` and wakes the waiting task, false when the ring is full
+    pub fn push(&self, byte: u8) -> bool {
+        let pushed = without_interrupts(|| unsafe { self.ring.push(byte) });
+        // Only locked with interrupts off, so this can't spin
+        if let Some(waker) = self.waker.try_lock().and_then(|mut waker| waker.take()) {
+            waker.wake();
+        }
+        pushed
+    }
+
+    /// Takes the oldest byte
+    pub fn pop(&self) -> Option<u8> {
+        without_interrupts(|| unsafe { self.ring.pop() })
+    }
+
+    /// Takes the oldest byte, or has the task of `cx` woken by the next
+    /// push
+    ///
+    /// Only one task should poll at a time, the last one is woken
+    pub fn poll_pop(&self, cx: &mut Context) -> Poll<u8> {
+        if let Some(byte) = self.pop() {
+            return Poll::Ready(byte);
+        }
+        without_interrupts(|| *self.waker.lock() = Some(cx.waker().clone()));
+        // The byte may have come in before the waker was stored
+        match self.pop() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Bytes dropped because the ring was full
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped()
+    }
+}
+
+impl Default for AsyncRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
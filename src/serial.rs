@@ -1,16 +1,97 @@
 //! Initializes and handles sending of data to the
 //! serial port
+//!
+//! Input from COM1 is interrupt driven: the IRQ 4 handler moves
+//! received bytes into a ring that `read_byte` and `ReadBytes` take
+//! them from.
 
+use core::task::{Context, Poll};
 use lazy_static::lazy_static;
 use spin::Mutex;
-use uart_16550::SerialPort;
+use x86_64::instructions::interrupts::{self as cpu_interrupts, without_interrupts};
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::{self, IrqError, IrqResult};
+use crate::ring::AsyncRing;
+use crate::uart::{self, ComPort, Uart};
+
+/// I/O port of COM1
+pub const COM1: u16 = 0x3f8;
+/// PIC line of COM1
+pub const COM1_IRQ: u8 = 4;
 
 lazy_static! {
-    pub static ref SERIAL_A: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    /// COM1, where `serial_print!` writes
+    pub static ref SERIAL_A: &'static Mutex<Uart> = uart::port(ComPort::Com1);
+}
+
+/// Bytes received on COM1 and not read yet
+static RX: AsyncRing = AsyncRing::new();
+
+/// Turns on the receive interrupt of COM1
+///
+/// Called by `init`
+pub fn init_input() -> Result<(), IrqError> {
+    interrupts::register_irq(COM1_IRQ, receive_interrupt)?;
+    without_interrupts(|| SERIAL_A.lock().set_receive_interrupt(true));
+    Ok(())
+}
+
+/// Whether COM1 holds a received byte
+fn data_ready() -> bool {
+    // Line status bit 0
+    unsafe { Port::<u8>::new(COM1 + 5).read() & 0x01 != 0 }
+}
+
+/// Moves the received bytes to `RX`
+fn receive_interrupt(_line: u8) -> IrqResult {
+    let mut received = false;
+    // The FIFO may hold several bytes by now
+    while data_ready() {
+        RX.push(unsafe { Port::<u8>::new(COM1).read() });
+        received = true;
+    }
+    if !received {
+        return IrqResult::NotMine;
+    }
+    IrqResult::Handled
+}
+
+/// Oldest byte received on COM1, if any
+pub fn read_byte() -> Option<u8> {
+    RX.pop()
+}
+
+/// Waits for a byte on COM1, halting in between
+///
+/// Interrupts must be enabled, they are again on return
+pub fn wait_byte() -> u8 {
+    loop {
+        // A byte arriving between the check and `hlt` would be missed
+        cpu_interrupts::disable();
+        if let Some(byte) = RX.pop() {
+            cpu_interrupts::enable();
+            return byte;
+        }
+        cpu_interrupts::enable_interrupts_and_hlt();
+    }
+}
+
+/// Bytes dropped because they weren't read in time
+pub fn dropped_bytes() -> u64 {
+    RX.dropped()
+}
+
+/// Stream of the bytes received on COM1
+///
+/// `poll_next` follows `futures::Stream`, the stream never ends
+#[derive(Debug, Default)]
+pub struct ReadBytes;
+
+impl ReadBytes {
+    pub fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<u8>> {
+        RX.poll_pop(cx).map(Some)
+    }
 }
 
 #[doc(hidden)]
@@ -0,0 +1,375 @@
+//! Slab allocator for fixed-size kernel objects
+//!
+//! A `SlabCache<T>` hands out `T`s from 4KiB slabs mapped in a
+//! dedicated virtual region. Each slab starts with a header followed
+//! by as many objects as fit in the page. Slabs sit on one of three
+//! lists depending on how many of their objects are in use.
+
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, Page, PageSize, Size4KiB, UnusedPhysFrame,
+};
+use x86_64::VirtAddr;
+
+use crate::{allocator, kaslr, memory};
+
+/// Size of the slab region
+pub const SLAB_REGION_SIZE: u64 = 0x_10_0000_0000;
+/// Empty slabs a cache keeps before giving pages back
+const MAX_EMPTY_SLABS: usize = 2;
+
+const SLAB_SIZE: usize = Size4KiB::SIZE as usize;
+
+/// Offset of the next unused page in the slab region
+///
+/// Pages of released slabs are unmapped but not reused
+static NEXT_SLAB_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Start of the virtual region slabs are mapped in
+///
+/// Randomised at boot, see `kaslr`
+pub fn slab_start() -> u64 {
+    kaslr::layout().slabs
+}
+
+/// Bookkeeping at the start of every slab
+#[repr(C)]
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject, // First free object in this slab
+    in_use: usize,
+}
+
+/// Free object slot, linked through the slot itself
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Doubly linked list of slabs
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+}
+
+/// Usage statistics of a cache
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub full_slabs: usize,
+    pub partial_slabs: usize,
+    pub empty_slabs: usize,
+    /// Objects currently handed out
+    pub active_objects: usize,
+    pub total_allocs: u64,
+    pub total_frees: u64,
+}
+
+/// Slab lists of a cache
+struct Slabs {
+    full: SlabList,
+    partial: SlabList,
+    empty: SlabList,
+    active_objects: usize,
+    total_allocs: u64,
+    total_frees: u64,
+}
+
+/// Cache of same-shaped objects
+///
+/// Every object handed out by `alloc` is initialized with the
+/// cache's constructor
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: fn() -> T,
+    slabs: Mutex<Slabs>,
+    _marker: PhantomData<T>,
+}
+
+// Slab pointers are only touched with the lock held
+unsafe impl<T: Send> Send for SlabCache<T> {}
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Creates an empty cache
+    ///
+    /// No memory is mapped until the first allocation
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        SlabCache {
+            name,
+            constructor,
+            slabs: Mutex::new(Slabs {
+                full: SlabList::new(),
+                partial: SlabList::new(),
+                empty: SlabList::new(),
+                active_objects: 0,
+                total_allocs: 0,
+                total_frees: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Bytes between two objects of a slab
+    fn stride() -> usize {
+        let size = mem::size_of::<T>().max(mem::size_of::<FreeObject>());
+        align_up(size, Self::align())
+    }
+
+    fn align() -> usize {
+        mem::align_of::<T>().max(mem::align_of::<FreeObject>())
+    }
+
+    /// Offset of the first object from the slab start
+    fn first_object() -> usize {
+        align_up(mem::size_of::<SlabHeader>(), Self::align())
+    }
+
+    /// Number of objects fitting in one slab
+    pub fn objects_per_slab() -> usize {
+        SLAB_SIZE.saturating_sub(Self::first_object()) / Self::stride()
+    }
+
+    /// Allocates an object initialized by the cache's constructor
+    ///
+    /// Panics when no memory is left
+    pub fn alloc(&self) -> SlabBox<T> {
+        self.try_alloc().expect("Slab cache out of memory")
+    }
+
+    /// Allocates an object initialized by the cache's constructor
+    ///
+    /// Returns `None` when no memory is left
+    pub fn try_alloc(&self) -> Option<SlabBox<T>> {
+        assert!(
+            Self::objects_per_slab() > 0,
+            "Object too large for a slab"
+        );
+        let object = {
+            let mut slabs = self.slabs.lock();
+            unsafe { self.take_object(&mut slabs)? }
+        };
+        unsafe { object.as_ptr().write((self.constructor)()) };
+        Some(SlabBox {
+            object,
+            cache: self,
+        })
+    }
+
+    /// Current usage of the cache
+    pub fn stats(&self) -> SlabStats {
+        let slabs = self.slabs.lock();
+        SlabStats {
+            name: self.name,
+            object_size: mem::size_of::<T>(),
+            objects_per_slab: Self::objects_per_slab(),
+            full_slabs: slabs.full.len,
+            partial_slabs: slabs.partial.len,
+            empty_slabs: slabs.empty.len,
+            active_objects: slabs.active_objects,
+            total_allocs: slabs.total_allocs,
+            total_frees: slabs.total_frees,
+        }
+    }
+
+    /// Gives the pages of all empty slabs back to the frame allocator
+    pub fn shrink(&self) {
+        let mut slabs = self.slabs.lock();
+        while !slabs.empty.head.is_null() {
+            unsafe {
+                let slab = slabs.empty.head;
+                slabs.empty.remove(slab);
+                release_slab(slab);
+            }
+        }
+    }
+
+    /// Pops a free slot, adding a slab when none is left
+    unsafe fn take_object(&self, slabs: &mut Slabs) -> Option<NonNull<T>> {
+        if slabs.partial.head.is_null() {
+            let slab = if slabs.empty.head.is_null() {
+                self.new_slab()?
+            } else {
+                let slab = slabs.empty.head;
+                slabs.empty.remove(slab);
+                slab
+            };
+            slabs.partial.push(slab);
+        }
+
+        let slab = slabs.partial.head;
+        let object = (*slab).free;
+        (*slab).free = (*object).next;
+        (*slab).in_use += 1;
+        if (*slab).in_use == Self::objects_per_slab() {
+            slabs.partial.remove(slab);
+            slabs.full.push(slab);
+        }
+
+        slabs.active_objects += 1;
+        slabs.total_allocs += 1;
+        NonNull::new(object as *mut T)
+    }
+
+    /// Returns a slot to its slab
+    unsafe fn free_object(&self, object: NonNull<T>) {
+        let mut slabs = self.slabs.lock();
+        let slab = (object.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+
+        let was_full = (*slab).in_use == Self::objects_per_slab();
+        let free = object.as_ptr() as *mut FreeObject;
+        (*free).next = (*slab).free;
+        (*slab).free = free;
+        (*slab).in_use -= 1;
+
+        if was_full {
+            slabs.full.remove(slab);
+            slabs.partial.push(slab);
+        }
+        if (*slab).in_use == 0 {
+            slabs.partial.remove(slab);
+            if slabs.empty.len < MAX_EMPTY_SLABS {
+                slabs.empty.push(slab);
+            } else {
+                release_slab(slab);
+            }
+        }
+
+        slabs.active_objects -= 1;
+        slabs.total_frees += 1;
+    }
+
+    /// Maps a page and lays out a fresh slab in it
+    unsafe fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let start = map_slab_page()?;
+        let slab = start.as_mut_ptr::<SlabHeader>();
+        slab.write(SlabHeader {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            free: ptr::null_mut(),
+            in_use: 0,
+        });
+
+        // Thread the free list through the slots, first slot on top
+        for index in (0..Self::objects_per_slab()).rev() {
+            let offset = Self::first_object() + index * Self::stride();
+            let object = (start + offset).as_mut_ptr::<FreeObject>();
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+        }
+        Some(slab)
+    }
+}
+
+/// Owning pointer to an object of a `SlabCache`
+///
+/// The object is dropped and its slot returned to the cache
+/// when the box goes out of scope
+pub struct SlabBox<'a, T> {
+    object: NonNull<T>,
+    cache: &'a SlabCache<T>,
+}
+
+impl<'a, T> Deref for SlabBox<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<'a, T> DerefMut for SlabBox<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<'a, T> Drop for SlabBox<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.free_object(self.object);
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Maps a fresh frame at the next unused page of the slab region
+fn map_slab_page() -> Option<VirtAddr> {
+    let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+    let kernel_memory = kernel_memory.as_mut()?;
+
+    let offset = NEXT_SLAB_OFFSET.fetch_add(SLAB_SIZE as u64, Ordering::Relaxed);
+    if offset >= SLAB_REGION_SIZE {
+        return None;
+    }
+    let start = VirtAddr::new(slab_start() + offset);
+    allocator::map_pages(
+        start,
+        SLAB_SIZE,
+        &mut kernel_memory.mapper,
+        &mut kernel_memory.frame_allocator,
+    )
+    .ok()?;
+    Some(start)
+}
+
+/// Unmaps a slab and frees its frame
+unsafe fn release_slab(slab: *mut SlabHeader) {
+    let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+    let kernel_memory = kernel_memory
+        .as_mut()
+        .expect("Slab mapped without kernel memory");
+
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::from_ptr(slab));
+    let (frame, flush) = kernel_memory
+        .mapper
+        .unmap(page)
+        .expect("Slab page not mapped");
+    flush.flush();
+    kernel_memory
+        .frame_allocator
+        .deallocate_frame(UnusedPhysFrame::new(frame));
+}
@@ -0,0 +1,235 @@
+//! Kernel stack allocator
+//!
+//! Stacks live in a dedicated virtual region split into fixed slots.
+//! The lowest page of each slot is never mapped, so a stack overflowing
+//! downwards faults on it instead of corrupting the memory below.
+//! Guard pages are recorded with a name, letting the fault handlers
+//! report which stack overflowed.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameDeallocator, Mapper, Page, PageSize, Size4KiB, UnusedPhysFrame,
+};
+use x86_64::VirtAddr;
+
+use crate::{allocator, gdt, kaslr, memory};
+
+/// Virtual space taken by one stack including its guard page
+pub const STACK_SLOT_SIZE: u64 = 64 * 1024;
+/// Number of stack slots in the region
+pub const MAX_STACKS: usize = 64;
+/// Size of the kernel stack region
+pub const STACKS_SIZE: u64 = STACK_SLOT_SIZE * MAX_STACKS as u64;
+/// Number of named guard pages that can be registered
+const MAX_GUARDS: usize = MAX_STACKS + 4;
+
+/// Pages of the interrupt stacks
+const IST_STACK_PAGES: u64 = 4;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Unmapped page owned by a stack or region
+#[derive(Debug, Clone, Copy)]
+struct Guard {
+    page: Page,
+    hit: GuardHit,
+}
+
+/// What a guard page protects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardKind {
+    /// Below a stack
+    Stack,
+    /// Next to a memory region such as the heap
+    Region,
+}
+
+/// Owner of a guard page that was accessed
+#[derive(Debug, Clone, Copy)]
+pub struct GuardHit {
+    pub name: &'static str,
+    pub kind: GuardKind,
+}
+
+impl fmt::Display for GuardHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            GuardKind::Stack => write!(f, "stack overflow in {}", self.name),
+            GuardKind::Region => write!(f, "access past the end of {}", self.name),
+        }
+    }
+}
+
+/// Named guard pages
+///
+/// Changed with interrupts disabled so fault handlers can read it
+static GUARDS: Mutex<[Option<Guard>; MAX_GUARDS]> = Mutex::new([None; MAX_GUARDS]);
+
+/// Slots currently holding a stack
+static SLOTS: Mutex<[bool; MAX_STACKS]> = Mutex::new([false; MAX_STACKS]);
+
+/// Errors returned by the stack allocator
+#[derive(Debug)]
+pub enum StackError {
+    /// All stack slots are in use
+    NoFreeSlot,
+    /// The stack doesn't fit in a slot
+    TooLarge,
+    /// `memory::install` hasn't been called
+    NoKernelMemory,
+    Map(MapToError),
+}
+
+/// Stack mapped in the kernel stack region
+#[derive(Debug)]
+pub struct KernelStack {
+    name: &'static str,
+    slot: usize,
+    pages: u64,
+}
+
+impl KernelStack {
+    /// Lowest mapped address
+    pub fn bottom(&self) -> VirtAddr {
+        slot_start(self.slot) + PAGE_SIZE
+    }
+
+    /// Address one past the highest byte, loaded into `rsp`
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + self.pages * PAGE_SIZE
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Start of the kernel stack region
+///
+/// Randomised at boot, see `kaslr`
+pub fn stacks_start() -> u64 {
+    kaslr::layout().stacks
+}
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(stacks_start() + slot as u64 * STACK_SLOT_SIZE)
+}
+
+/// Maps a stack of `pages` pages with an unmapped guard page below it
+pub fn allocate_stack(name: &'static str, pages: u64) -> Result<KernelStack, StackError> {
+    if pages == 0 || (pages + 1) * PAGE_SIZE > STACK_SLOT_SIZE {
+        return Err(StackError::TooLarge);
+    }
+    let slot = {
+        let mut slots = SLOTS.lock();
+        let slot = slots
+            .iter()
+            .position(|&used| !used)
+            .ok_or(StackError::NoFreeSlot)?;
+        slots[slot] = true;
+        slot
+    };
+    let stack = KernelStack { name, slot, pages };
+
+    let mapped = {
+        let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+        match kernel_memory.as_mut() {
+            Some(kernel_memory) => allocator::map_pages(
+                stack.bottom(),
+                (pages * PAGE_SIZE) as usize,
+                &mut kernel_memory.mapper,
+                &mut kernel_memory.frame_allocator,
+            )
+            .map_err(StackError::Map),
+            None => Err(StackError::NoKernelMemory),
+        }
+    };
+    if let Err(err) = mapped {
+        SLOTS.lock()[slot] = false;
+        return Err(err);
+    }
+
+    let guard = Page::containing_address(slot_start(slot));
+    register_guard(name, GuardKind::Stack, guard);
+    Ok(stack)
+}
+
+/// Unmaps a stack and frees its frames
+///
+/// # Unsafe
+/// ---------
+/// Nothing may run on the stack anymore
+/// ----------
+pub unsafe fn free_stack(stack: KernelStack) {
+    {
+        let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+        let kernel_memory = kernel_memory
+            .as_mut()
+            .expect("Stack mapped without kernel memory");
+
+        let first = Page::containing_address(stack.bottom());
+        let last = Page::containing_address(stack.top() - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            let (frame, flush) = kernel_memory
+                .mapper
+                .unmap(page)
+                .expect("Stack page not mapped");
+            flush.flush();
+            kernel_memory
+                .frame_allocator
+                .deallocate_frame(UnusedPhysFrame::new(frame));
+        }
+    }
+    unregister_guard(Page::containing_address(slot_start(stack.slot)));
+    SLOTS.lock()[stack.slot] = false;
+}
+
+/// Records `page` as a guard page named `name`
+///
+/// The page must stay unmapped
+pub fn register_guard(name: &'static str, kind: GuardKind, page: Page) {
+    interrupts::without_interrupts(|| {
+        let mut guards = GUARDS.lock();
+        if let Some(entry) = guards.iter_mut().find(|g| g.is_none()) {
+            *entry = Some(Guard {
+                page,
+                hit: GuardHit { name, kind },
+            });
+        }
+    })
+}
+
+fn unregister_guard(page: Page) {
+    interrupts::without_interrupts(|| {
+        let mut guards = GUARDS.lock();
+        for entry in guards.iter_mut() {
+            if entry.map_or(false, |g| g.page == page) {
+                *entry = None;
+            }
+        }
+    })
+}
+
+/// Owner of the guard page containing `addr`
+///
+/// Called from fault handlers, so it never waits for the lock
+pub fn guard_hit(addr: VirtAddr) -> Option<GuardHit> {
+    let guards = GUARDS.try_lock()?;
+    let page = Page::containing_address(addr);
+    guards.iter().flatten().find(|g| g.page == page).map(|g| g.hit)
+}
+
+/// Moves the interrupt stack from its boot array into a guarded stack
+///
+/// Call once `memory::install` is done
+pub fn init_ist_stacks() -> Result<(), StackError> {
+    let double_fault = allocate_stack("double fault", IST_STACK_PAGES)?;
+    unsafe {
+        gdt::set_ist_stack(gdt::DOUBLE_FAULT_IST_IDX, double_fault.top());
+    }
+    // The IST stack is used for the rest of the kernel's life
+    // and is never freed
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Kernel symbol table
+//!
+//! The kernel reserves the `.ksymtab` section, which
+//! `tools/embed_symbols.py` fills after linking with the start, size and
+//! demangled name of every function. The runner in `.cargo/config` does
+//! that before each boot, so crash output resolves return addresses to
+//! `function+offset` without the kernel binary at hand.
+//!
+//! Table layout, little endian:
+//! `[b"KSYM"][count: u32][count entries][names]`, each entry being
+//! `[start: u64][size: u32][name offset: u32]`, sorted by start. Names
+//! are NUL terminated, their offsets are from the start of the table.
+
+use core::fmt;
+
+/// Bytes reserved for the table
+pub const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+// Zeroed here, filled in the kernel binary after linking. Defined in
+// assembly so the compiler can't assume the contents. The size must
+// match `SYMBOL_TABLE_SIZE`.
+global_asm!(
+    "
+    .section .ksymtab, \"a\", @progbits
+    .balign 8
+    .global __ksymtab
+__ksymtab:
+    .zero 262144
+    .previous
+"
+);
+
+extern "C" {
+    /// The reserved table, `SYMBOL_TABLE_SIZE` bytes
+    static __ksymtab: [u8; SYMBOL_TABLE_SIZE];
+}
+
+fn table() -> &'static [u8] {
+    unsafe { &__ksymtab }
+}
+
+fn read_u32(offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&table()[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&table()[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Number of symbols, 0 when no table was embedded
+pub fn count() -> usize {
+    if &table()[..4] != MAGIC {
+        return 0;
+    }
+    let count = read_u32(4) as usize;
+    // A table that doesn't fit is ignored
+    if count > (SYMBOL_TABLE_SIZE - HEADER_SIZE) / ENTRY_SIZE {
+        return 0;
+    }
+    count
+}
+
+/// Whether the build embedded a symbol table
+pub fn is_embedded() -> bool {
+    count() != 0
+}
+
+/// Start, size and name offset of entry `index`
+fn entry(index: usize) -> (u64, u64, usize) {
+    let offset = HEADER_SIZE + index * ENTRY_SIZE;
+    (
+        read_u64(offset),
+        u64::from(read_u32(offset + 8)),
+        read_u32(offset + 12) as usize,
+    )
+}
+
+/// NUL terminated name at `offset`
+fn name(offset: usize) -> Option<&'static str> {
+    let bytes = table().get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Function containing an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub start: u64,
+    /// Bytes from the start of the function
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    /// Like `x86_kernel::init+0x1c`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Function containing `addr`, if the table has one
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    // Last entry starting at or below `addr`
+    let (mut low, mut high) = (0, count());
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if entry(middle).0 <= addr {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let (start, size, name_offset) = entry(low.checked_sub(1)?);
+    // A return address may sit right past a call ending the function
+    if addr - start > size {
+        return None;
+    }
+    Some(Symbol {
+        name: name(name_offset)?,
+        start,
+        offset: addr - start,
+    })
+}
@@ -0,0 +1,334 @@
+//! Exception entries saving every register
+//!
+//! Every exception enters through an assembly trampoline that pushes
+//! all general purpose registers next to the frame the CPU saved, before
+//! any compiled code can change them. Handlers get the whole `Context`:
+//! crash reports show the exact registers of the interrupted code, and
+//! a debugger can change any of them before it resumes.
+
+use core::mem::{self, size_of};
+
+use crate::backtrace::Registers;
+use crate::{gdb, interrupts, monitor};
+
+/// Vector of the debug exception
+pub const DEBUG_VECTOR: u64 = 1;
+/// Vector of the breakpoint exception
+pub const BREAKPOINT_VECTOR: u64 = 3;
+
+/// Trap flag of RFLAGS, raises a debug exception after each instruction
+pub const RFLAGS_TRAP: u64 = 1 << 8;
+
+/// Registers of the interrupted code, as laid out by the trampolines
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// Pushed by the trampoline
+    pub vector: u64,
+    /// Pushed by the CPU, or zero from the trampoline for exceptions
+    /// without one
+    pub error_code: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Context {
+    pub fn registers(&self) -> Registers {
+        Registers {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            rsp: self.rsp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rflags: self.rflags,
+        }
+    }
+
+    /// Changes the registers the interrupted code resumes with
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.rax = registers.rax;
+        self.rbx = registers.rbx;
+        self.rcx = registers.rcx;
+        self.rdx = registers.rdx;
+        self.rsi = registers.rsi;
+        self.rdi = registers.rdi;
+        self.rbp = registers.rbp;
+        self.rsp = registers.rsp;
+        self.r8 = registers.r8;
+        self.r9 = registers.r9;
+        self.r10 = registers.r10;
+        self.r11 = registers.r11;
+        self.r12 = registers.r12;
+        self.r13 = registers.r13;
+        self.r14 = registers.r14;
+        self.r15 = registers.r15;
+        self.rip = registers.rip;
+        self.rflags = registers.rflags;
+    }
+}
+
+// Exceptions without an error code get a zero in its place, so every
+// `Context` has the same layout. The CPU aligns the stack before
+// pushing its frame, and with the error code, the vector and the 15
+// registers the call finds it 16 byte aligned.
+global_asm!(
+    r#"
+.global trap_entry_0
+trap_entry_0:
+    pushq $0
+    pushq $0
+    jmp trap_common
+
+.global trap_entry_1
+trap_entry_1:
+    pushq $0
+    pushq $1
+    jmp trap_common
+
+.global trap_entry_2
+trap_entry_2:
+    pushq $0
+    pushq $2
+    jmp trap_common
+
+.global trap_entry_3
+trap_entry_3:
+    pushq $0
+    pushq $3
+    jmp trap_common
+
+.global trap_entry_4
+trap_entry_4:
+    pushq $0
+    pushq $4
+    jmp trap_common
+
+.global trap_entry_5
+trap_entry_5:
+    pushq $0
+    pushq $5
+    jmp trap_common
+
+.global trap_entry_6
+trap_entry_6:
+    pushq $0
+    pushq $6
+    jmp trap_common
+
+.global trap_entry_7
+trap_entry_7:
+    pushq $0
+    pushq $7
+    jmp trap_common
+
+.global trap_entry_8
+trap_entry_8:
+    pushq $8
+    jmp trap_common
+
+.global trap_entry_10
+trap_entry_10:
+    pushq $10
+    jmp trap_common
+
+.global trap_entry_11
+trap_entry_11:
+    pushq $11
+    jmp trap_common
+
+.global trap_entry_12
+trap_entry_12:
+    pushq $12
+    jmp trap_common
+
+.global trap_entry_13
+trap_entry_13:
+    pushq $13
+    jmp trap_common
+
+.global trap_entry_14
+trap_entry_14:
+    pushq $14
+    jmp trap_common
+
+.global trap_entry_16
+trap_entry_16:
+    pushq $0
+    pushq $16
+    jmp trap_common
+
+.global trap_entry_17
+trap_entry_17:
+    pushq $17
+    jmp trap_common
+
+.global trap_entry_18
+trap_entry_18:
+    pushq $0
+    pushq $18
+    jmp trap_common
+
+.global trap_entry_19
+trap_entry_19:
+    pushq $0
+    pushq $19
+    jmp trap_common
+
+.global trap_entry_20
+trap_entry_20:
+    pushq $0
+    pushq $20
+    jmp trap_common
+
+.global trap_entry_30
+trap_entry_30:
+    pushq $30
+    jmp trap_common
+
+trap_common:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, %rdi
+    cld
+    call trap_dispatch
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+    add $16, %rsp
+    iretq
+"#
+);
+
+extern "C" {
+    fn trap_entry_0();
+    fn trap_entry_1();
+    fn trap_entry_2();
+    fn trap_entry_3();
+    fn trap_entry_4();
+    fn trap_entry_5();
+    fn trap_entry_6();
+    fn trap_entry_7();
+    fn trap_entry_8();
+    fn trap_entry_10();
+    fn trap_entry_11();
+    fn trap_entry_12();
+    fn trap_entry_13();
+    fn trap_entry_14();
+    fn trap_entry_16();
+    fn trap_entry_17();
+    fn trap_entry_18();
+    fn trap_entry_19();
+    fn trap_entry_20();
+    fn trap_entry_30();
+}
+
+/// IDT entry of the exception `vector`, typed as the handler of its
+/// IDT slot
+///
+/// Panics for vectors without a trampoline
+pub fn entry<F: Copy>(vector: u8) -> F {
+    let entry: unsafe extern "C" fn() = match vector {
+        0 => trap_entry_0,
+        1 => trap_entry_1,
+        2 => trap_entry_2,
+        3 => trap_entry_3,
+        4 => trap_entry_4,
+        5 => trap_entry_5,
+        6 => trap_entry_6,
+        7 => trap_entry_7,
+        8 => trap_entry_8,
+        10 => trap_entry_10,
+        11 => trap_entry_11,
+        12 => trap_entry_12,
+        13 => trap_entry_13,
+        14 => trap_entry_14,
+        16 => trap_entry_16,
+        17 => trap_entry_17,
+        18 => trap_entry_18,
+        19 => trap_entry_19,
+        20 => trap_entry_20,
+        30 => trap_entry_30,
+        _ => panic!("no trampoline for vector {}", vector),
+    };
+    assert_eq!(size_of::<F>(), size_of::<usize>());
+    // Only the address ends up in the IDT
+    unsafe { mem::transmute_copy(&entry) }
+}
+
+/// Called by the trampolines with the saved registers
+///
+/// An attached debugger gets debug and breakpoint traps, then the
+/// monitor gets breakpoints, otherwise they're reported. The other
+/// exceptions go to `interrupts::exception`.
+#[no_mangle]
+extern "C" fn trap_dispatch(context: &mut Context) {
+    if context.vector != DEBUG_VECTOR && context.vector != BREAKPOINT_VECTOR {
+        return interrupts::exception(context);
+    }
+    if gdb::handle_trap(context) {
+        return;
+    }
+    if context.vector == BREAKPOINT_VECTOR && monitor::enter_breakpoint(context) {
+        return;
+    }
+    match context.vector {
+        DEBUG_VECTOR => interrupts::debug_trap(context),
+        _ => interrupts::breakpoint_trap(context),
+    }
+}
@@ -0,0 +1,443 @@
+//! 16550 UART driver
+//!
+//! Drives the four legacy COM ports. Each is probed on first use, with
+//! a scratch register check and a loopback test at `MAX_BAUD`, and set
+//! up with the default `UartConfig`. Every port sits behind its own
+//! lock, so one can carry logs while another talks to a debugger or
+//! test harness.
+
+use core::convert::TryFrom;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::serial_println;
+
+/// Input clock of the UART divided by 16, the fastest baud rate
+pub const MAX_BAUD: u32 = 115_200;
+
+// Register offsets from the port base
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+/// FIFO control on write
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+/// Line control bit giving the data and interrupt enable registers
+/// over to the divisor
+const DIVISOR_LATCH: u8 = 0x80;
+/// Modem control: DTR, RTS and OUT2, which gates the interrupt
+const MODEM_READY: u8 = 0x0b;
+const MODEM_LOOPBACK: u8 = 0x10;
+/// Line status: a byte was received
+const DATA_READY: u8 = 0x01;
+/// Line status: the transmit holding register is empty
+const TRANSMIT_EMPTY: u8 = 0x20;
+/// Line status: nothing is left to send, the shift register included
+const TRANSMITTER_IDLE: u8 = 0x40;
+
+/// Longest frame on the line: start, 8 data, parity and 2 stop bits
+const MAX_FRAME_BITS: u32 = 12;
+/// Sent to the UART itself by `loopback_test`
+const LOOPBACK_BYTE: u8 = 0xae;
+
+/// Line settings of the probe, whatever the firmware left behind
+const PROBE_CONFIG: UartConfig = UartConfig {
+    baud: MAX_BAUD,
+    data_bits: DataBits::Eight,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+    fifo_trigger: Some(FifoTrigger::Bytes14),
+};
+
+/// The legacy COM ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// First I/O port
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    /// PIC line, COM3 and COM4 share it with COM1 and COM2
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always set
+    Mark,
+    /// Parity bit always clear
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// One and a half with five data bits
+    Two,
+}
+
+/// Received bytes that raise the receive interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoTrigger {
+    Bytes1,
+    Bytes4,
+    Bytes8,
+    Bytes14,
+}
+
+/// Line settings of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    /// Must divide `MAX_BAUD`, at least 2 baud
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// `None` turns the FIFOs off
+    pub fifo_trigger: Option<FifoTrigger>,
+}
+
+impl Default for UartConfig {
+    /// 38400 baud 8N1 with 14 byte FIFOs
+    fn default() -> Self {
+        UartConfig {
+            baud: 38_400,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifo_trigger: Some(FifoTrigger::Bytes14),
+        }
+    }
+}
+
+impl UartConfig {
+    /// Divisor of `MAX_BAUD` giving the baud rate
+    fn divisor(&self) -> Option<u16> {
+        if self.baud == 0 || MAX_BAUD % self.baud != 0 {
+            return None;
+        }
+        u16::try_from(MAX_BAUD / self.baud).ok()
+    }
+
+    /// Bits of a byte that make it through the line
+    fn data_mask(&self) -> u8 {
+        match self.data_bits {
+            DataBits::Five => 0x1f,
+            DataBits::Six => 0x3f,
+            DataBits::Seven => 0x7f,
+            DataBits::Eight => 0xff,
+        }
+    }
+
+    fn line_control(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        data_bits | stop_bits | parity << 3
+    }
+
+    fn fifo_control(&self) -> u8 {
+        let trigger = match self.fifo_trigger {
+            Some(FifoTrigger::Bytes1) => 0b00,
+            Some(FifoTrigger::Bytes4) => 0b01,
+            Some(FifoTrigger::Bytes8) => 0b10,
+            Some(FifoTrigger::Bytes14) => 0b11,
+            None => return 0,
+        };
+        // Enable and clear both FIFOs
+        trigger << 6 | 0x07
+    }
+}
+
+impl fmt::Display for UartConfig {
+    /// Like `38400 8N1`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud, data_bits, parity, stop_bits)
+    }
+}
+
+/// Errors of the UART driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// Nothing answered at the port
+    NotPresent(ComPort),
+    /// The baud rate doesn't divide `MAX_BAUD` or is too slow
+    InvalidBaud(u32),
+}
+
+/// A COM port
+pub struct Uart {
+    port: ComPort,
+    present: bool,
+    config: UartConfig,
+}
+
+impl Uart {
+    /// Probes `port` and sets it up with the default config if present
+    fn open(port: ComPort) -> Self {
+        let mut uart = Uart {
+            port,
+            present: false,
+            config: UartConfig::default(),
+        };
+        uart.present = uart.probe();
+        if uart.present {
+            uart.configure(UartConfig::default())
+                .expect("the default UART config is valid");
+        } else {
+            // A failed loopback test leaves the probe's settings
+            uart.config = UartConfig::default();
+        }
+        uart
+    }
+
+    pub fn port(&self) -> ComPort {
+        self.port
+    }
+
+    /// Whether the UART passed its probe
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    pub fn config(&self) -> UartConfig {
+        self.config
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.port.base() + register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.port.base() + register).write(value) }
+    }
+
+    /// Checks the scratch register, then that a byte sent in loopback
+    /// mode comes back at `PROBE_CONFIG`
+    fn probe(&mut self) -> bool {
+        self.write(SCRATCH, 0x5a);
+        if self.read(SCRATCH) != 0x5a {
+            return false;
+        }
+        self.program(PROBE_CONFIG)
+            .expect("the probe UART config is valid");
+        self.loopback_test()
+    }
+
+    /// Line status reads lasting at least `bytes` byte times
+    ///
+    /// A port read takes a microsecond or more on the ISA bus
+    fn poll_budget(&self, bytes: u32) -> u32 {
+        bytes * MAX_FRAME_BITS * 1_000_000 / self.config.baud
+    }
+
+    /// Waits until the line status has one of `bits` set, false if it
+    /// doesn't within `bytes` byte times
+    fn poll_line_status(&self, bits: u8, bytes: u32) -> bool {
+        (0..self.poll_budget(bytes)).any(|_| self.read(LINE_STATUS) & bits != 0)
+    }
+
+    /// Sends a byte to the UART itself and checks it's received
+    ///
+    /// Output still queued is sent first, so it doesn't loop back
+    pub fn loopback_test(&mut self) -> bool {
+        // A full FIFO and the byte being shifted out
+        if !self.poll_line_status(TRANSMITTER_IDLE, 16 + 1) {
+            return false;
+        }
+        let modem_control = self.read(MODEM_CONTROL);
+        let interrupts = self.read(INTERRUPT_ENABLE);
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(MODEM_CONTROL, MODEM_LOOPBACK | MODEM_READY);
+        // Drop what was received before, at most a FIFO full
+        for _ in 0..16 {
+            if self.read(LINE_STATUS) & DATA_READY == 0 {
+                break;
+            }
+            self.read(DATA);
+        }
+        self.write(DATA, LOOPBACK_BYTE);
+
+        // Two byte times leave the UART room to be slow about it
+        let received = if self.poll_line_status(DATA_READY, 2) {
+            Some(self.read(DATA))
+        } else {
+            None
+        };
+        self.write(MODEM_CONTROL, modem_control);
+        self.write(INTERRUPT_ENABLE, interrupts);
+        received == Some(LOOPBACK_BYTE & self.config.data_mask())
+    }
+
+    /// Applies `config`, keeping the enabled interrupts
+    pub fn configure(&mut self, config: UartConfig) -> Result<(), UartError> {
+        if !self.present {
+            return Err(UartError::NotPresent(self.port));
+        }
+        self.program(config)
+    }
+
+    /// Writes `config` to the registers, present or not
+    fn program(&mut self, config: UartConfig) -> Result<(), UartError> {
+        let divisor = config
+            .divisor()
+            .ok_or(UartError::InvalidBaud(config.baud))?;
+        let interrupts = self.read(INTERRUPT_ENABLE);
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, DIVISOR_LATCH);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, config.line_control());
+        self.write(FIFO_CONTROL, config.fifo_control());
+        self.write(MODEM_CONTROL, MODEM_READY);
+        self.write(INTERRUPT_ENABLE, interrupts);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Raises the interrupt when a byte is received, or stops it
+    pub fn set_receive_interrupt(&mut self, enabled: bool) {
+        let interrupts = self.read(INTERRUPT_ENABLE);
+        self.write(INTERRUPT_ENABLE, (interrupts & !1) | enabled as u8);
+    }
+
+    /// Sends `byte` once the transmitter takes it
+    ///
+    /// Nothing is sent to a UART that isn't present
+    pub fn send(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+        while self.read(LINE_STATUS) & TRANSMIT_EMPTY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        self.write(DATA, byte);
+    }
+
+    /// Received byte, if any
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.present && self.read(LINE_STATUS) & DATA_READY != 0 {
+            Some(self.read(DATA))
+        } else {
+            None
+        }
+    }
+
+    /// Waits for a received byte
+    pub fn receive(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_receive() {
+                return byte;
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// The COM ports in `ComPort` order, probed on first use
+    static ref PORTS: [Mutex<Uart>; 4] = [
+        Mutex::new(Uart::open(ComPort::Com1)),
+        Mutex::new(Uart::open(ComPort::Com2)),
+        Mutex::new(Uart::open(ComPort::Com3)),
+        Mutex::new(Uart::open(ComPort::Com4)),
+    ];
+}
+
+/// The UART of `port`, a writer independent of the other ports
+pub fn port(port: ComPort) -> &'static Mutex<Uart> {
+    &PORTS[port as usize]
+}
+
+/// Probes the COM ports and logs the ones found
+///
+/// Called by `init`
+pub fn init() {
+    for com in present_ports() {
+        let config = port(com).lock().config();
+        serial_println!("[uart] {:?} at {:#x}: {}", com, com.base(), config);
+    }
+}
+
+/// Ports whose UART passed the probe
+pub fn present_ports() -> impl Iterator<Item = ComPort> {
+    ComPort::ALL
+        .iter()
+        .copied()
+        .filter(|&com| port(com).lock().is_present())
+}
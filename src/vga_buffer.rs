@@ -103,6 +103,45 @@ impl ScreenWriter {
             }
         }
     }
+
+    /// Column the next character goes to
+    pub fn column(&self) -> usize {
+        self.column_position
+    }
+
+    /// Redraws the current line from `column` on with `text`, blanking
+    /// the rest, and moves to `cursor` within `text`
+    ///
+    /// Text past the end of the line is cut off
+    pub fn rewrite_line(&mut self, column: usize, text: &str, cursor: usize) {
+        let row = BUFFER_HEIGHT - 1;
+        let mut bytes = text.bytes();
+        for col in column.min(BUFFER_WIDTH)..BUFFER_WIDTH {
+            let ascii_char = match bytes.next() {
+                Some(byte @ 0x20..=0x7e) => byte,
+                Some(_) => 0xfe,
+                None => b' ',
+            };
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_char,
+                colour_code: self.colour_code,
+            });
+        }
+        self.column_position = (column + cursor).min(BUFFER_WIDTH);
+    }
+
+    /// Erases the character before the cursor on the current line
+    pub fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+            let blank = ScreenChar {
+                ascii_char: b' ',
+                colour_code: self.colour_code,
+            };
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(blank);
+        }
+    }
+
     /// Shifts row lines up by wrapping the current line
     /// Iterates over all characters, shifting them a row up
     fn new_line(&mut self) {
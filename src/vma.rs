@@ -0,0 +1,426 @@
+//! Kernel virtual address space manager
+//!
+//! Keeps track of the virtual memory areas of the kernel address space:
+//! what each one is used for, its page flags, and which ranges are
+//! still free. Reservations never overlap. Free ranges of the vmalloc
+//! window are handed out on request, each followed by an unmapped page.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB, UnusedPhysFrame,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::paging::{self, PagingError};
+use crate::{allocator, kaslr, memory, serial_println, slab, stack};
+
+/// Size of the window `vmalloc` hands out ranges from
+pub const VMALLOC_SIZE: u64 = 0x_300_0000_0000;
+/// Number of areas that can be tracked
+const MAX_AREAS: usize = 64;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// What an area is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Heap,
+    Stacks,
+    Slabs,
+    /// Backed on first touch
+    Lazy,
+    /// Device memory
+    Mmio,
+    /// Handed out by `vmalloc`
+    Vmalloc,
+    /// Mapped at a fixed address
+    Fixed,
+}
+
+impl Purpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Purpose::Heap => "heap",
+            Purpose::Stacks => "stacks",
+            Purpose::Slabs => "slabs",
+            Purpose::Lazy => "lazy",
+            Purpose::Mmio => "mmio",
+            Purpose::Vmalloc => "vmalloc",
+            Purpose::Fixed => "fixed",
+        }
+    }
+}
+
+/// Reserved range of the kernel address space
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// Exclusive end
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub purpose: Purpose,
+}
+
+impl Vma {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        start < self.end && self.start < end
+    }
+}
+
+impl fmt::Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{:#014x}-{:#014x} {}{}{} {:<8} {}",
+            self.start.as_u64(),
+            self.end.as_u64(),
+            flag(self.flags.contains(PageTableFlags::WRITABLE), 'w'),
+            flag(self.flags.contains(PageTableFlags::USER_ACCESSIBLE), 'u'),
+            flag(!self.flags.contains(PageTableFlags::NO_EXECUTE), 'x'),
+            self.purpose.as_str(),
+            self.name
+        )
+    }
+}
+
+/// Errors of the address space manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// Start or size isn't page aligned, or the size is zero
+    Unaligned,
+    /// The range overlaps a reserved area
+    Overlap,
+    /// No free range of the requested size is left
+    NoSpace,
+    /// The area table is full
+    TableFull,
+    /// No area starts at the given address
+    NotFound,
+}
+
+/// Reserved areas sorted by start address
+struct AreaTable {
+    areas: [Option<Vma>; MAX_AREAS],
+    len: usize,
+}
+
+impl AreaTable {
+    fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas[..self.len].iter().flatten()
+    }
+
+    fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if self.iter().any(|a| a.overlaps(vma.start, vma.end)) {
+            return Err(VmaError::Overlap);
+        }
+        if self.len == MAX_AREAS {
+            return Err(VmaError::TableFull);
+        }
+        let index = self.iter().take_while(|a| a.start < vma.start).count();
+        for i in (index..self.len).rev() {
+            self.areas[i + 1] = self.areas[i];
+        }
+        self.areas[index] = Some(vma);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        let index = self.iter().position(|a| a.start == start)?;
+        let vma = self.areas[index];
+        for i in index..self.len - 1 {
+            self.areas[i] = self.areas[i + 1];
+        }
+        self.len -= 1;
+        self.areas[self.len] = None;
+        vma
+    }
+
+    /// Lowest free range of `size` bytes in `window` starting at a
+    /// multiple of `align` and followed by a free page
+    fn find_free(&self, size: u64, align: u64, window: (u64, u64)) -> Option<VirtAddr> {
+        let mut candidate = align_up(window.0, align);
+        for area in self.iter() {
+            let (start, end) = (area.start.as_u64(), area.end.as_u64());
+            if end <= candidate {
+                continue;
+            }
+            if start >= candidate + size + PAGE_SIZE {
+                break;
+            }
+            candidate = align_up(align_up(end, PAGE_SIZE) + PAGE_SIZE, align);
+        }
+        if candidate + size + PAGE_SIZE <= window.1 {
+            Some(VirtAddr::new(candidate))
+        } else {
+            None
+        }
+    }
+}
+
+/// Changed with interrupts disabled so fault handlers can read it
+static AREAS: Mutex<AreaTable> = Mutex::new(AreaTable {
+    areas: [None; MAX_AREAS],
+    len: 0,
+});
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Reserves `start..start + size` for `purpose`
+pub fn reserve(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    purpose: Purpose,
+) -> Result<(), VmaError> {
+    if size == 0 || start.as_u64() % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+        return Err(VmaError::Unaligned);
+    }
+    let vma = Vma {
+        name,
+        start,
+        end: start + size,
+        flags,
+        purpose,
+    };
+    interrupts::without_interrupts(|| AREAS.lock().insert(vma))
+}
+
+/// Reserves a free range of `size` bytes from the vmalloc window
+///
+/// Nothing is mapped. The page after the range stays unreserved
+/// so neighbouring ranges never touch.
+pub fn allocate_range(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, VmaError> {
+    allocate_range_aligned(name, size, PAGE_SIZE, flags)
+}
+
+/// Like `allocate_range`, but the range starts at a multiple of `align`
+///
+/// `align` must be a power of two of at least a page, e.g. the size of
+/// a huge page
+pub fn allocate_range_aligned(
+    name: &'static str,
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, VmaError> {
+    allocate(name, size, align, flags, Purpose::Vmalloc)
+}
+
+fn allocate(
+    name: &'static str,
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+    purpose: Purpose,
+) -> Result<VirtAddr, VmaError> {
+    if size == 0 || size % PAGE_SIZE != 0 || align < PAGE_SIZE || !align.is_power_of_two() {
+        return Err(VmaError::Unaligned);
+    }
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let start = areas
+            .find_free(size, align, vmalloc_window())
+            .ok_or(VmaError::NoSpace)?;
+        areas.insert(Vma {
+            name,
+            start,
+            end: start + size,
+            flags,
+            purpose,
+        })?;
+        Ok(start)
+    })
+}
+
+/// Start and exclusive end of the vmalloc window
+///
+/// The start is randomised at boot, see `kaslr`
+pub fn vmalloc_window() -> (u64, u64) {
+    let start = kaslr::layout().vmalloc;
+    (start, start + VMALLOC_SIZE)
+}
+
+/// Removes the reservation starting at `start`
+///
+/// Mappings in the area are left alone
+pub fn release(start: VirtAddr) -> Result<Vma, VmaError> {
+    interrupts::without_interrupts(|| AREAS.lock().remove(start)).ok_or(VmaError::NotFound)
+}
+
+/// Area containing `addr`
+///
+/// Never waits for the lock, so it is usable from fault handlers
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    let areas = AREAS.try_lock()?;
+    areas.iter().find(|a| a.contains(addr)).copied()
+}
+
+/// Calls `f` with every reserved area in address order
+pub fn for_each(mut f: impl FnMut(&Vma)) {
+    let areas = AREAS.lock();
+    for area in areas.iter() {
+        f(area);
+    }
+}
+
+/// Reserves a range from the vmalloc window and backs it with fresh frames
+///
+/// The pages are mapped with `flags`, writable and executable ones are
+/// refused
+pub fn vmalloc(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, VmallocError> {
+    let size = align_up(size, PAGE_SIZE);
+    let start = allocate_range(name, size, flags).map_err(VmallocError::Vma)?;
+
+    let first = Page::containing_address(start);
+    let pages = Page::range(first, first + size / PAGE_SIZE);
+    if let Err(err) = paging::map_alloc(pages, flags) {
+        // Nothing stays mapped on failure
+        let _ = release(start);
+        return Err(match err {
+            PagingError::NoKernelMemory => VmallocError::NoKernelMemory,
+            err => VmallocError::Paging(err),
+        });
+    }
+    Ok(start)
+}
+
+/// Unmaps a range returned by `vmalloc` or `ioremap`
+///
+/// The frames of a `vmalloc` range are freed, device memory is only
+/// unmapped.
+///
+/// # Unsafe
+/// ---------
+/// Nothing may use the memory anymore
+/// ----------
+pub unsafe fn vfree(start: VirtAddr) -> Result<(), VmaError> {
+    let vma = find(start)
+        .filter(|vma| match vma.purpose {
+            Purpose::Vmalloc => vma.start == start,
+            // `ioremap` hands out the address inside the first page
+            Purpose::Mmio => start.as_u64() - vma.start.as_u64() < PAGE_SIZE,
+            _ => false,
+        })
+        .ok_or(VmaError::NotFound)?;
+    {
+        let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+        let kernel_memory = kernel_memory
+            .as_mut()
+            .expect("vmalloc range without kernel memory");
+
+        let first = Page::<Size4KiB>::containing_address(vma.start);
+        let last = Page::containing_address(vma.end - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((frame, flush)) = kernel_memory.mapper.unmap(page) {
+                flush.flush();
+                if vma.purpose == Purpose::Vmalloc {
+                    kernel_memory
+                        .frame_allocator
+                        .deallocate_frame(UnusedPhysFrame::new(frame));
+                }
+            }
+        }
+    }
+    release(vma.start).map(|_| ())
+}
+
+/// Maps the `size` bytes of device memory at `phys` into the vmalloc
+/// window
+///
+/// The pages are uncached and never executable. Returns the address
+/// `phys` is mapped at, `vfree` unmaps it again.
+///
+/// # Unsafe
+/// ---------
+/// The memory must be device memory, or at least not in use in a way
+/// conflicting with an uncached alias
+/// ----------
+pub unsafe fn ioremap(
+    name: &'static str,
+    phys: PhysAddr,
+    size: u64,
+) -> Result<VirtAddr, VmallocError> {
+    let offset = phys.as_u64() % PAGE_SIZE;
+    let size = align_up(size + offset, PAGE_SIZE);
+    let flags = paging::data_flags() | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let start = allocate(name, size, PAGE_SIZE, flags, Purpose::Mmio).map_err(VmallocError::Vma)?;
+
+    let first = Page::containing_address(start);
+    let pages = Page::range(first, first + size / PAGE_SIZE);
+    let mapped = paging::map_physical(pages, PhysAddr::new(phys.as_u64() - offset), flags);
+    if let Err(err) = mapped {
+        let _ = release(start);
+        return Err(VmallocError::Paging(err));
+    }
+    Ok(start + offset)
+}
+
+/// Errors of `vmalloc` and `ioremap`
+#[derive(Debug)]
+pub enum VmallocError {
+    Vma(VmaError),
+    Paging(PagingError),
+    /// `memory::install` hasn't been called
+    NoKernelMemory,
+}
+
+/// Reserves the fixed regions of the kernel address space
+///
+/// Called by `memory::install`
+pub(crate) fn reserve_kernel_regions() {
+    let data = paging::data_flags();
+    let regions = [
+        (
+            "kernel heap",
+            allocator::heap_start() as u64,
+            allocator::HEAP_MAX_SIZE as u64,
+            Purpose::Heap,
+        ),
+        (
+            "kernel stacks",
+            stack::stacks_start(),
+            stack::STACKS_SIZE,
+            Purpose::Stacks,
+        ),
+        (
+            "slab caches",
+            slab::slab_start(),
+            slab::SLAB_REGION_SIZE,
+            Purpose::Slabs,
+        ),
+    ];
+    for &(name, start, size, purpose) in regions.iter() {
+        reserve(name, VirtAddr::new(start), size, data, purpose)
+            .expect("Fixed kernel regions overlap");
+    }
+}
+
+/// Prints the current layout over serial
+pub fn dump() {
+    serial_println!("[vma] kernel address space:");
+    for_each(|area| serial_println!("[vma] {}", area));
+}
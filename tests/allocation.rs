@@ -8,28 +8,31 @@
 extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
-use core::{panic, PanicInfo};
+use core::panic::PanicInfo;
 
-use alloc::boxed::Box;
-use x86_kernel::{allocator::HEAP_SIZE, serial_print, serial_println};
+use alloc::{boxed::Box, vec::Vec};
+use x86_kernel::{
+    allocator::{self, HEAP_SIZE},
+    serial_println,
+};
 
 entry_point!(main);
 
 /// Heap test entry point
-fn main(_boot_info: &'static BootInfo) -> ! {
-    use x86_kernel::{
-        allocator,
-        memory::{self, BootInfoFrameAllocator},
-    };
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::memory::{self, BitmapFrameAllocator};
 
     use x86_64::VirtAddr;
 
     x86_kernel::init();
+    serial_println!("Heap allocator: {}", allocator::ALLOCATOR_NAME);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
     test_main();
     loop {}
 }
@@ -44,9 +47,9 @@ fn panic(info: &PanicInfo) -> ! {
 #[test_case]
 fn test_allocation() -> () {
     serial_println!("Allocation test...");
-    let value = Box::new(54);
+    let heap_value = Box::new(54);
     assert_eq!(*heap_value, 54);
-    serial_println("[ok]");
+    serial_println!("[ok]");
 }
 
 /// Tests multiple heap allocations
@@ -72,3 +75,82 @@ fn test_allocator_free_memory() {
     }
     serial_println!("[ok]");
 }
+
+/// Allocations larger than every size class still succeed
+#[test_case]
+fn test_large_allocation() {
+    serial_println!("Large allocation...");
+    let large = Vec::<u8>::with_capacity(8 * 1024);
+    assert!(large.capacity() >= 8 * 1024);
+    serial_println!("[ok]");
+}
+
+/// Strictly aligned layouts are honoured
+#[test_case]
+fn test_aligned_allocation() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    serial_println!("Aligned allocation...");
+    for &align in &[8, 64, 4096] {
+        let layout = Layout::from_size_align(24, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        unsafe { dealloc(ptr, layout) };
+    }
+    serial_println!("[ok]");
+}
+
+/// The heap maps more pages when an allocation exceeds its size
+#[test_case]
+fn test_heap_growth() {
+    serial_println!("Heap growth...");
+    let size = 4 * HEAP_SIZE;
+    let mut buffer = Vec::<u8>::with_capacity(size);
+    buffer.resize(size, 0xaa);
+
+    assert!(allocator::heap_size() > HEAP_SIZE);
+    assert!(allocator::heap_size() <= allocator::heap_limit());
+    assert!(buffer.iter().all(|&b| b == 0xaa));
+    serial_println!("[ok]");
+}
+
+/// Statistics follow allocations and frees
+#[test_case]
+fn test_heap_stats() {
+    serial_println!("Heap statistics...");
+    let before = allocator::stats();
+    let value = Box::new([0u8; 100]);
+    let during = allocator::stats();
+
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.bytes_allocated >= before.bytes_allocated + 100);
+    assert!(during.peak_bytes_allocated >= during.bytes_allocated);
+    drop(value);
+
+    let after = allocator::stats();
+    assert_eq!(after.bytes_allocated, before.bytes_allocated);
+    assert_eq!(after.live_allocations(), before.live_allocations());
+    assert_eq!(after.mapped_pages() * 4096, after.heap_size);
+    serial_println!("[ok]");
+}
+
+/// Scopes that free everything they allocate pass the leak check
+#[cfg(feature = "leak-tracking")]
+#[test_case]
+fn test_no_leaks() {
+    use x86_kernel::leak;
+
+    serial_println!("Leak tracking...");
+    leak::assert_no_leaks(|| {
+        let values: Vec<u64> = (0..64).collect();
+        assert_eq!(values.len(), 64);
+    });
+
+    let mark = leak::mark();
+    let kept = Box::new(5);
+    assert_eq!(leak::leaks_since(mark), 1);
+    drop(kept);
+    assert_eq!(leak::leaks_since(mark), 0);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,84 @@
+//! APIC interrupt controller tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_kernel::{acpi, apic, interrupts, serial_println};
+
+entry_point!(main);
+
+/// APIC test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Waits for `count` more timer interrupts
+fn wait_ticks(count: u64) {
+    let target = interrupts::timer_ticks() + count;
+    while interrupts::timer_ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// The timer ticks through the PICs before the APIC takes over
+#[test_case]
+fn test_pic_timer() {
+    serial_println!("Timer through the PICs...");
+    assert!(!apic::is_enabled());
+    wait_ticks(2);
+    serial_println!("[ok]");
+}
+
+/// QEMU describes its I/O APIC in the MADT
+#[test_case]
+fn test_madt() {
+    serial_println!("MADT...");
+    let madt = acpi::madt().expect("no MADT");
+    let io_apic = madt.io_apics[0].expect("no I/O APIC in the MADT");
+    assert_eq!(io_apic.addr, PhysAddr::new(0xfec0_0000));
+    assert!(madt.local_apic.as_u64() != 0);
+    serial_println!("[ok]");
+}
+
+/// Timer interrupts keep arriving, and being acknowledged, through the APIC
+#[test_case]
+fn test_apic_timer() {
+    serial_println!("Timer through the APIC...");
+    if !apic::supported() {
+        serial_println!("No APIC, skipped");
+        serial_println!("[ok]");
+        return;
+    }
+    let info = apic::init().expect("APIC initialization failed");
+    assert!(apic::is_enabled());
+    assert!(info.from_madt);
+    assert_eq!(info.lapic_id, apic::lapic_id());
+
+    // More than one tick means every EOI reached the local APIC
+    wait_ticks(3);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,115 @@
+//! Register snapshot and backtrace tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use x86_64::VirtAddr;
+use x86_kernel::backtrace::{self, Backtrace};
+use x86_kernel::{kernel_image, serial_println};
+
+entry_point!(main);
+
+/// Backtrace test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Whether `addr` is in an executable segment of the kernel
+fn in_kernel_code(addr: u64) -> bool {
+    kernel_image::segment(VirtAddr::new(addr)).map_or(false, |s| s.executable)
+}
+
+/// Backtrace taken `depth` calls of itself deep
+#[inline(never)]
+fn nested(depth: usize) -> Backtrace {
+    if depth == 0 {
+        return Backtrace::capture();
+    }
+    let backtrace = nested(depth - 1);
+    // Keeps the recursive call from becoming a jump
+    compiler_fence(Ordering::SeqCst);
+    backtrace
+}
+
+/// The snapshot describes the calling function
+#[test_case]
+fn test_capture_registers() {
+    serial_println!("Register snapshot...");
+    let local = 0u64;
+    let registers = backtrace::capture_registers();
+    serial_println!("{}", registers);
+
+    let local_addr = &local as *const u64 as u64;
+    assert!(registers.rsp <= local_addr && local_addr < registers.rbp + 16);
+    assert!(in_kernel_code(registers.rip));
+    // RFLAGS bit 1 is always set
+    assert!(registers.rflags & 0b10 != 0);
+    serial_println!("[ok]");
+}
+
+/// Each recursive call shows up as a frame returning to the same place
+#[test_case]
+fn test_backtrace() {
+    serial_println!("Frame pointer backtrace...");
+    let backtrace = nested(3);
+    serial_println!("{}", backtrace);
+
+    let frames = backtrace.frames();
+    assert!(frames.len() > 4);
+    assert!(frames.iter().take(4).all(|&frame| in_kernel_code(frame)));
+    // Returns into `nested` from the three inner calls
+    assert_eq!(frames[0], frames[1]);
+    assert_eq!(frames[1], frames[2]);
+    assert_ne!(frames[2], frames[3]);
+    serial_println!("[ok]");
+}
+
+/// Frames resolve to the function they return into
+#[test_case]
+fn test_symbols() {
+    use x86_kernel::symbols;
+
+    serial_println!("Symbolicated backtrace...");
+    assert!(symbols::is_embedded());
+    let backtrace = nested(1);
+    let symbol = symbols::resolve(backtrace.frames()[0]).expect("frame not resolved");
+    assert_eq!(symbol.name, "backtrace::nested");
+    assert!(symbol.offset > 0);
+    assert_eq!(symbol.start, nested as usize as u64);
+    assert_eq!(symbols::resolve(0x1234), None);
+    serial_println!("[ok]");
+}
+
+/// A broken chain ends the backtrace instead of faulting
+#[test_case]
+fn test_broken_chain() {
+    serial_println!("Unmapped frame pointer...");
+    let backtrace = Backtrace::from_frame(Some(0x1234), 0xdead_0000);
+    assert_eq!(backtrace.frames(), &[0x1234]);
+    assert_eq!(backtrace::saved_frame_pointer(0xdead_0000), 0);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,91 @@
+//! Buddy allocator tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Size2MiB};
+use x86_64::VirtAddr;
+use x86_kernel::{
+    buddy::{self, BuddyFrameAllocator},
+    serial_println,
+};
+
+entry_point!(main);
+
+/// Allocator shared by the test cases
+static BUDDY: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+/// Buddy allocator test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *BUDDY.lock() = Some(allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Blocks are aligned to their size
+#[test_case]
+fn test_block_alignment() {
+    serial_println!("Buddy block alignment...");
+    let mut guard = BUDDY.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    for order in 0..=4 {
+        let block = allocator.allocate(order).expect("no block");
+        assert_eq!(block.as_u64() % buddy::block_size(order), 0);
+        unsafe { allocator.deallocate(block, order) };
+    }
+    serial_println!("[ok]");
+}
+
+/// Freeing both halves of a split block merges them back
+#[test_case]
+fn test_buddies_merge() {
+    serial_println!("Buddy merging...");
+    let mut guard = BUDDY.lock();
+    let allocator = guard.as_mut().unwrap();
+    let before = allocator.stats();
+
+    let first = allocator.allocate(0).expect("no block");
+    let second = allocator.allocate(0).expect("no block");
+
+    unsafe {
+        allocator.deallocate(first, 0);
+        allocator.deallocate(second, 0);
+    }
+    let after = allocator.stats();
+    assert_eq!(after.free_frames, before.free_frames);
+    assert_eq!(after.free_blocks, before.free_blocks);
+    serial_println!("[ok]");
+}
+
+/// 2MiB frames come out of the buddy allocator
+#[test_case]
+fn test_huge_frame_allocation() {
+    serial_println!("2MiB frame allocation...");
+    let mut guard = BUDDY.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let frame: Option<_> = FrameAllocator::<Size2MiB>::allocate_frame(allocator);
+    let frame = frame.expect("no 2MiB frame");
+    assert_eq!(frame.start_address().as_u64() % buddy::block_size(buddy::ORDER_2MIB), 0);
+    assert!(allocator.stats().fragmentation(buddy::ORDER_2MIB) <= 100);
+    FrameDeallocator::<Size2MiB>::deallocate_frame(allocator, frame);
+    serial_println!("[ok]");
+}
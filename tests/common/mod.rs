@@ -0,0 +1,44 @@
+//! Helpers shared by the integration tests
+
+use core::task::{RawWaker, RawWakerVTable, Waker};
+
+use x86_64::instructions::port::Port;
+use x86_kernel::serial::{self, COM1};
+
+/// Sends `bytes` to COM1 itself
+///
+/// Nothing reaches the host while the UART loops back, so nothing may
+/// print in between
+pub fn loop_back(bytes: &[u8]) {
+    let lock = serial::SERIAL_A.lock();
+    let mut modem_control = Port::<u8>::new(COM1 + 4);
+    let mut line_status = Port::<u8>::new(COM1 + 5);
+    let mut data = Port::<u8>::new(COM1);
+    unsafe {
+        let saved = modem_control.read();
+        modem_control.write(saved | 0x10);
+        for &byte in bytes {
+            // Transmitter holding register empty
+            while line_status.read() & 0x20 == 0 {}
+            data.write(byte);
+        }
+        // Transmitter empty, the last byte is received
+        while line_status.read() & 0x40 == 0 {}
+        modem_control.write(saved);
+    }
+    drop(lock);
+}
+
+fn noop_raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+/// Waker that does nothing, for polling by hand
+pub fn noop_waker() -> Waker {
+    unsafe { Waker::from_raw(noop_raw_waker()) }
+}
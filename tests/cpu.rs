@@ -0,0 +1,63 @@
+//! CPU hardening tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::rflags::{self, RFlags};
+use x86_kernel::{cpu, serial_println};
+
+entry_point!(main);
+
+/// CPU hardening test entry point
+fn main(_boot_info: &'static BootInfo) -> ! {
+    x86_kernel::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Every supported protection is on after `init`
+#[test_case]
+fn test_supported_protections_enabled() {
+    serial_println!("Supported protections enabled...");
+    let enabled = cpu::enabled();
+    assert_eq!(enabled, cpu::supported());
+
+    let cr4 = cpu::read_cr4();
+    assert_eq!(cr4 & (1 << 20) != 0, enabled.smep);
+    assert_eq!(cr4 & (1 << 21) != 0, enabled.smap);
+    assert_eq!(cr4 & (1 << 11) != 0, enabled.umip);
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+    serial_println!("[ok]");
+}
+
+/// User access is only allowed inside `with_user_access`
+#[test_case]
+fn test_with_user_access() {
+    serial_println!("User access window...");
+    let access_allowed = || rflags::read().contains(RFlags::ALIGNMENT_CHECK);
+    assert!(!access_allowed());
+
+    let smap = cpu::enabled().smap;
+    let value = cpu::with_user_access(|| {
+        assert_eq!(access_allowed(), smap);
+        // Nested windows don't close the outer one
+        cpu::with_user_access(|| ());
+        assert_eq!(access_allowed(), smap);
+        42
+    });
+    assert_eq!(value, 42);
+    assert!(!access_allowed());
+    serial_println!("[ok]");
+}
@@ -0,0 +1,70 @@
+//! Crash report tests
+//!
+//! Loads a selector past the end of the GDT. The #GP handler has to
+//! report it and panic with the decoded selector.
+#![feature(asm)]
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use x86_kernel::{exit_qemu, serial_println, QemuExitCode};
+
+/// Selector of GDT entry 0x246, far past the end of the GDT
+const BAD_SELECTOR: u16 = 0x1230;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_println!("General protection fault report...");
+    x86_kernel::init();
+
+    unsafe { asm!("mov $0, %ds" :: "r"(BAD_SELECTOR) :: "volatile") };
+
+    serial_println!("[oops!]\nExecution continued after the #GP");
+    exit_qemu(QemuExitCode::Failure);
+    loop {}
+}
+
+/// Keeps the start of the panic message
+struct Message {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Message {
+    fn contains(&self, needle: &str) -> bool {
+        self.buf[..self.len]
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    }
+}
+
+/// Passes when the panic came from the #GP handler
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        buf: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    if message.contains("#GP") && message.contains("Gdt index 0x246") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        x86_kernel::test_panic_handler(info);
+    }
+    loop {}
+}
@@ -0,0 +1,93 @@
+//! Demand paging tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use x86_kernel::{
+    demand_paging::{self, RegionError},
+    memory, serial_println,
+};
+
+entry_point!(main);
+
+/// Start of the lazy region used by the tests
+const LAZY_START: u64 = 0x_7777_0000_0000;
+const LAZY_SIZE: u64 = 16 * 4096;
+
+/// Demand paging test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    demand_paging::register_lazy_region("test", VirtAddr::new(LAZY_START), LAZY_SIZE, flags)
+        .expect("Failed to register lazy region");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Touching a lazy page maps a zeroed frame
+#[test_case]
+fn test_first_touch() {
+    serial_println!("Demand paging first touch...");
+    let used_before = memory::stats().used_frames;
+
+    let ptr = LAZY_START as *mut u64;
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(memory::stats().used_frames > used_before);
+
+    // Pages are only backed once
+    let used_after = memory::stats().used_frames;
+    unsafe { ptr.add(1).write_volatile(7) };
+    assert_eq!(memory::stats().used_frames, used_after);
+    serial_println!("[ok]");
+}
+
+/// Overlapping or unaligned regions are refused
+#[test_case]
+fn test_region_validation() {
+    serial_println!("Lazy region validation...");
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let overlap = VirtAddr::new(LAZY_START + 4096);
+    assert_eq!(
+        demand_paging::register_lazy_region("overlap", overlap, 4096, flags),
+        Err(RegionError::Overlap)
+    );
+    let unaligned = VirtAddr::new(LAZY_START + LAZY_SIZE + 1);
+    assert_eq!(
+        demand_paging::register_lazy_region("unaligned", unaligned, 4096, flags),
+        Err(RegionError::Unaligned)
+    );
+    let free = VirtAddr::new(LAZY_START + 2 * LAZY_SIZE);
+    assert_eq!(
+        demand_paging::register_lazy_region("wx", free, 4096, PageTableFlags::WRITABLE),
+        Err(RegionError::WritableExecutable)
+    );
+    serial_println!("[ok]");
+}
@@ -0,0 +1,76 @@
+//! Physical frame allocation tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use x86_64::VirtAddr;
+use x86_kernel::{memory::BitmapFrameAllocator, serial_println};
+
+entry_point!(main);
+
+/// Allocator shared by the test cases
+static FRAMES: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// Frame allocation test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAMES.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Allocated frames are distinct and update the counters
+#[test_case]
+fn test_allocate_frames() {
+    serial_println!("Frame allocation...");
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let free_before = frames.free_frames();
+    let first = frames.allocate_frame().expect("no frame");
+    let second = frames.allocate_frame().expect("no frame");
+
+    assert_ne!(first.start_address(), second.start_address());
+    assert_eq!(frames.free_frames(), free_before - 2);
+    assert_eq!(frames.used_frames() + frames.free_frames(), frames.total_frames());
+
+    frames.deallocate_frame(first);
+    frames.deallocate_frame(second);
+    assert_eq!(frames.free_frames(), free_before);
+    serial_println!("[ok]");
+}
+
+/// A freed frame is handed out again
+#[test_case]
+fn test_reuse_freed_frame() {
+    serial_println!("Frame reuse...");
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let frame = frames.allocate_frame().expect("no frame");
+    let addr = frame.start_address();
+    frames.deallocate_frame(frame);
+
+    let again = frames.allocate_frame().expect("no frame");
+    assert_eq!(again.start_address(), addr);
+    frames.deallocate_frame(again);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,164 @@
+//! GDB stub tests
+//!
+//! The debugger is a script of packets fed to the stub through a
+//! `Connection`, its replies are collected and checked afterwards
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{boxed::Box, format};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{compiler_fence, Ordering};
+use spin::Mutex;
+
+use x86_64::VirtAddr;
+use x86_kernel::gdb::{self, Connection};
+use x86_kernel::serial_println;
+
+entry_point!(main);
+
+/// GDB stub test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Bytes the debugger sends
+static INPUT: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+/// Bytes the stub sent
+static OUTPUT: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// Debugger playing back `INPUT`
+struct Script;
+
+impl Connection for Script {
+    fn read_byte(&mut self) -> u8 {
+        let mut input = INPUT.lock();
+        assert!(!input.is_empty(), "stub read past the script");
+        input.remove(0)
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        OUTPUT.lock().push(byte);
+    }
+}
+
+/// Queues the packet `data` and the ack of the stub's reply to it
+///
+/// `c` and `s` are answered by the stop reply once the kernel stops again
+fn send(data: &str) {
+    let framed = format!("${}#{:02x}+", data, gdb::checksum(data.as_bytes()));
+    INPUT.lock().extend(framed.bytes());
+}
+
+/// Packets the stub sent, without framing
+fn replies() -> Vec<String> {
+    let output = core::mem::replace(&mut *OUTPUT.lock(), Vec::new());
+    let output = String::from_utf8(output).expect("binary reply");
+    output
+        .split('$')
+        .skip(1)
+        .map(|packet| {
+            let (data, sum) = packet.split_at(packet.find('#').expect("unterminated reply"));
+            let sum = u8::from_str_radix(&sum[1..3], 16).unwrap();
+            assert_eq!(sum, gdb::checksum(data.as_bytes()));
+            String::from(data)
+        })
+        .collect()
+}
+
+static mut WATCHED: u32 = 0x1234_5678;
+
+/// Where the test sets its breakpoint
+#[inline(never)]
+fn target() -> u64 {
+    compiler_fence(Ordering::SeqCst);
+    42
+}
+
+/// Packets are acknowledged and answered with the checksum
+#[test_case]
+fn test_packets() {
+    serial_println!("Stub packets...");
+    let watched = unsafe { &WATCHED as *const u32 as u64 };
+    send("qSupported:swbreak+");
+    send("?");
+    send("g");
+    send(&format!("m{:x},4", watched));
+    send(&format!("M{:x},2:cdab", watched));
+    send("vMustReplyEmpty");
+    send("D");
+
+    gdb::attach(Box::new(Script));
+    gdb::breakpoint();
+
+    let replies = replies();
+    assert_eq!(replies[0], "PacketSize=400;swbreak+");
+    assert_eq!(replies[1], "S05");
+    // 17 64 bit and 7 32 bit registers
+    assert_eq!(replies[2].len(), (17 * 8 + 7 * 4) * 2);
+    assert_eq!(replies[3], "78563412");
+    assert_eq!(replies[4], "OK");
+    assert_eq!(replies[5], "");
+    assert_eq!(replies[6], "OK");
+    assert!(!gdb::is_attached());
+    assert_eq!(unsafe { core::ptr::read_volatile(&WATCHED) }, 0x1234_abcd);
+    assert!(INPUT.lock().is_empty());
+    serial_println!("[ok]");
+}
+
+/// A software breakpoint stops, single steps stop after one instruction
+#[test_case]
+fn test_breakpoint_and_step() {
+    serial_println!("Stub breakpoint and step...");
+    let addr = target as fn() -> u64 as u64;
+    send(&format!("Z0,{:x},1", addr));
+    send(&format!("m{:x},1", addr));
+    send("c");
+    // Stopped at the breakpoint
+    send(&format!("z0,{:x},1", addr));
+    send("s");
+    // Stopped after the step
+    send("D");
+
+    gdb::attach(Box::new(Script));
+    gdb::breakpoint();
+    assert_eq!(target(), 42);
+
+    let replies = replies();
+    let original = format!("{:02x}", unsafe { *(addr as *const u8) });
+    assert_eq!(replies[0], "OK");
+    // The int3 is hidden from memory reads
+    assert_eq!(replies[1], original);
+    assert_eq!(replies[2], "T05swbreak:;");
+    assert_eq!(replies[3], "OK");
+    assert_eq!(replies[4], "S05");
+    assert_eq!(replies[5], "OK");
+    assert!(!gdb::is_attached());
+    assert!(INPUT.lock().is_empty());
+    serial_println!("[ok]");
+}
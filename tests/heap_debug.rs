@@ -0,0 +1,175 @@
+//! Heap debugging layer tests
+//!
+//! Every case corrupts the heap of its own `DebugAlloc` in a different
+//! way. Detection panics, so the panic handler checks the report and
+//! starts the next case.
+#![no_std]
+#![no_main]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_kernel::heap_debug::{DebugAlloc, QUARANTINE_SIZE};
+use x86_kernel::{exit_qemu, serial_println, QemuExitCode};
+
+/// Bytes of the arena the cases allocate from
+const ARENA_SIZE: usize = 64 * 1024;
+
+/// Allocator handing out an arena front to back, frees are ignored
+struct Bump {
+    arena: UnsafeCell<[u8; ARENA_SIZE]>,
+    next: AtomicUsize,
+}
+
+// Only moved forward through the atomic
+unsafe impl Sync for Bump {}
+
+unsafe impl GlobalAlloc for Bump {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.arena.get() as usize;
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let start = (base + next + layout.align() - 1) & !(layout.align() - 1);
+            let end = start - base + layout.size();
+            if end > ARENA_SIZE {
+                return core::ptr::null_mut();
+            }
+            match self
+                .next
+                .compare_exchange(next, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return start as *mut u8,
+                Err(current) => next = current,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+static HEAP: DebugAlloc<Bump> = DebugAlloc::new(Bump {
+    arena: UnsafeCell::new([0; ARENA_SIZE]),
+    next: AtomicUsize::new(0),
+});
+
+/// Zeroed memory that was never allocated
+static NOT_ALLOCATED: [u64; 16] = [0; 16];
+
+/// Report expected from each case and the case itself
+const CASES: [(&str, fn()); 4] = [
+    ("heap overflow", overflow),
+    ("double free", double_free),
+    ("invalid free", invalid_free),
+    ("write after free", write_after_free),
+];
+
+/// Case running now
+static CASE: AtomicUsize = AtomicUsize::new(0);
+
+fn layout() -> Layout {
+    Layout::from_size_align(32, 8).unwrap()
+}
+
+/// Writes a byte past the end of an allocation
+fn overflow() {
+    unsafe {
+        let ptr = HEAP.alloc(layout());
+        ptr.add(layout().size()).write(0);
+        HEAP.dealloc(ptr, layout());
+    }
+}
+
+fn double_free() {
+    unsafe {
+        let ptr = HEAP.alloc(layout());
+        HEAP.dealloc(ptr, layout());
+        HEAP.dealloc(ptr, layout());
+    }
+}
+
+fn invalid_free() {
+    unsafe {
+        let ptr = NOT_ALLOCATED.as_ptr().add(8) as *mut u8;
+        HEAP.dealloc(ptr, layout());
+    }
+}
+
+/// Writes to a freed block, then frees until it leaves the quarantine
+fn write_after_free() {
+    unsafe {
+        let ptr = HEAP.alloc(layout());
+        HEAP.dealloc(ptr, layout());
+        ptr.write(0);
+        for _ in 0..QUARANTINE_SIZE {
+            let other = HEAP.alloc(layout());
+            HEAP.dealloc(other, layout());
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    run_cases()
+}
+
+/// Runs the cases from `CASE` on, each has to panic
+fn run_cases() -> ! {
+    let case = CASE.load(Ordering::SeqCst);
+    if let Some((expected, run)) = CASES.get(case) {
+        serial_println!("Heap debug {}...", expected);
+        run();
+        serial_println!("[oops!]\nThe {} wasn't detected", expected);
+        exit_qemu(QemuExitCode::Failure);
+    } else {
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
+
+/// Keeps the start of the panic message
+struct Message {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Message {
+    fn contains(&self, needle: &str) -> bool {
+        self.buf[..self.len]
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    }
+}
+
+/// Passes the case when its corruption was reported
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        buf: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let case = CASE.load(Ordering::SeqCst);
+    match CASES.get(case) {
+        Some((expected, _)) if message.contains(expected) => {
+            serial_println!("[ok]");
+            CASE.store(case + 1, Ordering::SeqCst);
+            run_cases()
+        }
+        _ => x86_kernel::test_panic_handler(info),
+    }
+}
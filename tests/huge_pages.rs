@@ -0,0 +1,163 @@
+//! Huge page mapping and translation tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_kernel::{memory, paging, serial_println, vma};
+
+entry_point!(main);
+
+/// Huge page test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Free range of the vmalloc window starting at a multiple of `align`
+fn free_range(size: u64, align: u64) -> VirtAddr {
+    vma::allocate_range_aligned("huge page test", size, align, paging::data_flags()).unwrap()
+}
+
+/// Both translation paths agree on `addr`
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let walked = unsafe { memory::translate_virt_addr(addr, memory::physical_memory_offset()) };
+    assert_eq!(walked, paging::translate(addr));
+    walked
+}
+
+/// Addresses inside a 2MiB page translate to the matching offset
+#[test_case]
+fn test_translate_2mib_page() {
+    serial_println!("Translate inside a 2MiB page...");
+    let start = free_range(Size2MiB::SIZE, Size2MiB::SIZE);
+
+    unsafe {
+        paging::map_physical_region(
+            start,
+            PhysAddr::new(0),
+            Size2MiB::SIZE,
+            paging::data_flags(),
+        )
+    }
+    .unwrap();
+    let mapping = paging::query(Page::containing_address(start + 0xb8000u64)).unwrap();
+    assert_eq!(mapping.page_size, Size2MiB::SIZE);
+    assert!(mapping.flags.contains(PageTableFlags::HUGE_PAGE));
+    assert_eq!(mapping.frame.start_address(), PhysAddr::new(0xb8000));
+
+    assert_eq!(translate(start), Some(PhysAddr::new(0)));
+    assert_eq!(translate(start + 0xb8123u64), Some(PhysAddr::new(0xb8123)));
+    assert_eq!(
+        translate(start + 0x1f_ffffu64),
+        Some(PhysAddr::new(0x1f_ffff))
+    );
+
+    // The huge page aliases the physical memory window
+    let alias = unsafe { *(start + 0xb8000u64).as_ptr::<u8>() };
+    let window = unsafe { *memory::phys_to_virt(PhysAddr::new(0xb8000)).as_ptr::<u8>() };
+    assert_eq!(alias, window);
+
+    unsafe { paging::unmap_region(start, Size2MiB::SIZE).unwrap() };
+    assert_eq!(translate(start + 0xb8123u64), None);
+    serial_println!("[ok]");
+}
+
+/// Unaligned edges of a region fall back to 4KiB pages
+#[test_case]
+fn test_mixed_page_sizes() {
+    serial_println!("Mixed page sizes...");
+    let base = free_range(3 * Size2MiB::SIZE, Size2MiB::SIZE);
+    let start = base + (Size2MiB::SIZE - Size4KiB::SIZE);
+    let phys = PhysAddr::new(Size2MiB::SIZE - Size4KiB::SIZE);
+    let size = Size2MiB::SIZE + 2 * Size4KiB::SIZE;
+
+    unsafe { paging::map_physical_region(start, phys, size, paging::data_flags()).unwrap() };
+    let page_size = |addr: VirtAddr| {
+        paging::query(Page::containing_address(addr))
+            .unwrap()
+            .page_size
+    };
+    assert_eq!(page_size(start), Size4KiB::SIZE);
+    assert_eq!(
+        page_size(start + Size4KiB::SIZE + 0x1234u64),
+        Size2MiB::SIZE
+    );
+    assert_eq!(page_size(start + size - 1u64), Size4KiB::SIZE);
+
+    for &offset in [0, 0x10, Size4KiB::SIZE + 0x1234, size - 1].iter() {
+        assert_eq!(translate(start + offset), Some(phys + offset));
+    }
+
+    unsafe { paging::unmap_region(start, size).unwrap() };
+    assert_eq!(translate(start + Size4KiB::SIZE), None);
+    serial_println!("[ok]");
+}
+
+/// Addresses inside a 1GiB page translate to the matching offset
+#[test_case]
+fn test_translate_1gib_page() {
+    serial_println!("Translate inside a 1GiB page...");
+    if !paging::gigabyte_pages_supported() {
+        serial_println!("1GiB pages not supported by the CPU, skipped");
+        serial_println!("[ok]");
+        return;
+    }
+    let start = free_range(Size1GiB::SIZE, Size1GiB::SIZE);
+
+    unsafe {
+        paging::map_physical_region(
+            start,
+            PhysAddr::new(0),
+            Size1GiB::SIZE,
+            paging::data_flags(),
+        )
+    }
+    .unwrap();
+    let mapping = paging::query(Page::containing_address(start + 0x4321_0000u64)).unwrap();
+    assert_eq!(mapping.page_size, Size1GiB::SIZE);
+    assert_eq!(
+        translate(start + 0x2345_6789u64),
+        Some(PhysAddr::new(0x2345_6789))
+    );
+
+    unsafe { paging::unmap_region(start, Size1GiB::SIZE).unwrap() };
+    assert_eq!(translate(start), None);
+    serial_println!("[ok]");
+}
+
+/// Regular pages still report 4KiB
+#[test_case]
+fn test_query_4kib_page() {
+    serial_println!("Query a 4KiB page...");
+    let start = free_range(Size4KiB::SIZE, Size4KiB::SIZE);
+    let page = Page::containing_address(start);
+
+    paging::map_alloc(Page::range(page, page + 1), paging::data_flags()).unwrap();
+    assert_eq!(paging::query(page).unwrap().page_size, Size4KiB::SIZE);
+    unsafe { paging::unmap(Page::range(page, page + 1)).unwrap() };
+    serial_println!("[ok]");
+}
@@ -0,0 +1,199 @@
+//! IRQ registration and exception report tests
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_kernel::interrupts::{self, IrqError, IrqHandler, IrqResult, MAX_SHARED_HANDLERS};
+use x86_kernel::serial_println;
+
+entry_point!(main);
+
+/// IRQ test entry point
+fn main(_boot_info: &'static BootInfo) -> ! {
+    x86_kernel::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Line without a device in QEMU
+const FREE_LINE: u8 = 5;
+
+static CLAIMED: AtomicU64 = AtomicU64::new(0);
+static PASSED: AtomicU64 = AtomicU64::new(0);
+
+fn claiming_handler(_line: u8) -> IrqResult {
+    CLAIMED.fetch_add(1, Ordering::Relaxed);
+    IrqResult::Handled
+}
+
+fn passing_handler(_line: u8) -> IrqResult {
+    PASSED.fetch_add(1, Ordering::Relaxed);
+    IrqResult::NotMine
+}
+
+fn other_handler(_line: u8) -> IrqResult {
+    IrqResult::NotMine
+}
+
+fn fourth_handler(_line: u8) -> IrqResult {
+    IrqResult::NotMine
+}
+
+fn fifth_handler(_line: u8) -> IrqResult {
+    IrqResult::NotMine
+}
+
+/// Raises the interrupt of `FREE_LINE` from software
+fn raise_free_line() {
+    unsafe { asm!("int $$37" :::: "volatile") };
+}
+
+/// Raises the interrupt of the master PIC's lowest priority line
+fn raise_line_7() {
+    unsafe { asm!("int $$39" :::: "volatile") };
+}
+
+/// Timer and keyboard are registered at init and the timer counts ticks
+#[test_case]
+fn test_builtin_handlers() {
+    serial_println!("Timer and keyboard handlers...");
+    assert_eq!(interrupts::irq_stats(0).handlers, 1);
+    assert_eq!(interrupts::irq_stats(1).handlers, 1);
+
+    let start = interrupts::timer_ticks();
+    while interrupts::timer_ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(interrupts::irq_stats(0).unhandled, 0);
+    serial_println!("[ok]");
+}
+
+/// Invalid lines and duplicate handlers are refused
+#[test_case]
+fn test_register_errors() {
+    serial_println!("Registration errors...");
+    assert_eq!(
+        interrupts::register_irq(16, claiming_handler),
+        Err(IrqError::InvalidLine(16))
+    );
+    assert_eq!(
+        interrupts::register_irq(2, claiming_handler),
+        Err(IrqError::InvalidLine(2))
+    );
+    assert_eq!(
+        interrupts::unregister_irq(FREE_LINE, claiming_handler),
+        Err(IrqError::NotRegistered)
+    );
+
+    interrupts::register_irq(FREE_LINE, claiming_handler).unwrap();
+    assert_eq!(
+        interrupts::register_irq(FREE_LINE, claiming_handler),
+        Err(IrqError::AlreadyRegistered)
+    );
+    interrupts::unregister_irq(FREE_LINE, claiming_handler).unwrap();
+    assert_eq!(interrupts::irq_stats(FREE_LINE).handlers, 0);
+    serial_println!("[ok]");
+}
+
+/// A line takes at most `MAX_SHARED_HANDLERS` handlers
+#[test_case]
+fn test_line_full() {
+    serial_println!("Full line...");
+    let handlers: [IrqHandler; MAX_SHARED_HANDLERS] = [
+        claiming_handler,
+        passing_handler,
+        other_handler,
+        fourth_handler,
+    ];
+    for &handler in &handlers {
+        interrupts::register_irq(FREE_LINE, handler).unwrap();
+    }
+    assert_eq!(
+        interrupts::register_irq(FREE_LINE, fifth_handler),
+        Err(IrqError::LineFull)
+    );
+
+    for &handler in &handlers {
+        interrupts::unregister_irq(FREE_LINE, handler).unwrap();
+    }
+    serial_println!("[ok]");
+}
+
+/// Every handler of a shared line runs and the interrupt is counted
+#[test_case]
+fn test_shared_line() {
+    serial_println!("Shared line...");
+    interrupts::register_irq(FREE_LINE, passing_handler).unwrap();
+    interrupts::register_irq(FREE_LINE, claiming_handler).unwrap();
+    let before = interrupts::irq_stats(FREE_LINE);
+    let (claimed, passed) = (
+        CLAIMED.load(Ordering::Relaxed),
+        PASSED.load(Ordering::Relaxed),
+    );
+
+    raise_free_line();
+    let after = interrupts::irq_stats(FREE_LINE);
+    assert_eq!(after.count, before.count + 1);
+    assert_eq!(after.unhandled, before.unhandled);
+    assert_eq!(CLAIMED.load(Ordering::Relaxed), claimed + 1);
+    assert_eq!(PASSED.load(Ordering::Relaxed), passed + 1);
+
+    // Nobody claims it once the claiming handler is gone
+    interrupts::unregister_irq(FREE_LINE, claiming_handler).unwrap();
+    raise_free_line();
+    let last = interrupts::irq_stats(FREE_LINE);
+    assert_eq!(last.count, after.count + 1);
+    assert_eq!(last.unhandled, after.unhandled + 1);
+    assert_eq!(PASSED.load(Ordering::Relaxed), passed + 2);
+
+    interrupts::unregister_irq(FREE_LINE, passing_handler).unwrap();
+    serial_println!("[ok]");
+}
+
+/// Line 7 firing while not in service is a spurious interrupt
+#[test_case]
+fn test_spurious_line_7() {
+    serial_println!("Spurious IRQ 7...");
+    let spurious = interrupts::spurious_interrupts();
+    let count = interrupts::irq_stats(7).count;
+
+    raise_line_7();
+    assert_eq!(interrupts::spurious_interrupts(), spurious + 1);
+    assert_eq!(interrupts::irq_stats(7).count, count);
+    serial_println!("[ok]");
+}
+
+/// A debug exception with the writers held leaves them locked
+#[test_case]
+fn test_report_skips_held_writers() {
+    use x86_64::instructions::interrupts::without_interrupts;
+    use x86_kernel::{crash, serial, vga_buffer};
+
+    serial_println!("Reports skip held writers...");
+    let skipped = crash::skipped_reports();
+    let still_held = without_interrupts(|| {
+        let serial = serial::SERIAL_A.lock();
+        let vga = vga_buffer::WRITER.lock();
+        unsafe { asm!("int $$1" :::: "volatile") };
+        let still_held = serial::SERIAL_A.is_locked() && vga_buffer::WRITER.is_locked();
+        drop(vga);
+        drop(serial);
+        still_held
+    });
+    assert!(still_held);
+    assert_eq!(crash::skipped_reports(), skipped + 1);
+    serial_println!("[ok]");
+}
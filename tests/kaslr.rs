@@ -0,0 +1,111 @@
+//! Address space layout randomisation tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::VirtAddr;
+use x86_kernel::{
+    allocator, fw_cfg,
+    kaslr::{self, Layout, Window},
+    serial_println, slab, stack, vma,
+};
+
+entry_point!(main);
+
+/// KASLR test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::memory::{self, BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Whether a region of `size` bytes at `base` lies in `window`
+fn inside(window: Window, base: u64, size: u64) -> bool {
+    base >= window.start && base + size <= window.end && base % (2 * 1024 * 1024) == 0
+}
+
+/// Seeded layouts stay inside their windows
+#[test_case]
+fn test_seeded_layouts_inside_windows() {
+    serial_println!("Seeded layouts inside windows...");
+    let mut seed = 1u64;
+    for _ in 0..64 {
+        let layout = Layout::from_seed(seed);
+        assert_eq!(layout, Layout::from_seed(seed));
+        assert!(inside(
+            kaslr::HEAP_WINDOW,
+            layout.heap,
+            allocator::HEAP_MAX_SIZE as u64
+        ));
+        assert!(inside(
+            kaslr::SLAB_WINDOW,
+            layout.slabs,
+            slab::SLAB_REGION_SIZE
+        ));
+        assert!(inside(
+            kaslr::STACKS_WINDOW,
+            layout.stacks,
+            stack::STACKS_SIZE
+        ));
+        assert!(inside(
+            kaslr::VMALLOC_WINDOW,
+            layout.vmalloc,
+            vma::VMALLOC_SIZE
+        ));
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    }
+    assert_ne!(Layout::from_seed(1), Layout::from_seed(2));
+    serial_println!("[ok]");
+}
+
+/// The regions are placed at the layout chosen at boot
+#[test_case]
+fn test_regions_follow_layout() {
+    serial_println!("Regions follow the layout...");
+    let layout = kaslr::layout();
+    assert!(layout == Layout::FIXED || layout == Layout::from_seed(kaslr::seed()));
+
+    assert_eq!(allocator::heap_start() as u64, layout.heap);
+    assert_eq!(slab::slab_start(), layout.slabs);
+    assert_eq!(stack::stacks_start(), layout.stacks);
+    assert_eq!(vma::vmalloc_window().0, layout.vmalloc);
+
+    let heap = vma::find(VirtAddr::new(layout.heap)).expect("heap not reserved");
+    assert_eq!(heap.start.as_u64(), layout.heap);
+    let stack = stack::allocate_stack("kaslr test", 2).unwrap();
+    assert!(stack.bottom().as_u64() >= layout.stacks);
+    assert!(stack.top().as_u64() <= layout.stacks + stack::STACKS_SIZE);
+    unsafe { stack::free_stack(stack) };
+    serial_println!("[ok]");
+}
+
+/// Boot options are read from QEMU's configuration device
+#[test_case]
+fn test_fw_cfg() {
+    serial_println!("Firmware configuration...");
+    assert!(fw_cfg::is_present());
+    let mut buf = [0; 8];
+    assert_eq!(fw_cfg::read_file("opt/x86-kernel/missing", &mut buf), None);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,81 @@
+//! Kernel stack allocator tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_kernel::{
+    serial_println,
+    stack::{self, GuardKind},
+};
+
+entry_point!(main);
+
+/// Kernel stack test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use x86_kernel::{
+        allocator,
+        memory::{self, BitmapFrameAllocator},
+    };
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+    stack::init_ist_stacks().expect("Failed to map interrupt stacks");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Stacks are usable and have a named guard page below them
+#[test_case]
+fn test_stack_guard_page() {
+    serial_println!("Kernel stack guard page...");
+    let stack = stack::allocate_stack("test", 2).expect("stack allocation failed");
+
+    let bottom: *mut u64 = stack.bottom().as_mut_ptr();
+    let top: *mut u64 = (stack.top() - 8u64).as_mut_ptr();
+    unsafe {
+        bottom.write_volatile(1);
+        top.write_volatile(2);
+    }
+
+    let hit = stack::guard_hit(stack.bottom() - 1u64).expect("no guard page");
+    assert_eq!(hit.name, "test");
+    assert_eq!(hit.kind, GuardKind::Stack);
+    assert!(stack::guard_hit(stack.bottom()).is_none());
+
+    let guard = stack.bottom() - 1u64;
+    unsafe { stack::free_stack(stack) };
+    assert!(stack::guard_hit(guard).is_none());
+    serial_println!("[ok]");
+}
+
+/// The pages around the heap region are guards
+#[test_case]
+fn test_heap_guard_pages() {
+    use x86_64::VirtAddr;
+    use x86_kernel::allocator::{heap_start, HEAP_MAX_SIZE};
+
+    serial_println!("Heap guard pages...");
+    let end = VirtAddr::new((heap_start() + HEAP_MAX_SIZE) as u64);
+    let hit = stack::guard_hit(end).expect("no heap guard page");
+    assert_eq!(hit.kind, GuardKind::Region);
+    serial_println!("[ok]");
+}
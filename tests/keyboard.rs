@@ -0,0 +1,111 @@
+//! Keyboard queue and line editing tests
+//!
+//! Scancodes are queued as the keyboard interrupt would, terminal input
+//! is fed to COM1 through the UART's loopback mode
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::task::{Context, Poll};
+
+use pc_keyboard::DecodedKey;
+use x86_kernel::keyboard::{self, ScancodeStream};
+use x86_kernel::{console, serial_println};
+
+mod common;
+
+use common::{loop_back, noop_waker};
+
+entry_point!(main);
+
+/// Keyboard test entry point
+///
+/// `read_line` needs the heap
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use x86_kernel::allocator;
+    use x86_kernel::memory::{self, BitmapFrameAllocator};
+
+    x86_kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Queues the press and release of each make code
+fn press(keys: &[u8]) {
+    for &key in keys {
+        keyboard::add_scancode(key);
+        keyboard::add_scancode(key | 0x80);
+    }
+}
+
+/// Queued scancodes decode to keys
+#[test_case]
+fn test_read_key() {
+    serial_println!("Keyboard keys...");
+    assert_eq!(keyboard::read_key(), None);
+    // A
+    press(&[0x1e]);
+    assert_eq!(keyboard::read_key(), Some(DecodedKey::Unicode('a')));
+    assert_eq!(keyboard::read_key(), None);
+    assert_eq!(keyboard::dropped_scancodes(), 0);
+    serial_println!("[ok]");
+}
+
+/// The stream is pending until a scancode is queued
+#[test_case]
+fn test_stream() {
+    serial_println!("Scancode stream...");
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut stream = ScancodeStream;
+    assert_eq!(stream.poll_next(&mut cx), Poll::Pending);
+    keyboard::add_scancode(0x1e);
+    assert_eq!(stream.poll_next(&mut cx), Poll::Ready(Some(0x1e)));
+    assert_eq!(keyboard::read_scancode(), None);
+    serial_println!("[ok]");
+}
+
+/// Lines are typed on the keyboard
+#[test_case]
+fn test_keyboard_line() {
+    serial_println!("Keyboard line...");
+    // H, I, Enter
+    press(&[0x23, 0x17, 0x1c]);
+    assert_eq!(console::read_line(), "hi");
+    serial_println!("[ok]");
+}
+
+/// Terminal input is edited and earlier lines come back
+#[test_case]
+fn test_serial_line() {
+    serial_println!("Serial line editing...");
+    loop_back(b"ab\x7fc\r");
+    assert_eq!(console::read_line(), "ac");
+    loop_back(b"xz\x1b[Dy\r\n");
+    assert_eq!(console::read_line(), "xyz");
+    loop_back(b"bcd\x1b[Ha\x1b[3~\r");
+    assert_eq!(console::read_line(), "acd");
+    // Back to "ac" and forward to "xyz"
+    loop_back(b"\x1b[A\x1b[A\x1b[A\x1b[B\r");
+    assert_eq!(console::read_line(), "xyz");
+    serial_println!("[ok]");
+}
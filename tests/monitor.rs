@@ -0,0 +1,100 @@
+//! Kernel monitor command tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, string::String};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::registers::model_specific::Efer;
+use x86_64::VirtAddr;
+use x86_kernel::backtrace;
+use x86_kernel::monitor::{Flow, Monitor, Reason};
+use x86_kernel::serial_println;
+
+entry_point!(main);
+
+/// Monitor test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+static DUMPED: [u8; 4] = *b"mon!";
+
+/// Output of `command`
+fn run(command: &str) -> (Flow, String) {
+    let monitor = Monitor::new(Reason::Breakpoint, backtrace::capture_registers());
+    let mut out = String::new();
+    let flow = monitor.execute(command, &mut out);
+    serial_println!("{}", out);
+    (flow, out)
+}
+
+/// `mem` shows bytes and stops at unmapped memory
+#[test_case]
+fn test_mem() {
+    serial_println!("Monitor mem...");
+    let addr = DUMPED.as_ptr() as u64;
+    let (flow, out) = run(&format!("mem {:#x} 4", addr));
+    assert_eq!(flow, Flow::Stay);
+    assert!(out.contains("6d 6f 6e 21"));
+    assert!(out.contains("mon!"));
+
+    let (_, out) = run("mem 0xdead00000000 16");
+    assert!(out.contains("not mapped"));
+    let (_, out) = run("mem");
+    assert!(out.contains("usage"));
+    serial_println!("[ok]");
+}
+
+/// `irq`, `msr` and `regs` show the machine state
+#[test_case]
+fn test_state() {
+    serial_println!("Monitor state commands...");
+    let (_, out) = run("irq");
+    assert!(out.contains("spurious"));
+
+    let (_, out) = run("msr efer");
+    assert!(out.contains(&format!("{:#018x}", Efer::read_raw())));
+    let (_, out) = run("msr nonsense");
+    assert!(out.contains("unknown MSR"));
+
+    let (_, out) = run("regs");
+    assert!(out.contains("rip") && out.contains("cr3"));
+    serial_println!("[ok]");
+}
+
+/// Only `cont` leaves the monitor
+#[test_case]
+fn test_flow() {
+    serial_println!("Monitor flow...");
+    assert_eq!(run("").0, Flow::Stay);
+    let (flow, out) = run("frobnicate");
+    assert_eq!(flow, Flow::Stay);
+    assert!(out.contains("unknown command"));
+    assert_eq!(run("cont").0, Flow::Leave);
+    serial_println!("[ok]");
+}
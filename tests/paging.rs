@@ -0,0 +1,112 @@
+//! Paging API tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_kernel::{
+    memory,
+    paging::{self, PagingError},
+    serial_println, vma,
+};
+
+entry_point!(main);
+
+/// Paging test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Free pages of the vmalloc window
+fn free_pages(count: u64) -> (Page, Page) {
+    let start = vma::allocate_range("paging test", count * 4096, paging::data_flags()).unwrap();
+    let first = Page::containing_address(start);
+    (first, first + count)
+}
+
+/// Mapped pages can be queried, and unmapping frees their frames
+#[test_case]
+fn test_map_query_unmap() {
+    serial_println!("Map, query and unmap...");
+    let (first, end) = free_pages(4);
+    // Page tables created for the range stay after unmapping, so they
+    // are made before counting
+    paging::map_alloc(Page::range(first, end), paging::data_flags()).unwrap();
+    unsafe { paging::unmap(Page::range(first, end)).unwrap() };
+    let used_before = memory::stats().used_frames;
+
+    paging::map_alloc(Page::range(first, end), paging::data_flags()).unwrap();
+    let mapping = paging::query(first).unwrap();
+    assert_eq!(mapping.page, first);
+    assert!(mapping.flags.contains(PageTableFlags::WRITABLE));
+    unsafe { first.start_address().as_mut_ptr::<u64>().write_volatile(1) };
+
+    assert_eq!(
+        paging::map_alloc(Page::range(first, first + 1), paging::data_flags()),
+        Err(PagingError::AlreadyMapped(first))
+    );
+
+    unsafe { paging::unmap(Page::range(first, end)).unwrap() };
+    assert_eq!(paging::query(first), Err(PagingError::NotMapped(first)));
+    assert_eq!(memory::stats().used_frames, used_before);
+    serial_println!("[ok]");
+}
+
+/// Protection changes show up in the page table
+#[test_case]
+fn test_protect() {
+    serial_println!("Protect...");
+    let (first, end) = free_pages(2);
+    paging::map_alloc(Page::range(first, end), paging::data_flags()).unwrap();
+
+    paging::protect(Page::range(first, end), PageTableFlags::PRESENT).unwrap();
+    let flags = paging::query(first + 1).unwrap().flags;
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+    unsafe { paging::unmap(Page::range(first, end)).unwrap() };
+    assert_eq!(
+        paging::protect(Page::range(first, end), paging::data_flags()),
+        Err(PagingError::NotMapped(first))
+    );
+    serial_println!("[ok]");
+}
+
+/// Pages can alias a given physical range
+#[test_case]
+fn test_map_physical() {
+    serial_println!("Physical mapping...");
+    let (first, end) = free_pages(1);
+    let vga = PhysAddr::new(0xb8000);
+
+    unsafe { paging::map_physical(Page::range(first, end), vga, paging::data_flags()).unwrap() };
+    assert_eq!(
+        paging::translate(first.start_address() + 8u64),
+        Some(vga + 8u64)
+    );
+    unsafe { paging::unmap_keep_frames(Page::range(first, end)).unwrap() };
+    serial_println!("[ok]");
+}
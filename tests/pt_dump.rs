@@ -0,0 +1,138 @@
+//! Page table inspector tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_kernel::{
+    memory, paging,
+    pt_dump::{self, PtRange},
+    serial_println, vma,
+};
+
+entry_point!(main);
+
+/// Page table inspector test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::{allocator, memory::BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Free range of the vmalloc window starting at a multiple of `align`
+fn free_range(size: u64, align: u64) -> VirtAddr {
+    vma::allocate_range_aligned("pt dump test", size, align, paging::data_flags()).unwrap()
+}
+
+/// Ranges of the active page table inside `start..start + size`
+fn ranges_in(start: VirtAddr, size: u64) -> alloc::vec::Vec<PtRange> {
+    pt_dump::ranges()
+        .into_iter()
+        .filter(|r| r.start >= start && r.start < start + size)
+        .collect()
+}
+
+/// Contiguous pages with equal flags merge into one range
+#[test_case]
+fn test_merges_contiguous_pages() {
+    serial_println!("Merge contiguous pages...");
+    let start = free_range(3 * Size4KiB::SIZE, Size4KiB::SIZE);
+    let first = Page::containing_address(start);
+    let vga = PhysAddr::new(0xb8000);
+
+    unsafe {
+        paging::map_physical(Page::range(first, first + 3), vga, paging::data_flags()).unwrap()
+    };
+    let ranges = ranges_in(start, 3 * Size4KiB::SIZE);
+    assert_eq!(ranges.len(), 1);
+    let range = ranges[0];
+    assert_eq!(range.size, 3 * Size4KiB::SIZE);
+    assert_eq!(range.phys, vga);
+    assert!(range.writable() && !range.user_accessible() && !range.huge());
+    assert_eq!(range.translate(start + 0x1008u64), Some(vga + 0x1008u64));
+
+    unsafe { paging::unmap_keep_frames(Page::range(first, first + 3)).unwrap() };
+    assert!(ranges_in(start, 3 * Size4KiB::SIZE).is_empty());
+    serial_println!("[ok]");
+}
+
+/// Flag changes and physical gaps split ranges
+#[test_case]
+fn test_splits_ranges() {
+    serial_println!("Split ranges...");
+    let start = free_range(3 * Size4KiB::SIZE, Size4KiB::SIZE);
+    let first = Page::containing_address(start);
+    let vga = PhysAddr::new(0xb8000);
+
+    unsafe {
+        paging::map_physical(Page::range(first, first + 2), vga, paging::data_flags()).unwrap();
+        paging::map_physical(Page::range(first + 2, first + 3), vga, paging::data_flags()).unwrap();
+    }
+    paging::protect(Page::range(first + 1, first + 2), PageTableFlags::PRESENT).unwrap();
+
+    let ranges = ranges_in(start, 3 * Size4KiB::SIZE);
+    assert_eq!(ranges.len(), 3);
+    assert!(ranges[0].writable());
+    assert!(!ranges[1].writable());
+    assert_eq!(ranges[1].phys, vga + Size4KiB::SIZE);
+    assert_eq!(ranges[2].phys, vga);
+
+    unsafe { paging::unmap_keep_frames(Page::range(first, first + 3)).unwrap() };
+    serial_println!("[ok]");
+}
+
+/// Huge pages show up with their page size
+#[test_case]
+fn test_reports_huge_pages() {
+    serial_println!("Report huge pages...");
+    let size = 2 * Size2MiB::SIZE;
+    let start = free_range(size, Size2MiB::SIZE);
+
+    unsafe {
+        paging::map_physical_region(start, PhysAddr::new(0), size, paging::data_flags()).unwrap()
+    };
+    let ranges = ranges_in(start, size);
+    assert_eq!(ranges.len(), 1);
+    assert_eq!(ranges[0].size, size);
+    assert_eq!(ranges[0].page_size, Size2MiB::SIZE);
+    assert!(ranges[0].huge());
+
+    unsafe { paging::unmap_region(start, size).unwrap() };
+    serial_println!("[ok]");
+}
+
+/// The dump walks the whole active hierarchy
+#[test_case]
+fn test_dump() {
+    serial_println!("Dump the active page table...");
+    let ranges = pt_dump::ranges();
+    let heap = VirtAddr::new(x86_kernel::allocator::heap_start() as u64);
+    assert!(ranges.iter().any(|r| r.contains(heap)));
+    assert!(ranges.windows(2).all(|w| w[0].end() <= w[1].start.as_u64()));
+    pt_dump::dump();
+    serial_println!("[ok]");
+}
@@ -0,0 +1,112 @@
+//! Serial input and console tests
+//!
+//! Bytes are fed to COM1 through the UART's loopback mode
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::task::{Context, Poll};
+
+use x86_kernel::ring::{ByteRing, RING_CAPACITY};
+use x86_kernel::serial::{self, ReadBytes};
+use x86_kernel::{console, serial_println};
+
+mod common;
+
+use common::{loop_back, noop_waker};
+
+entry_point!(main);
+
+/// Serial test entry point
+fn main(_boot_info: &'static BootInfo) -> ! {
+    x86_kernel::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Waits until `count` bytes arrived through the receive interrupt
+fn wait_received(count: usize) -> [u8; 8] {
+    let mut received = [0; 8];
+    for byte in received.iter_mut().take(count) {
+        *byte = serial::wait_byte();
+    }
+    received
+}
+
+/// Bytes come out in order and a full ring drops new ones
+#[test_case]
+fn test_ring() {
+    serial_println!("Byte ring...");
+    let ring = ByteRing::new();
+    // The test is the only producer and consumer
+    unsafe {
+        assert_eq!(ring.pop(), None);
+        for i in 0..RING_CAPACITY {
+            assert!(ring.push(i as u8));
+        }
+        assert!(!ring.push(0xff));
+        assert_eq!(ring.dropped(), 1);
+        assert_eq!(ring.len(), RING_CAPACITY);
+        for i in 0..RING_CAPACITY {
+            assert_eq!(ring.pop(), Some(i as u8));
+        }
+    }
+    assert!(ring.is_empty());
+    serial_println!("[ok]");
+}
+
+/// Received bytes are read back through the interrupt handler
+#[test_case]
+fn test_receive() {
+    serial_println!("Serial receive...");
+    let before = x86_kernel::interrupts::irq_stats(serial::COM1_IRQ).count;
+    loop_back(b"ping");
+    assert_eq!(&wait_received(4)[..4], b"ping");
+    assert_eq!(serial::read_byte(), None);
+    assert!(x86_kernel::interrupts::irq_stats(serial::COM1_IRQ).count > before);
+    assert_eq!(serial::dropped_bytes(), 0);
+    serial_println!("[ok]");
+}
+
+/// The console reads serial input
+#[test_case]
+fn test_console() {
+    serial_println!("Serial console...");
+    assert_eq!(
+        console::add_source(serial::read_byte),
+        Err(console::ConsoleError::AlreadyAdded)
+    );
+    loop_back(b"c");
+    assert_eq!(console::wait_byte(), b'c');
+    serial_println!("[ok]");
+}
+
+/// The stream is pending until a byte arrives
+#[test_case]
+fn test_stream() {
+    serial_println!("Serial stream...");
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut stream = ReadBytes;
+    assert_eq!(stream.poll_next(&mut cx), Poll::Pending);
+
+    loop_back(b"s");
+    let polled = loop {
+        match stream.poll_next(&mut cx) {
+            Poll::Ready(byte) => break byte,
+            Poll::Pending => x86_64::instructions::hlt(),
+        }
+    };
+    assert_eq!(polled, Some(b's'));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,96 @@
+//! Slab cache tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use alloc::vec::Vec;
+use x86_kernel::{slab::SlabCache, serial_println};
+
+extern crate alloc;
+
+entry_point!(main);
+
+/// Object shaped like a small control block
+struct Block {
+    id: u64,
+    data: [u8; 48],
+}
+
+fn new_block() -> Block {
+    Block {
+        id: 7,
+        data: [0xab; 48],
+    }
+}
+
+static BLOCKS: SlabCache<Block> = SlabCache::new("block", new_block);
+
+/// Slab test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use x86_kernel::{
+        allocator,
+        memory::{self, BitmapFrameAllocator},
+    };
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// Objects come out initialized by the constructor
+#[test_case]
+fn test_constructor() {
+    serial_println!("Slab constructor...");
+    let mut block = BLOCKS.alloc();
+    assert_eq!(block.id, 7);
+    assert!(block.data.iter().all(|&b| b == 0xab));
+    block.id = 8;
+    assert_eq!(block.id, 8);
+    serial_println!("[ok]");
+}
+
+/// Slabs move between the full, partial and empty lists
+#[test_case]
+fn test_slab_lists() {
+    serial_println!("Slab lists...");
+    let per_slab = SlabCache::<Block>::objects_per_slab();
+    let mut blocks = Vec::new();
+    for _ in 0..per_slab + 1 {
+        blocks.push(BLOCKS.alloc());
+    }
+
+    let stats = BLOCKS.stats();
+    assert_eq!(stats.active_objects, per_slab + 1);
+    assert_eq!(stats.full_slabs, 1);
+    assert_eq!(stats.partial_slabs, 1);
+
+    drop(blocks);
+    let stats = BLOCKS.stats();
+    assert_eq!(stats.active_objects, 0);
+    assert_eq!(stats.full_slabs + stats.partial_slabs, 0);
+    assert_eq!(stats.total_allocs, stats.total_frees);
+
+    BLOCKS.shrink();
+    assert_eq!(BLOCKS.stats().empty_slabs, 0);
+    serial_println!("[ok]");
+}
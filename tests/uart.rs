@@ -0,0 +1,110 @@
+//! UART driver tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_kernel::serial_println;
+use x86_kernel::uart::{
+    self, ComPort, DataBits, FifoTrigger, Parity, StopBits, UartConfig, UartError,
+};
+
+entry_point!(main);
+
+/// UART test entry point
+fn main(_boot_info: &'static BootInfo) -> ! {
+    x86_kernel::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// COM1 carries the test output, so it is found
+#[test_case]
+fn test_probe() {
+    serial_println!("UART probe...");
+    assert!(uart::present_ports().any(|com| com == ComPort::Com1));
+    let passed = without_interrupts(|| uart::port(ComPort::Com1).lock().loopback_test());
+    assert!(passed);
+    for com in uart::present_ports() {
+        serial_println!("{:?} at {:#x}", com, com.base());
+    }
+    serial_println!("[ok]");
+}
+
+/// Line settings end up in the line control register
+#[test_case]
+fn test_configure() {
+    serial_println!("UART line settings...");
+    let port = uart::port(ComPort::Com1);
+    let default = port.lock().config();
+    let config = UartConfig {
+        baud: 9600,
+        data_bits: DataBits::Seven,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+        fifo_trigger: Some(FifoTrigger::Bytes4),
+    };
+
+    let (line_control, loopback) = without_interrupts(|| {
+        let mut com1 = port.lock();
+        com1.configure(config).unwrap();
+        let line_control = unsafe { Port::<u8>::new(ComPort::Com1.base() + 3).read() };
+        let loopback = com1.loopback_test();
+        com1.configure(default).unwrap();
+        (line_control, loopback)
+    });
+    // Even parity, two stop bits, seven data bits
+    assert_eq!(line_control, 0b0001_1110);
+    // Waits long enough at 9600 baud and only checks 7 bits
+    assert!(loopback);
+
+    let invalid = UartConfig {
+        baud: 1000,
+        ..default
+    };
+    assert_eq!(
+        port.lock().configure(invalid),
+        Err(UartError::InvalidBaud(1000))
+    );
+    // The divisor wouldn't fit its 16 bits
+    let too_slow = UartConfig { baud: 1, ..default };
+    assert_eq!(
+        port.lock().configure(too_slow),
+        Err(UartError::InvalidBaud(1))
+    );
+    assert_eq!(port.lock().config(), default);
+    serial_println!("[ok]");
+}
+
+/// Absent ports refuse settings and swallow output
+#[test_case]
+fn test_absent_port() {
+    serial_println!("Absent UART...");
+    let absent = ComPort::ALL
+        .iter()
+        .copied()
+        .find(|&com| !uart::port(com).lock().is_present());
+    if let Some(com) = absent {
+        let mut port = uart::port(com).lock();
+        assert_eq!(
+            port.configure(UartConfig::default()),
+            Err(UartError::NotPresent(com))
+        );
+        write!(port, "lost").unwrap();
+        assert_eq!(port.try_receive(), None);
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,130 @@
+//! Kernel address space manager tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::VirtAddr;
+use x86_kernel::{
+    allocator, paging, serial_println,
+    vma::{self, Purpose, VmaError},
+};
+
+entry_point!(main);
+
+/// VMA test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::memory::{self, BitmapFrameAllocator};
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+/// The fixed kernel regions are reserved at install
+#[test_case]
+fn test_kernel_regions_reserved() {
+    serial_println!("Kernel regions reserved...");
+    let heap = vma::find(VirtAddr::new(allocator::heap_start() as u64)).expect("heap not reserved");
+    assert_eq!(heap.purpose, Purpose::Heap);
+
+    let overlap = vma::reserve(
+        "overlap",
+        VirtAddr::new(allocator::heap_start() as u64),
+        4096,
+        paging::data_flags(),
+        Purpose::Fixed,
+    );
+    assert_eq!(overlap, Err(VmaError::Overlap));
+    vma::dump();
+    serial_println!("[ok]");
+}
+
+/// Free ranges are handed out without touching each other
+#[test_case]
+fn test_allocate_range() {
+    serial_println!("Range allocation...");
+    let first = vma::allocate_range("first", 2 * 4096, paging::data_flags()).unwrap();
+    let second = vma::allocate_range("second", 4096, paging::data_flags()).unwrap();
+
+    assert!(second >= first + 3 * 4096u64);
+    assert!(vma::find(first + 2 * 4096u64).is_none());
+
+    vma::release(first).unwrap();
+    vma::release(second).unwrap();
+    assert_eq!(vma::release(second).unwrap_err(), VmaError::NotFound);
+    serial_println!("[ok]");
+}
+
+/// vmalloc ranges are mapped and writable
+#[test_case]
+fn test_vmalloc() {
+    serial_println!("vmalloc...");
+    let start =
+        vma::vmalloc("test buffer", 3 * 4096, paging::data_flags()).expect("vmalloc failed");
+    let buffer: *mut u8 = start.as_mut_ptr();
+    unsafe {
+        buffer.write_bytes(0x5a, 3 * 4096);
+        assert_eq!(*buffer.add(3 * 4096 - 1), 0x5a);
+        vma::vfree(start).unwrap();
+    }
+    assert!(vma::find(start).is_none());
+    serial_println!("[ok]");
+}
+
+/// vmalloc maps with the flags it's given
+#[test_case]
+fn test_vmalloc_flags() {
+    use x86_64::structures::paging::{Page, PageTableFlags};
+
+    serial_println!("vmalloc flags...");
+    let read_only = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    let start = vma::vmalloc("read-only buffer", 4096, read_only).expect("vmalloc failed");
+    let mapping = paging::query(Page::containing_address(start)).unwrap();
+    assert!(!mapping.flags.contains(PageTableFlags::WRITABLE));
+    unsafe { vma::vfree(start).unwrap() };
+
+    let writable_executable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    assert!(vma::vmalloc("w+x buffer", 4096, writable_executable).is_err());
+    serial_println!("[ok]");
+}
+
+/// vfree unmaps ioremap ranges without freeing the device frames
+#[test_case]
+fn test_vfree_ioremap() {
+    use x86_64::PhysAddr;
+    use x86_kernel::memory;
+
+    serial_println!("vfree after ioremap...");
+    // Inside the VGA text buffer, not at a page boundary
+    let vga = PhysAddr::new(0xb8010);
+    // The first mapping may allocate page tables, which stay
+    unsafe { vma::vfree(vma::ioremap("vga", vga, 16).unwrap()).unwrap() };
+    let free_before = memory::stats().free_frames;
+    let addr = unsafe { vma::ioremap("vga", vga, 16).unwrap() };
+    assert_eq!(paging::translate(addr), Some(vga));
+
+    unsafe { vma::vfree(addr).unwrap() };
+    assert!(vma::find(addr).is_none());
+    assert_eq!(paging::translate(addr), None);
+    assert_eq!(memory::stats().free_frames, free_before);
+    serial_println!("[ok]");
+}
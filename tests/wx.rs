@@ -0,0 +1,156 @@
+//! W^X enforcement tests
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(x86_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+use x86_kernel::{
+    allocator, kernel_image, memory,
+    paging::{self, PagingError},
+    pt_dump, serial_println, vma,
+};
+
+entry_point!(main);
+
+/// W^X test entry point
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_kernel::memory::BitmapFrameAllocator;
+
+    x86_kernel::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::map_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory::install(mapper, frame_allocator);
+    kernel_image::protect().expect("Failed to protect the kernel image");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+/// Test panic handler
+fn panic(info: &PanicInfo) -> ! {
+    x86_kernel::test_panic_handler(info)
+}
+
+static DATA: [u8; 16] = [0; 16];
+static mut BSS: [u8; 16] = [0; 16];
+
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    paging::query(Page::containing_address(addr)).unwrap().flags
+}
+
+/// Writable and executable requests are refused
+#[test_case]
+fn test_refuse_writable_executable() {
+    serial_println!("Refuse writable+executable...");
+    let wx = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let start = vma::allocate_range("wx test", 4096, paging::data_flags()).unwrap();
+    let page = Page::containing_address(start);
+
+    assert_eq!(
+        paging::map_alloc(Page::range(page, page + 1), wx),
+        Err(PagingError::WritableExecutable)
+    );
+    paging::map_alloc(Page::range(page, page + 1), paging::data_flags()).unwrap();
+    assert_eq!(
+        paging::protect(Page::range(page, page + 1), wx),
+        Err(PagingError::WritableExecutable)
+    );
+    unsafe { paging::unmap(Page::range(page, page + 1)).unwrap() };
+    serial_println!("[ok]");
+}
+
+/// NX is on when the CPU supports it
+#[test_case]
+fn test_nx_enabled() {
+    serial_println!("NX enabled...");
+    assert_eq!(paging::nx_enabled(), paging::nx_supported());
+    serial_println!("[ok]");
+}
+
+/// Code is read-only and executable, data never executable
+#[test_case]
+fn test_kernel_sections() {
+    serial_println!("Kernel sections...");
+    let code = VirtAddr::new(test_kernel_sections as usize as u64);
+    let rodata = VirtAddr::new(&DATA as *const _ as u64);
+    let bss = VirtAddr::new(unsafe { &BSS as *const _ as u64 });
+
+    let segment = kernel_image::segment(code).expect("code outside the kernel image");
+    assert!(segment.executable && !segment.writable);
+    let flags = flags_of(code);
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+
+    if paging::nx_enabled() {
+        assert!(flags_of(rodata).contains(PageTableFlags::NO_EXECUTE));
+        assert!(!flags_of(rodata).contains(PageTableFlags::WRITABLE));
+        assert!(flags_of(bss).contains(PageTableFlags::NO_EXECUTE | PageTableFlags::WRITABLE));
+    }
+    serial_println!("[ok]");
+}
+
+/// No mapping is writable and executable, the heap included
+#[test_case]
+fn test_no_writable_executable_mapping() {
+    serial_println!("No writable+executable mapping...");
+    if !paging::nx_enabled() {
+        serial_println!("NX not supported by the CPU, skipped");
+        serial_println!("[ok]");
+        return;
+    }
+    let heap = VirtAddr::new(allocator::heap_start() as u64);
+    assert!(flags_of(heap).contains(PageTableFlags::NO_EXECUTE));
+
+    for range in pt_dump::ranges() {
+        assert!(
+            !range.writable() || range.no_execute(),
+            "writable and executable: {}",
+            range
+        );
+    }
+    serial_println!("[ok]");
+}
+
+/// Protecting writable pages keeps their other leaf flags
+#[test_case]
+fn test_protect_keeps_leaf_flags() {
+    use x86_64::structures::paging::{FrameAllocator, Mapper};
+
+    serial_println!("Protect keeps leaf flags...");
+    if !paging::nx_enabled() {
+        serial_println!("NX not supported by the CPU, skipped");
+        serial_println!("[ok]");
+        return;
+    }
+    let uncached = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let start = vma::allocate_range("uncached wx page", 4096, paging::data_flags()).unwrap();
+    let page = Page::containing_address(start);
+    // Like a bootloader mapping, past the W^X checks of `paging`
+    {
+        let mut kernel_memory = memory::KERNEL_MEMORY.lock();
+        let kernel_memory = kernel_memory.as_mut().unwrap();
+        let frame = kernel_memory.frame_allocator.allocate_frame().unwrap();
+        kernel_memory
+            .mapper
+            .map_to(page, frame, uncached, &mut kernel_memory.frame_allocator)
+            .unwrap()
+            .flush();
+    }
+
+    kernel_image::protect().unwrap();
+    let flags = flags_of(start);
+    assert!(flags.contains(uncached | PageTableFlags::NO_EXECUTE));
+    unsafe { paging::unmap(Page::range(page, page + 1)).unwrap() };
+    serial_println!("[ok]");
+}
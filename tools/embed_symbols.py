@@ -0,0 +1,123 @@
+#!/usr/bin/env python3
+"""Embeds the kernel symbol table into a linked kernel binary.
+
+Reads the function symbols from the ELF symbol table, demangles them and
+writes the table `src/symbols.rs` reads into the reserved `.ksymtab`
+section, in place. Binaries without the section are left alone.
+
+Usage: embed_symbols.py <kernel binary>
+"""
+
+import re
+import struct
+import sys
+
+SECTION = b".ksymtab"
+MAGIC = b"KSYM"
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+# Escapes of the legacy Rust mangling
+ESCAPES = {
+    "$SP$": "@",
+    "$BP$": "*",
+    "$RF$": "&",
+    "$LT$": "<",
+    "$GT$": ">",
+    "$LP$": "(",
+    "$RP$": ")",
+    "$C$": ",",
+}
+HASH = re.compile(r"^h[0-9a-f]{16}$")
+
+
+def demangle(name):
+    """Demangles a legacy `_ZN...E` Rust symbol, others are kept"""
+    if not (name.startswith("_ZN") and name.endswith("E")):
+        return name
+    rest = name[3:-1]
+    parts = []
+    while rest:
+        match = re.match(r"(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start : start + length])
+        rest = rest[start + length :]
+    if parts and HASH.match(parts[-1]):
+        parts.pop()
+
+    def unescape(part):
+        if part.startswith("_$"):
+            part = part[1:]
+        part = part.replace("..", "::")
+        for escape, char in ESCAPES.items():
+            part = part.replace(escape, char)
+        return re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+
+    return "::".join(unescape(part) for part in parts)
+
+
+def sections(elf):
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    headers = []
+    for i in range(shnum):
+        fields = struct.unpack_from("<IIQQQQIIQQ", elf, shoff + i * shentsize)
+        headers.append(fields)
+    names = headers[shstrndx]
+    result = []
+    for header in headers:
+        name_start = names[4] + header[0]
+        name = elf[name_start : elf.index(b"\0", name_start)]
+        result.append((name, header))
+    return result
+
+
+def functions(elf, headers):
+    """(start, size, name) of every function, sorted by start"""
+    found = {}
+    for _, header in headers:
+        if header[1] != SHT_SYMTAB:
+            continue
+        offset, size, link, entsize = header[4], header[5], header[6], header[9]
+        strtab = headers[link][1][4]
+        for at in range(offset, offset + size, entsize):
+            name, info, _, shndx, value, sym_size = struct.unpack_from("<IBBHQQ", elf, at)
+            if info & 0xF != STT_FUNC or shndx == 0 or value == 0:
+                continue
+            raw = elf[strtab + name : elf.index(b"\0", strtab + name)].decode()
+            found.setdefault(value, (sym_size, demangle(raw)))
+    return sorted((start, size, name) for start, (size, name) in found.items())
+
+
+def table(symbols, capacity):
+    header_size = 8 + 16 * len(symbols)
+    entries = bytearray()
+    names = bytearray()
+    for start, size, name in symbols:
+        entries += struct.pack("<QII", start, min(size, 0xFFFFFFFF), header_size + len(names))
+        names += name.encode() + b"\0"
+    data = MAGIC + struct.pack("<I", len(symbols)) + entries + names
+    if len(data) > capacity:
+        sys.exit("symbol table of {} bytes doesn't fit in {}".format(len(data), capacity))
+    return data + bytes(capacity - len(data))
+
+
+def main():
+    path = sys.argv[1]
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+    headers = sections(elf)
+    target = [header for name, header in headers if name == SECTION]
+    if not target:
+        return
+    offset, size = target[0][4], target[0][5]
+    elf[offset : offset + size] = table(functions(elf, headers), size)
+    with open(path, "wb") as file:
+        file.write(elf)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,5 @@
+#!/bin/sh
+# Cargo runner: embeds the symbol table into the kernel, then boots it
+set -e
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+exec bootimage_runner "$@"